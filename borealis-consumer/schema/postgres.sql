@@ -0,0 +1,72 @@
+-- Schema of PostgreSQL sink for Borealis Consumer (`--sink Postgres`).
+-- Rows are upserted idempotently (keyed by hashes), all rows of a block are committed in a single transaction.
+-- Whole views are stored as JSONB, with the most used fields extracted into columns.
+
+CREATE TABLE IF NOT EXISTS blocks (
+    hash TEXT PRIMARY KEY,
+    height BIGINT NOT NULL,
+    prev_hash TEXT NOT NULL,
+    epoch_id TEXT NOT NULL,
+    author TEXT NOT NULL,
+    timestamp_nanosec NUMERIC(20, 0) NOT NULL,
+    header JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS blocks_height_idx ON blocks (height);
+
+CREATE TABLE IF NOT EXISTS chunks (
+    chunk_hash TEXT PRIMARY KEY,
+    block_hash TEXT NOT NULL REFERENCES blocks (hash) ON DELETE CASCADE,
+    shard_id BIGINT NOT NULL,
+    author TEXT,
+    height_included BIGINT NOT NULL,
+    header JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chunks_block_hash_idx ON chunks (block_hash);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    hash TEXT PRIMARY KEY,
+    block_hash TEXT NOT NULL REFERENCES blocks (hash) ON DELETE CASCADE,
+    chunk_hash TEXT NOT NULL,
+    shard_id BIGINT NOT NULL,
+    signer_id TEXT NOT NULL,
+    receiver_id TEXT NOT NULL,
+    nonce NUMERIC(20, 0) NOT NULL,
+    transaction JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transactions_block_hash_idx ON transactions (block_hash);
+CREATE INDEX IF NOT EXISTS transactions_signer_id_idx ON transactions (signer_id);
+CREATE INDEX IF NOT EXISTS transactions_receiver_id_idx ON transactions (receiver_id);
+
+CREATE TABLE IF NOT EXISTS receipts (
+    receipt_id TEXT PRIMARY KEY,
+    block_hash TEXT NOT NULL REFERENCES blocks (hash) ON DELETE CASCADE,
+    chunk_hash TEXT,
+    shard_id BIGINT NOT NULL,
+    predecessor_id TEXT NOT NULL,
+    receiver_id TEXT NOT NULL,
+    receipt JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS receipts_block_hash_idx ON receipts (block_hash);
+CREATE INDEX IF NOT EXISTS receipts_receiver_id_idx ON receipts (receiver_id);
+
+-- Execution outcomes of transactions and receipts (`id` is transaction hash or receipt id)
+CREATE TABLE IF NOT EXISTS execution_outcomes (
+    id TEXT PRIMARY KEY,
+    block_hash TEXT NOT NULL REFERENCES blocks (hash) ON DELETE CASCADE,
+    executed_in_block_hash TEXT NOT NULL,
+    shard_id BIGINT NOT NULL,
+    executor_id TEXT NOT NULL,
+    gas_burnt NUMERIC(20, 0) NOT NULL,
+    status JSONB NOT NULL,
+    outcome JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS execution_outcomes_block_hash_idx ON execution_outcomes (block_hash);
+
+-- State changes are keyed by block hash, shard and index of the change within the shard
+CREATE TABLE IF NOT EXISTS state_changes (
+    block_hash TEXT NOT NULL REFERENCES blocks (hash) ON DELETE CASCADE,
+    shard_id BIGINT NOT NULL,
+    index INTEGER NOT NULL,
+    state_change JSONB NOT NULL,
+    PRIMARY KEY (block_hash, shard_id, index)
+);
@@ -0,0 +1,83 @@
+use crate::configs::{Error, RunArgs};
+use crate::dead_letter;
+use tracing::error;
+
+/// Failure of message processing, which determines acknowledgement of JetStream message
+#[derive(Debug)]
+pub(crate) enum ProcessingError {
+    /// Message can't be decoded (poison message), so its redelivery won't help
+    Decoding(Error),
+    /// Blocks of the message weren't handled by the sink (e.g. database isn't available), so the message should be redelivered
+    Handling(Error),
+}
+
+impl ProcessingError {
+    /// Failure class, for dead-letter message headers and metrics
+    pub(crate) fn class(&self) -> &'static str {
+        match self {
+            Self::Decoding(_) => "Decoding",
+            Self::Handling(_) => "Handling",
+        }
+    }
+
+    pub(crate) fn error(&self) -> &Error {
+        match self {
+            Self::Decoding(error) | Self::Handling(error) => error,
+        }
+    }
+}
+
+/// Acknowledgement of JetStream message accordingly to the result of its processing:
+/// `ack` after successful processing, `term` of poison messages, `nak` with `--nak-delay` of messages failed to be handled,
+/// until the message is delivered `--max-deliver` times. Terminated messages are republished to dead-letter subject, if it's set.
+pub(crate) fn acknowledge(
+    nats_connection: &nats::Connection,
+    msg: &nats::Message,
+    result: Result<(), ProcessingError>,
+    context: &RunArgs,
+) -> Result<(), Error> {
+    match result {
+        Ok(()) => msg.ack()?,
+        Err(processing_error @ ProcessingError::Decoding(_)) => {
+            error!(target: "borealis_consumer", "Message decoding error, message is terminated: {:?}", processing_error.error());
+            dead_letter::publish(nats_connection, msg, &processing_error, context)?;
+            term(msg)?;
+        }
+        Err(processing_error @ ProcessingError::Handling(_)) => {
+            let delivered = msg
+                .jetstream_message_info()
+                .map(|info| info.delivered)
+                .unwrap_or(1);
+            if context.max_deliver > 0 && delivered >= context.max_deliver {
+                error!(
+                    target: "borealis_consumer",
+                    "Message processing error, message has been delivered {} times (`--max-deliver` {}) and is terminated: {:?}",
+                    delivered, context.max_deliver, processing_error.error()
+                );
+                dead_letter::publish(nats_connection, msg, &processing_error, context)?;
+                term(msg)?;
+            } else {
+                error!(
+                    target: "borealis_consumer",
+                    "Message processing error, message will be redelivered after {} ms (delivery attempt {}): {:?}",
+                    context.nak_delay, delivered, processing_error.error()
+                );
+                nak(msg, context.nak_delay)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Negative acknowledgement: the message is redelivered after the delay (in milliseconds)
+pub(crate) fn nak(msg: &nats::Message, delay: u64) -> Result<(), Error> {
+    let delay_nanos = core::time::Duration::from_millis(delay).as_nanos() as u64;
+    msg.respond(format!("-NAK {{\"delay\":{}}}", delay_nanos))?;
+    Ok(())
+}
+
+/// Termination: the message isn't redelivered anymore
+pub(crate) fn term(msg: &nats::Message) -> Result<(), Error> {
+    msg.respond(b"+TERM")?;
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use crate::configs::{BackfillSource, Error, MsgFormat, RunArgs};
+use borealis_envelope::ZstdDictionaries;
+use crate::envelope::Envelope;
+use borealis_envelope::stream_message_get;
+use borealis_types::types::{BorealisMessage, StreamerMessage};
+use serde::Deserialize;
+use tracing::{error, info};
+
+/// Maximum number of missing blocks, which could be backfilled at once
+const MAX_BACKFILL_BLOCKS: usize = 1000;
+/// Timeout for requests to the indexer's query service
+const BACKFILL_REQUEST_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10000);
+
+/// Reply of the indexer's query service (only variants requested by the consumer)
+#[derive(Deserialize, Debug)]
+enum QueryReply {
+    Message(StreamerMessage),
+    Error(QueryError),
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryError {
+    kind: String,
+    message: String,
+}
+
+/// Maximum number of tracked height ranges of gaps, which haven't been backfilled (the oldest ones are forgotten)
+const MAX_UNFILLED_GAPS: usize = 1000;
+
+/// Delivery state: the last delivered block and inclusive height ranges of gaps, which haven't been backfilled.
+/// Blocks from unfilled gaps may still arrive late (e.g. redelivered by JetStream), and they are delivered then.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeliveryState {
+    last_delivered: Option<(u64, String)>,
+    unfilled_gaps: Vec<(u64, u64)>,
+}
+
+/// Position of the received block relatively to already delivered blocks
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Continuity {
+    /// The first block, or the block continuing hash chain of the last delivered block
+    Next,
+    /// Previous block hash doesn't match the hash of the last delivered block, missing blocks should be backfilled
+    Gap { last_height: u64, last_hash: String },
+    /// Block from the height range of unfilled gap, which hasn't been delivered yet
+    Late,
+    /// Block has been already delivered
+    AlreadyDelivered,
+}
+
+impl DeliveryState {
+    pub(crate) fn continuity(&self, block_height: u64, prev_hash: &str) -> Continuity {
+        match &self.last_delivered {
+            None => Continuity::Next,
+            Some(_) if self.is_unfilled(block_height) => Continuity::Late,
+            Some((last_height, _)) if block_height <= *last_height => Continuity::AlreadyDelivered,
+            Some((_, last_hash)) if prev_hash == last_hash => Continuity::Next,
+            Some((last_height, last_hash)) => Continuity::Gap {
+                last_height: *last_height,
+                last_hash: last_hash.clone(),
+            },
+        }
+    }
+
+    fn is_unfilled(&self, block_height: u64) -> bool {
+        self.unfilled_gaps
+            .iter()
+            .any(|(first_height, last_height)| (*first_height..=*last_height).contains(&block_height))
+    }
+
+    /// Mark the block as delivered: either the next one after the last delivered block, or a late block from unfilled gap
+    pub(crate) fn delivered(&mut self, block_height: u64, block_hash: String) {
+        if let Some(position) = self
+            .unfilled_gaps
+            .iter()
+            .position(|(first_height, last_height)| (*first_height..=*last_height).contains(&block_height))
+        {
+            let (first_height, last_height) = self.unfilled_gaps.remove(position);
+            if first_height < block_height {
+                self.unfilled_gaps.push((first_height, block_height - 1));
+            }
+            if block_height < last_height {
+                self.unfilled_gaps.push((block_height + 1, last_height));
+            }
+            return;
+        }
+        if self.last_delivered.as_ref().map_or(true, |(last_height, _)| block_height > *last_height) {
+            self.last_delivered = Some((block_height, block_hash));
+        }
+    }
+
+    /// Remember heights of the gap before the received block, which remain unfilled after backfilling:
+    /// heights below the first backfilled block (its height and previous block hash), unless it continues the hash chain
+    pub(crate) fn backfilled(&mut self, block_height: u64, first_backfilled: Option<(u64, String)>) {
+        let (last_height, last_hash) = match &self.last_delivered {
+            Some(last_delivered) => last_delivered.clone(),
+            None => return,
+        };
+        let unfilled_until = match first_backfilled {
+            Some((_, prev_hash)) if prev_hash == last_hash => return,
+            Some((first_height, _)) => first_height,
+            None => block_height,
+        };
+        self.unfilled(last_height + 1, unfilled_until.saturating_sub(1));
+    }
+
+    /// Remember height range of the gap, which hasn't been backfilled
+    pub(crate) fn unfilled(&mut self, first_height: u64, last_height: u64) {
+        if first_height > last_height {
+            return;
+        }
+        self.unfilled_gaps.push((first_height, last_height));
+        if self.unfilled_gaps.len() > MAX_UNFILLED_GAPS {
+            self.unfilled_gaps.remove(0);
+        }
+    }
+}
+
+/// Tracking of blocks continuity (by height and previous block hash) for delivering gap-free stream of blocks to the handler,
+/// with backfilling of missing blocks from JetStream stream or from the indexer's query service
+pub(crate) struct GapFiller {
+    nats_connection: Option<nats::Connection>,
+    context: RunArgs,
+    state: DeliveryState,
+}
+
+impl GapFiller {
+    pub(crate) fn new(nats_connection: nats::Connection, context: &RunArgs) -> Self {
+        Self {
+            nats_connection: Some(nats_connection),
+            context: context.clone(),
+            state: DeliveryState::default(),
+        }
+    }
+
+    /// Delivery state, to be rewound if delivered blocks fail to be handled
+    pub(crate) fn state(&self) -> DeliveryState {
+        self.state.clone()
+    }
+
+    /// Rewind the delivery state, so blocks which failed to be handled aren't skipped on redelivery
+    pub(crate) fn rewind(&mut self, state: DeliveryState) {
+        self.state = state;
+    }
+
+    /// Blocks to be delivered to the handler for the received block, in order of heights:
+    /// missing blocks (if there is a gap and backfilling is enabled) followed by the block itself,
+    /// or nothing, if the block has been already delivered. Block from unfilled gap, which arrives late, is delivered as well,
+    /// so the block is never skipped (and acknowledged) unless it's been delivered before.
+    pub(crate) fn deliver(
+        &mut self,
+        streamer_message: StreamerMessage,
+        stream_sequence: Option<u64>,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Vec<StreamerMessage> {
+        let block_height = streamer_message.block.header.height;
+        let prev_hash = streamer_message.block.header.prev_hash.to_string();
+        let mut blocks = Vec::new();
+
+        match self.state.continuity(block_height, &prev_hash) {
+            Continuity::Next => {}
+            Continuity::AlreadyDelivered => {
+                info!(target: "borealis_consumer", "Block #{} has been already delivered, skipped\n", block_height);
+                return blocks;
+            }
+            Continuity::Late => {
+                info!(target: "borealis_consumer", "Block #{} from unfilled gap arrived late, delivered out of order\n", block_height);
+            }
+            Continuity::Gap { last_height, last_hash } => {
+                info!(target: "borealis_consumer", "Gap detected between the last delivered block #{} and block #{}\n", last_height, block_height);
+                let result = match self.context.backfill {
+                    Some(BackfillSource::Stream) => self.stream_backfill(
+                        last_height,
+                        block_height,
+                        stream_sequence,
+                        zstd_dictionaries,
+                    ),
+                    Some(BackfillSource::Query) => self.query_backfill(last_height, &last_hash, &prev_hash),
+                    None => Ok(Vec::new()),
+                };
+                let missing_blocks = result.unwrap_or_else(|error| {
+                    error!(target: "borealis_consumer", "Backfill: Error while fetching missing blocks, the gap remains: {:?}", error);
+                    Vec::new()
+                });
+                info!(target: "borealis_consumer", "Backfill: {} missing blocks fetched\n", missing_blocks.len());
+                let first_backfilled = missing_blocks.first().map(|first_block| {
+                    (first_block.block.header.height, first_block.block.header.prev_hash.to_string())
+                });
+                self.state.backfilled(block_height, first_backfilled);
+                blocks.extend(missing_blocks);
+            }
+        }
+
+        blocks.push(streamer_message);
+        for block in blocks.iter() {
+            self.state
+                .delivered(block.block.header.height, block.block.header.hash.to_string());
+        }
+        blocks
+    }
+
+    fn nats_connection(&self) -> Result<&nats::Connection, Error> {
+        self.nats_connection
+            .as_ref()
+            .ok_or_else(|| "Backfill: NATS connection isn't established".into())
+    }
+
+    /// Fetch missing blocks from JetStream stream, walking back by stream sequences from the received message
+    /// (or from the last message on the subject), until the last delivered block
+    fn stream_backfill(
+        &self,
+        last_height: u64,
+        block_height: u64,
+        stream_sequence: Option<u64>,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<Vec<StreamerMessage>, Error> {
+        let subject = format!("{}_{}", self.context.subject, self.context.msg_format.to_string());
+        let stream = format!("JS_{}", subject);
+
+        let mut sequence = match stream_sequence {
+            Some(stream_sequence) => stream_sequence.saturating_sub(1),
+            None => match stream_message_get(self.nats_connection()?, &stream, serde_json::json!({ "last_by_subj": subject }))? {
+                Some(raw_message) => raw_message.seq,
+                None => return Ok(Vec::new()),
+            },
+        };
+
+        let mut missing_blocks: Vec<StreamerMessage> = Vec::new();
+        while sequence > 0 && missing_blocks.len() < MAX_BACKFILL_BLOCKS {
+            // Failed lookup leaves the gap unfilled, rather than delivering only a part of missing blocks
+            let raw_message = stream_message_get(self.nats_connection()?, &stream, serde_json::json!({ "seq": sequence }))
+                .map_err(|error| format!("Backfill: Stream message #{} is unavailable: {:?}", sequence, error))?;
+            sequence -= 1;
+            let raw_message = match raw_message {
+                Some(raw_message) if raw_message.subject == subject => raw_message,
+                // Deleted messages and messages of other subjects are skipped
+                _ => continue,
+            };
+            let streamer_message: StreamerMessage = raw_message.decode(self.context.msg_format, zstd_dictionaries)?;
+            let height = streamer_message.block.header.height;
+            if height <= last_height {
+                break;
+            }
+            if height < block_height {
+                missing_blocks.push(streamer_message);
+            }
+        }
+
+        missing_blocks.sort_by_key(|streamer_message| streamer_message.block.header.height);
+        missing_blocks.dedup_by_key(|streamer_message| streamer_message.block.header.height);
+        Ok(missing_blocks)
+    }
+
+    /// Fetch missing blocks from the indexer's query service, walking back by previous block hashes until the last delivered block
+    fn query_backfill(
+        &self,
+        last_height: u64,
+        last_hash: &str,
+        prev_hash: &str,
+    ) -> Result<Vec<StreamerMessage>, Error> {
+        let mut missing_blocks: Vec<StreamerMessage> = Vec::new();
+        let mut block_hash = prev_hash.to_string();
+        while block_hash != last_hash {
+            if missing_blocks.len() >= MAX_BACKFILL_BLOCKS {
+                return Err(format!("Backfill: More than {} blocks are missing after block #{}", MAX_BACKFILL_BLOCKS, last_height).into());
+            }
+            let streamer_message = self.query_message(&block_hash)?;
+            if streamer_message.block.header.height <= last_height {
+                return Err(format!(
+                    "Backfill: Fork detected, block {} doesn't lead to the last delivered block #{} ({})",
+                    prev_hash, last_height, last_hash
+                )
+                .into());
+            }
+            block_hash = streamer_message.block.header.prev_hash.to_string();
+            missing_blocks.push(streamer_message);
+        }
+        missing_blocks.reverse();
+        Ok(missing_blocks)
+    }
+
+    fn query_message(&self, block_hash: &str) -> Result<StreamerMessage, Error> {
+        let reply = self.nats_connection()?.request_timeout(
+            format!("{}.Message.{}", self.context.query_subject, block_hash).as_str(),
+            b"",
+            BACKFILL_REQUEST_TIMEOUT,
+        )?;
+        // Reply format is described by its headers (or detected from the reply itself for older indexers)
+        let msg_format = Envelope::from_headers(reply.headers.as_ref(), &reply.data, self.context.msg_format)?.msg_format;
+        let borealis_message: BorealisMessage<QueryReply> = match msg_format {
+            MsgFormat::Cbor => BorealisMessage::from_cbor(&reply.data)
+                .ok_or("[From CBOR bytes vector: message empty] Query reply decoding error")?,
+            MsgFormat::Json => BorealisMessage::from_json_bytes(&reply.data)
+                .ok_or("[From JSON bytes vector: message empty] Query reply decoding error")?,
+        }
+        .map_err(|error| format!("Backfill: Query reply decoding error: {:?}", error))?;
+        match borealis_message.payload {
+            QueryReply::Message(streamer_message) => Ok(streamer_message),
+            QueryReply::Error(query_error) => Err(format!(
+                "Backfill: Query error for block {}: {}: {}",
+                block_hash, query_error.kind, query_error.message
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivered(heights: std::ops::RangeInclusive<u64>) -> DeliveryState {
+        let mut state = DeliveryState::default();
+        for height in heights {
+            state.delivered(height, format!("hash{}", height));
+        }
+        state
+    }
+
+    #[test]
+    fn continuity_of_hash_chain() {
+        let mut state = DeliveryState::default();
+        assert_eq!(state.continuity(100, "hash99"), Continuity::Next);
+        state.delivered(100, "hash100".to_string());
+        assert_eq!(state.continuity(101, "hash100"), Continuity::Next);
+        assert_eq!(state.continuity(100, "hash99"), Continuity::AlreadyDelivered);
+        assert_eq!(state.continuity(90, "hash89"), Continuity::AlreadyDelivered);
+        assert_eq!(
+            state.continuity(105, "hash104"),
+            Continuity::Gap {
+                last_height: 100,
+                last_hash: "hash100".to_string()
+            }
+        );
+        // Fork at the next height is a gap as well
+        assert_eq!(
+            state.continuity(101, "fork100"),
+            Continuity::Gap {
+                last_height: 100,
+                last_hash: "hash100".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn gap_without_backfilled_blocks_remains_unfilled() {
+        let mut state = delivered(100..=100);
+        state.backfilled(105, None);
+        state.delivered(105, "hash105".to_string());
+        assert_eq!(state.unfilled_gaps, vec![(101, 104)]);
+        assert_eq!(state.continuity(106, "hash105"), Continuity::Next);
+        for height in 101..=104 {
+            assert_eq!(state.continuity(height, "any"), Continuity::Late);
+        }
+        assert_eq!(state.continuity(100, "hash99"), Continuity::AlreadyDelivered);
+    }
+
+    #[test]
+    fn gap_backfilled_from_the_last_delivered_block() {
+        let mut state = delivered(100..=100);
+        state.backfilled(105, Some((101, "hash100".to_string())));
+        for height in 101..=105 {
+            state.delivered(height, format!("hash{}", height));
+        }
+        assert!(state.unfilled_gaps.is_empty());
+        assert_eq!(state.continuity(103, "hash102"), Continuity::AlreadyDelivered);
+        assert_eq!(state.continuity(106, "hash105"), Continuity::Next);
+    }
+
+    #[test]
+    fn gap_partially_backfilled() {
+        let mut state = delivered(100..=100);
+        state.backfilled(105, Some((103, "hash102".to_string())));
+        for height in 103..=105 {
+            state.delivered(height, format!("hash{}", height));
+        }
+        assert_eq!(state.unfilled_gaps, vec![(101, 102)]);
+        assert_eq!(state.continuity(102, "hash101"), Continuity::Late);
+        assert_eq!(state.continuity(103, "hash102"), Continuity::AlreadyDelivered);
+    }
+
+    #[test]
+    fn late_blocks_split_unfilled_gap() {
+        let mut state = delivered(100..=100);
+        state.backfilled(110, None);
+        state.delivered(110, "hash110".to_string());
+        assert_eq!(state.unfilled_gaps, vec![(101, 109)]);
+
+        // Late block is delivered, without moving the last delivered block back
+        state.delivered(105, "hash105".to_string());
+        assert_eq!(state.unfilled_gaps, vec![(101, 104), (106, 109)]);
+        assert_eq!(state.continuity(105, "hash104"), Continuity::AlreadyDelivered);
+        assert_eq!(state.continuity(111, "hash110"), Continuity::Next);
+
+        state.delivered(101, "hash101".to_string());
+        state.delivered(109, "hash109".to_string());
+        assert_eq!(state.unfilled_gaps, vec![(102, 104), (106, 108)]);
+        assert_eq!(state.continuity(101, "hash100"), Continuity::AlreadyDelivered);
+        assert_eq!(state.continuity(102, "hash101"), Continuity::Late);
+    }
+
+    #[test]
+    fn rewound_state_delivers_blocks_again() {
+        let mut state = delivered(100..=100);
+        let rewind = state.clone();
+        state.delivered(101, "hash101".to_string());
+        assert_eq!(state.continuity(101, "hash100"), Continuity::AlreadyDelivered);
+        state = rewind;
+        assert_eq!(state.continuity(101, "hash100"), Continuity::Next);
+    }
+
+    #[test]
+    fn unfilled_gaps_are_bounded() {
+        let mut state = delivered(1_000_000..=1_000_000);
+        for gap in 0..(MAX_UNFILLED_GAPS as u64 + 10) {
+            state.unfilled(gap * 10 + 1, gap * 10 + 5);
+        }
+        assert_eq!(state.unfilled_gaps.len(), MAX_UNFILLED_GAPS);
+        // The oldest gaps are forgotten
+        assert_eq!(state.continuity(3, "any"), Continuity::AlreadyDelivered);
+        assert_eq!(state.continuity(MAX_UNFILLED_GAPS as u64 * 10 + 3, "any"), Continuity::Late);
+        state.unfilled(5, 4);
+        assert_eq!(state.unfilled_gaps.len(), MAX_UNFILLED_GAPS);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::acknowledgement::ProcessingError;
+use crate::configs::{DlqReplayArgs, Error, RunArgs};
+use borealis_envelope::ZstdDictionaries;
+use crate::management::default_dlq_stream_name;
+use crate::sink::Sink;
+use crate::{message_decoder, message_processor, metrics, sink_flush};
+use nats::jetstream::{AckPolicy, Consumer, ConsumerConfig, DeliverPolicy, ReplayPolicy};
+use serde::Deserialize;
+use tracing::{error, info};
+
+/// Failure class of dead-letter message: `Decoding` or `Handling`
+pub(crate) const HEADER_DLQ_ERROR_CLASS: &str = "Borealis-Dlq-Error-Class";
+/// Error description of dead-letter message (single line)
+pub(crate) const HEADER_DLQ_ERROR: &str = "Borealis-Dlq-Error";
+/// Subject, on which the message has been received
+pub(crate) const HEADER_DLQ_ORIGINAL_SUBJECT: &str = "Borealis-Dlq-Original-Subject";
+/// Sequence of the message in JetStream stream, if it's been received from JetStream consumer
+pub(crate) const HEADER_DLQ_STREAM_SEQUENCE: &str = "Borealis-Dlq-Stream-Sequence";
+/// Number of deliveries of the message by JetStream consumer
+pub(crate) const HEADER_DLQ_DELIVERED: &str = "Borealis-Dlq-Delivered";
+/// Time of the failure (RFC 3339)
+pub(crate) const HEADER_DLQ_FAILED_AT: &str = "Borealis-Dlq-Failed-At";
+
+/// Timeout for awaiting of the next message from dead-letter stream, after which the stream is considered to be replayed to the end
+const DLQ_STREAM_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10000);
+/// Timeout for requests to JetStream API
+const DLQ_REQUEST_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10000);
+
+/// Dead-letter subject: `--dead-letter-subject`, or `<subject>_<msg-format>.dlq` with `--dlq` flag
+pub(crate) fn dead_letter_subject(context: &RunArgs) -> Option<String> {
+    context.dead_letter_subject.clone().or_else(|| {
+        context
+            .dlq
+            .then(|| format!("{}_{}.dlq", context.subject, context.msg_format.to_string()))
+    })
+}
+
+/// Republish the failed message intact (original headers and data) to dead-letter subject, with error metadata headers
+pub(crate) fn publish(
+    nats_connection: &nats::Connection,
+    msg: &nats::Message,
+    processing_error: &ProcessingError,
+    context: &RunArgs,
+) -> Result<(), Error> {
+    let dead_letter_subject = match dead_letter_subject(context) {
+        Some(dead_letter_subject) => dead_letter_subject,
+        None => {
+            error!(target: "borealis_consumer", "Message is dropped, dead-letter subject isn't set (`--dlq` or `--dead-letter-subject`):\n{}", msg);
+            return Ok(());
+        }
+    };
+
+    let mut headers = msg.headers.clone().unwrap_or_default();
+    insert_header(&mut headers, HEADER_DLQ_ERROR_CLASS, processing_error.class().to_string());
+    insert_header(
+        &mut headers,
+        HEADER_DLQ_ERROR,
+        format!("{}", processing_error.error()).replace(|c: char| c == '\r' || c == '\n', " "),
+    );
+    insert_header(&mut headers, HEADER_DLQ_ORIGINAL_SUBJECT, msg.subject.clone());
+    if let Some(info) = msg.jetstream_message_info() {
+        insert_header(&mut headers, HEADER_DLQ_STREAM_SEQUENCE, info.stream_seq.to_string());
+        insert_header(&mut headers, HEADER_DLQ_DELIVERED, info.delivered.to_string());
+    }
+    insert_header(&mut headers, HEADER_DLQ_FAILED_AT, chrono::Utc::now().to_rfc3339());
+
+    match jetstream_publish(nats_connection, &dead_letter_subject, &headers, &msg.data) {
+        Ok(publish_ack) => {
+            metrics::DEAD_LETTER_MESSAGES
+                .with_label_values(&[processing_error.class()])
+                .inc();
+            info!(
+                target: "borealis_consumer",
+                "Message is republished to dead-letter subject {}, stream: {}, sequence: {}",
+                dead_letter_subject, publish_ack.stream, publish_ack.seq
+            );
+            Ok(())
+        }
+        Err(error) => {
+            metrics::DEAD_LETTER_PUBLISH_FAILURES.inc();
+            Err(format!("Error while republishing message to dead-letter subject {}: {:?}", dead_letter_subject, error).into())
+        }
+    }
+}
+
+fn insert_header(headers: &mut nats::Headers, name: &str, value: String) {
+    headers
+        .inner
+        .insert(name.to_string(), std::iter::once(value).collect());
+}
+
+/// Acknowledgement of message stored by JetStream stream
+#[derive(Debug)]
+struct PublishAck {
+    stream: String,
+    seq: u64,
+}
+
+/// Reply of JetStream for published message
+#[derive(Deserialize, Debug)]
+struct PublishAckResponse {
+    stream: Option<String>,
+    seq: Option<u64>,
+    error: Option<serde_json::Value>,
+}
+
+/// Publish message to JetStream stream, awaiting for acknowledgement of the message being stored by the stream.
+/// Empty reply (no responders, when there is no stream for the subject) and JetStream error reply are failures.
+fn jetstream_publish(
+    nats_connection: &nats::Connection,
+    subject: &str,
+    headers: &nats::Headers,
+    message_bytes: &[u8],
+) -> Result<PublishAck, Error> {
+    let ack_inbox = nats_connection.new_inbox();
+    let ack_subscription = nats_connection.subscribe(&ack_inbox)?;
+    let result = nats_connection
+        .publish_with_reply_or_headers(subject, Some(&ack_inbox), Some(headers), message_bytes)
+        .and_then(|_| ack_subscription.next_timeout(DLQ_REQUEST_TIMEOUT));
+    ack_subscription.unsubscribe().unwrap_or_else(|error| {
+        error!(target: "borealis_consumer", "Acknowledgement subscription unsubscribe error: {:?}", error)
+    });
+    let ack = result?;
+    if ack.data.is_empty() {
+        return Err(format!("No acknowledgement from JetStream (no stream for the subject?), headers: {:?}", ack.headers).into());
+    }
+    let response: PublishAckResponse = serde_json::from_slice(&ack.data)?;
+    match response {
+        PublishAckResponse {
+            stream: Some(stream),
+            seq: Some(seq),
+            error: None,
+        } => Ok(PublishAck { stream, seq }),
+        PublishAckResponse { error, .. } => Err(format!("JetStream publish error: {:?}", error).into()),
+    }
+}
+
+/// Reply of JetStream API for stream message delete request
+#[derive(Deserialize, Debug)]
+struct StreamMessageDeleteResponse {
+    success: Option<bool>,
+    error: Option<serde_json::Value>,
+}
+
+/// Delete replayed message from dead-letter stream
+fn stream_message_delete(nats_connection: &nats::Connection, stream: &str, sequence: u64) -> Result<(), Error> {
+    let reply = nats_connection.request_timeout(
+        format!("$JS.API.STREAM.MSG.DELETE.{}", stream).as_str(),
+        serde_json::to_vec(&serde_json::json!({ "seq": sequence }))?,
+        DLQ_REQUEST_TIMEOUT,
+    )?;
+    let response: StreamMessageDeleteResponse = serde_json::from_slice(&reply.data)?;
+    match (response.success, response.error) {
+        (Some(true), None) => Ok(()),
+        (_, error) => Err(format!("Stream message delete error: {:?}", error).into()),
+    }
+}
+
+/// Reprocess messages from dead-letter stream (JetStream stream, which stores dead-letter subject) by the sink.
+/// Successfully reprocessed messages are deleted from the stream (unless `--dlq-keep` is set), failed ones remain in it.
+pub(crate) fn replay(
+    nats_connection: &nats::Connection,
+    context: &DlqReplayArgs,
+    zstd_dictionaries: &ZstdDictionaries,
+    sink: &mut dyn Sink,
+) -> Result<(), Error> {
+    let run_args = &context.run_args;
+    let dlq_stream = context
+        .dlq_stream
+        .clone()
+        .unwrap_or_else(|| default_dlq_stream_name(run_args));
+
+    // Ephemeral consumer without acknowledgements, so replay doesn't affect durable consumers of the stream
+    let mut consumer = Consumer::create_or_open(
+        nats_connection.clone(),
+        dlq_stream.as_str(),
+        ConsumerConfig {
+            deliver_subject: Some(nats_connection.new_inbox()),
+            deliver_policy: DeliverPolicy::All,
+            ack_policy: AckPolicy::None,
+            replay_policy: ReplayPolicy::Instant,
+            ..Default::default()
+        },
+    )?;
+    consumer.timeout = DLQ_STREAM_TIMEOUT;
+    info!(target: "borealis_consumer", "DLQ replay: Replaying messages of dead-letter stream {}", dlq_stream);
+
+    let mut replayed: u64 = 0;
+    let mut failed: u64 = 0;
+    loop {
+        let msg = match consumer.process_timeout(|msg| Ok(msg.clone())) {
+            Ok(msg) => msg,
+            Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+                info!(target: "borealis_consumer", "DLQ replay: No messages within {:?}, the stream has been replayed to the end", DLQ_STREAM_TIMEOUT);
+                break;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let stream_sequence = msg.jetstream_message_info().map(|info| info.stream_seq);
+
+        let result = message_decoder(&msg, run_args, zstd_dictionaries)
+            .map_err(ProcessingError::Decoding)
+            .and_then(|streamer_message| {
+                message_processor(streamer_message, sink)
+                    .and_then(|_| sink_flush(sink))
+                    .map_err(ProcessingError::Handling)
+            });
+        match result {
+            Ok(()) => {
+                replayed += 1;
+                if let (false, Some(stream_sequence)) = (context.dlq_keep, stream_sequence) {
+                    stream_message_delete(nats_connection, &dlq_stream, stream_sequence)?;
+                }
+            }
+            Err(processing_error) => {
+                failed += 1;
+                error!(
+                    target: "borealis_consumer",
+                    "DLQ replay: Message #{:?} failed again and remains in dead-letter stream: {:?}",
+                    stream_sequence, processing_error
+                );
+            }
+        }
+    }
+
+    info!(target: "borealis_consumer", "DLQ replay: Finished, {} messages replayed, {} messages failed", replayed, failed);
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use crate::configs::{Error, RunArgs};
+use borealis_envelope::ZstdDictionaries;
+use borealis_types::types::StreamerMessage;
+use tracing::debug;
+
+pub(crate) use borealis_envelope::{block_height, Envelope};
+
+/// Decode `StreamerMessage` from Borealis Message received from NATS subject, accordingly to envelope description
+/// from message headers. Encoding of legacy messages without headers is detected from the message itself
+/// (`--msg-format`, if it can't be detected), and compression codec of their payload by frame format magic number.
+pub(crate) fn decode_message(
+    msg: &nats::Message,
+    context: &RunArgs,
+    zstd_dictionaries: &ZstdDictionaries,
+) -> Result<StreamerMessage, Error> {
+    let envelope = Envelope::from_headers(msg.headers.as_ref(), &msg.data, context.msg_format)?;
+    debug!(target: "borealis_consumer", "Decoding message with envelope: {:?}", envelope);
+    envelope.decode(&msg.data, zstd_dictionaries)
+}
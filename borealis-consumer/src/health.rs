@@ -0,0 +1,36 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Whether the consumer is subscribed to the subject (or JetStream consumer has been created or opened)
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+/// Time of the last received message (or subscription), milliseconds since Unix epoch
+static LAST_MESSAGE_RECEIVED: AtomicU64 = AtomicU64::new(0);
+/// Maximum time without received messages (milliseconds), within which the consumer is still considered ready
+static STALENESS_WINDOW: AtomicU64 = AtomicU64::new(60000);
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub(crate) fn set_staleness_window(staleness_window: u64) {
+    STALENESS_WINDOW.store(staleness_window, Ordering::SeqCst);
+}
+
+/// Mark the consumer as subscribed, staleness window starts from the subscription time
+pub(crate) fn observe_subscribed() {
+    LAST_MESSAGE_RECEIVED.store(now_millis(), Ordering::SeqCst);
+    SUBSCRIBED.store(true, Ordering::SeqCst);
+}
+
+pub(crate) fn observe_received_message() {
+    LAST_MESSAGE_RECEIVED.store(now_millis(), Ordering::SeqCst);
+}
+
+/// The consumer is ready when it's subscribed and messages are flowing within the staleness window
+pub(crate) fn is_ready() -> bool {
+    SUBSCRIBED.load(Ordering::SeqCst)
+        && now_millis().saturating_sub(LAST_MESSAGE_RECEIVED.load(Ordering::SeqCst))
+            <= STALENESS_WINDOW.load(Ordering::SeqCst)
+}
@@ -1,24 +1,44 @@
-use actix;
+use acknowledgement::ProcessingError;
+use backfill::GapFiller;
 use clap::Parser;
-use configs::{init_logging, MsgFormat, Opts, RunArgs, SubCommand, VerbosityLevel, WorkMode, CompressionMode};
+use configs::{init_logging, DlqCommand, Error, Opts, RunArgs, SubCommand, WorkMode};
+use borealis_envelope::ZstdDictionaries;
+use pull_consumer::PullConsumer;
+use shards::ShardsAssembler;
+use sink::Sink;
+use start_position::start_position;
 use nats;
 use nats::jetstream::{
     AckPolicy, Consumer, ConsumerConfig, DeliverPolicy, DiscardPolicy, ReplayPolicy,
     RetentionPolicy, StorageType, StreamConfig,
 };
 // use near_indexer::StreamerMessage;
-use borealis_types::types::{BorealisMessage, StreamerMessage};
-use serde_cbor as cbor;
-use serde_json;
-use tracing:: {info, error};
+use borealis_types::types::StreamerMessage;
+use tracing:: {debug, info, error};
 
+pub mod acknowledgement;
+pub mod backfill;
 pub mod configs;
-
+pub mod dead_letter;
+pub mod envelope;
+pub mod health;
+pub mod management;
+pub mod metrics;
+pub mod postgres_sink;
+pub mod pull_consumer;
+pub mod shards;
+pub mod sink;
+pub mod start_position;
+
+/// Returns decoding error for poison message, or handling error if blocks weren't handled by the sink,
+/// so the message shouldn't be acknowledged
 fn message_consumer(
-    msg: nats::Message,
+    msg: &nats::Message,
     context: RunArgs,
-    verbosity_level: Option<VerbosityLevel>,
-) {
+    zstd_dictionaries: &ZstdDictionaries,
+    gap_filler: &mut GapFiller,
+    sink: &mut dyn Sink,
+) -> Result<(), ProcessingError> {
     /*
         Example of `StreamerMessage` with all data fields (filled with synthetic data, as an example):
 
@@ -257,36 +277,109 @@ fn message_consumer(
         "Message consumer loop executed: message received\n"
     );
 
-    let streamer_message = if let Some(compression_mode) = context.payload_compression {
-        // Decoding of Borealis Message receved from NATS subject
-        let borealis_message: BorealisMessage<Vec<u8>> = match context.msg_format {
-            MsgFormat::Cbor => BorealisMessage::from_cbor(msg.data.as_ref())
-                .expect("[From CBOR bytes vector: message empty] Message decoding error").unwrap(),
-            MsgFormat::Json => BorealisMessage::from_json_bytes(msg.data.as_ref())
-                .expect("[From JSON bytes vector: message empty] Message decoding error").unwrap(),
-        };
-        // Get `StreamerMessage` from received Borealis Message
-        let (payload_bytes_decompressed, _payload_len) = match compression_mode {
-            CompressionMode::Lz4f => BorealisMessage::<Vec<u8>>::payload_decompress_lz4(&borealis_message.payload).unwrap(),
-            CompressionMode::Zstd => BorealisMessage::<Vec<u8>>::payload_decompress_zstd(&borealis_message.payload).unwrap(),
-        };
-        let streamer_message: StreamerMessage = serde_json::from_slice(&payload_bytes_decompressed).unwrap();
-        streamer_message
-    } else {
-        // Decoding of Borealis Message receved from NATS subject
-        let borealis_message: BorealisMessage<StreamerMessage> = match context.msg_format {
-            MsgFormat::Cbor => BorealisMessage::from_cbor(msg.data.as_ref())
-                .expect("[From CBOR bytes vector: message empty] Message decoding error").unwrap(),
-            MsgFormat::Json => BorealisMessage::from_json_bytes(msg.data.as_ref())
-                .expect("[From JSON bytes vector: message empty] Message decoding error").unwrap(),
-        };
-        // Get `StreamerMessage` from received Borealis Message
-        let streamer_message: StreamerMessage = borealis_message.payload;
-        streamer_message
-    };
+    let streamer_message =
+        message_decoder(msg, &context, zstd_dictionaries).map_err(ProcessingError::Decoding)?;
+
+    // Gap-free stream of blocks, with missing blocks backfilled before the received one.
+    // If blocks aren't handled or committed by the sink, the delivery state is rewound entirely,
+    // so the redelivered message isn't skipped as already delivered.
+    let delivery_state = gap_filler.state();
+    let stream_sequence = msg.jetstream_message_info().map(|info| info.stream_seq);
+    for streamer_message in gap_filler.deliver(streamer_message, stream_sequence, zstd_dictionaries) {
+        if let Err(error) = message_processor(streamer_message, sink) {
+            gap_filler.rewind(delivery_state);
+            return Err(ProcessingError::Handling(error));
+        }
+    }
+    if let Err(error) = sink_flush(sink) {
+        gap_filler.rewind(delivery_state);
+        return Err(ProcessingError::Handling(error));
+    }
+    Ok(())
+}
+
+/// Message consuming for horizontally scaled subscribers (queue group or partitions by block height modulo),
+/// without tracking of blocks continuity. Partitioned subscriber handles only blocks of its partition: messages are routed
+/// by block height header before decoding, and messages without the header (from older indexers) after decoding.
+fn scaled_message_consumer(
+    msg: &nats::Message,
+    context: &RunArgs,
+    zstd_dictionaries: &ZstdDictionaries,
+    sink: &mut dyn Sink,
+) -> Result<(), ProcessingError> {
+    if let Some(block_height) = envelope::block_height(msg.headers.as_ref()) {
+        if !in_partition(block_height, context) {
+            debug!(target: "borealis_consumer", "Block #{} belongs to another partition, skipped without decoding\n", block_height);
+            return Ok(());
+        }
+    }
+    let streamer_message =
+        message_decoder(msg, context, zstd_dictionaries).map_err(ProcessingError::Decoding)?;
+    let block_height = streamer_message.block.header.height;
+    if !in_partition(block_height, context) {
+        debug!(target: "borealis_consumer", "Block #{} belongs to another partition, skipped\n", block_height);
+        return Ok(());
+    }
+    message_processor(streamer_message, sink).map_err(ProcessingError::Handling)?;
+    sink_flush(sink).map_err(ProcessingError::Handling)
+}
+
+/// Whether the block belongs to the partition of the subscriber (`--partition-index` of block height modulo `--partition-count`),
+/// always true without partitioning
+fn in_partition(block_height: u64, context: &RunArgs) -> bool {
+    match (context.partition_count, context.partition_index) {
+        (Some(partition_count), Some(partition_index)) => block_height % partition_count == partition_index,
+        _ => true,
+    }
+}
 
-    // Data handling from `StreamerMessage` data structure. For custom filtering purposes.
-    // Same as: jq '{block_height: .block.header.height, block_hash: .block.header.hash, block_header_chunk: .block.chunks[0], shard_chunk_header: .shards[0].chunk.header, transactions: .shards[0].chunk.transactions, receipts: .shards[0].chunk.receipts, receipt_execution_outcomes: .shards[0].receipt_execution_outcomes, state_changes: .state_changes}'
+/// Decoding of Borealis Message received from NATS subject, accordingly to envelope description from message headers
+fn message_decoder(
+    msg: &nats::Message,
+    context: &RunArgs,
+    zstd_dictionaries: &ZstdDictionaries,
+) -> Result<StreamerMessage, Error> {
+    metrics::RECEIVED_MESSAGES.inc();
+    health::observe_received_message();
+    let decode_latency_timer = metrics::DECODE_LATENCY.start_timer();
+    // Get `StreamerMessage` from received Borealis Message
+    let result = envelope::decode_message(msg, context, zstd_dictionaries);
+    decode_latency_timer.observe_duration();
+    match &result {
+        Ok(_) => metrics::DECODED_MESSAGES.inc(),
+        Err(_) => metrics::FAILED_MESSAGES.inc(),
+    }
+    result
+}
+
+/// Run messages consuming and processing loop on a dedicated thread, outside of any async runtime,
+/// since sinks use blocking clients (e.g. PostgreSQL client, which blocks on its own internal runtime)
+fn spawn_consumer<F, T>(consume: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(String::from("consumer"))
+        .spawn(consume)
+        .expect("Main(): Run(): Error while spawning messages consumer thread")
+}
+
+/// Data handling of `StreamerMessage`, decoded from the whole block message or reassembled from per-shard messages
+fn message_processor(streamer_message: StreamerMessage, sink: &mut dyn Sink) -> Result<(), Error> {
+    if let Some(last_block_height) = metrics::observe_processed_block(
+        streamer_message.block.header.height,
+        streamer_message.block.header.hash.to_string(),
+        streamer_message.block.header.prev_hash.to_string(),
+        streamer_message.block.header.timestamp_nanosec,
+    ) {
+        error!(
+            target: "borealis_consumer",
+            "Gap in blocks sequence detected: block #{} doesn't follow the last processed block #{}\n",
+            &streamer_message.block.header.height,
+            last_block_height
+        );
+    }
 
     info!(
         target: "borealis_consumer",
@@ -295,121 +388,28 @@ fn message_consumer(
         &streamer_message.block.header.hash
     );
 
-    if let Some(_verbosity_level) = verbosity_level {
-        println!(
-            "block_height: #{}, block_hash: {}\n",
-            &streamer_message.block.header.height, &streamer_message.block.header.hash
-        );
-    };
-
-    if let Some(VerbosityLevel::WithStreamerMessageDump)
-    | Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level
-    {
-        println!(
-            "streamer_message: {}\n",
-            serde_json::to_string_pretty(&streamer_message).unwrap()
-        );
-        println!(
-            "streamer_message: {}\n",
-            serde_json::to_string(&streamer_message).unwrap()
-        );
-    };
-
-    if let Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level {
-        println!(
-            "streamer_message: {}\n",
-            serde_json::to_value(&streamer_message).unwrap()
-        );
-        println!(
-            "streamer_message: {:?}\n",
-            cbor::to_vec(&streamer_message).unwrap()
-        );
-
-        println!(
-            "block_header: {}\n",
-            serde_json::to_value(&streamer_message.block.header).unwrap()
-        );
-        println!(
-            "block_header: {:?}\n",
-            cbor::to_vec(&streamer_message.block.header).unwrap()
-        );
-
-        println!(
-            "block_header_chunks#: {}\n",
-            streamer_message.block.chunks.len()
-        );
-        streamer_message.block.chunks.iter().for_each(|chunk| {
-            println!(
-                "block_header_chunk: {}\n",
-                serde_json::to_value(&chunk).unwrap()
-            );
-            println!("block_header_chunk: {:?}\n", cbor::to_vec(&chunk).unwrap());
-        });
-
-        println!("shards#: {}\n", streamer_message.shards.len());
-        streamer_message.shards.iter().for_each(|shard| {
-            if let Some(chunk) = &shard.chunk {
-                println!(
-                    "shard_chunk_header: {}\n",
-                    serde_json::to_value(&chunk.header).unwrap()
-                );
-                println!(
-                    "shard_chunk_header: {:?}\n",
-                    cbor::to_vec(&chunk.header).unwrap()
-                );
-
-                println!("shard_chunk_transactions#: {}\n", chunk.transactions.len());
-                println!(
-                    "shard_chunk_transactions: {}\n",
-                    serde_json::to_value(&chunk.transactions).unwrap()
-                );
-                println!(
-                    "shard_chunk_transactions: {:?}\n",
-                    cbor::to_vec(&chunk.transactions).unwrap()
-                );
-
-                println!("shard_chunk_receipts#: {}\n", chunk.receipts.len());
-                println!(
-                    "shard_chunk_receipts: {}\n",
-                    serde_json::to_value(&chunk.receipts).unwrap()
-                );
-                println!(
-                    "shard_chunk_receipts: {:?}\n",
-                    cbor::to_vec(&chunk.receipts).unwrap()
-                );
-            } else {
-                println!("shard_chunk_header: None\n");
-
-                println!("shard_chunk_transactions#: None\n");
-                println!("shard_chunk_transactions: None\n");
-
-                println!("shard_chunk_receipts#: None\n");
-                println!("shard_chunk_receipts: None\n");
-            };
-
-            println!(
-                "shard_receipt_execution_outcomes#: {}\n",
-                shard.receipt_execution_outcomes.len()
-            );
-            println!(
-                "shard_receipt_execution_outcomes: {}\n",
-                serde_json::to_value(&shard.receipt_execution_outcomes).unwrap()
-            );
-            println!(
-                "shard_receipt_execution_outcomes: {:?}\n",
-                cbor::to_vec(&shard.receipt_execution_outcomes).unwrap()
-            );
+    sink.handle(&streamer_message).map_err(|error| {
+        metrics::HANDLING_FAILURES.inc();
+        error!(target: "borealis_consumer", "Sink: Error while handling block #{}: {:?}", &streamer_message.block.header.height, error);
+        error
+    })
+}
 
-            println!("StateChanges#: {}\n", shard.state_changes.len());
-            shard.state_changes.iter().for_each(|state_change| {
-                println!(
-                    "StateChange: {}\n",
-                    serde_json::to_value(&state_change).unwrap()
-                );
-                println!("StateChange: {:?}\n", cbor::to_vec(&state_change).unwrap());
-            });
-        });
-    };
+/// Flush blocks handled by the sink to the destination
+fn sink_flush(sink: &mut dyn Sink) -> Result<(), Error> {
+    match sink.flush() {
+        Ok(()) => {
+            if let Some(block_height) = sink.checkpoint() {
+                debug!(target: "borealis_consumer", "Sink: Blocks up to #{} are flushed\n", block_height);
+            }
+            Ok(())
+        }
+        Err(error) => {
+            metrics::HANDLING_FAILURES.inc();
+            error!(target: "borealis_consumer", "Sink: Error while flushing blocks: {:?}", error);
+            Err(error)
+        }
+    }
 }
 
 /// Create connection to Borealis NATS Bus
@@ -570,51 +570,167 @@ fn main() {
         SubCommand::Init(run_args) => {
             let nats_connection = nats_connect(run_args.to_owned());
 
-            // JetStreams cannot be created from NATS Client side due to restrictions on NATS server side, but this ability is still available for client side consumers
-            let stream_info = nats_connection.create_stream(StreamConfig {
-                name: format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()),
+            // JetStreams cannot be created from NATS Client side due to restrictions on NATS server side, but this ability is still available for client side consumers.
+            // Initialization is idempotent: existing stream and consumer are updated instead
+            let stream_info = management::stream_create_or_update(&nats_connection, StreamConfig {
+                name: management::default_stream_name(&run_args),
                 discard: DiscardPolicy::Old,
-                subjects: Some(vec![format!("{}_{}", run_args.subject, run_args.msg_format.to_string())]),
-                duplicate_window: 86400,
+                subjects: Some(management::default_stream_subjects(&run_args)),
+                duplicate_window: 86400 * management::NANOS_PER_SECOND,
                 retention: RetentionPolicy::Limits,
                 storage: StorageType::File,
                 ..Default::default()
-            }).expect("IO error, something went wrong while creating a new stream, maybe stream already exist");
+            }).expect("IO error, something went wrong while creating or updating the stream");
 
-            let consumer = nats_connection.create_consumer(format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()).as_str(), ConsumerConfig {
-                deliver_subject: Some(format!("JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())),
-                durable_name: Some(format!("Borealis_Consumer_JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())),
+            let zstd_dictionaries = ZstdDictionaries::load(&run_args.zstd_dictionary)
+                .expect("Main(): Init(): Error while loading Zstd dictionaries for payload decompression");
+            let consumer_config = start_position(&nats_connection, &run_args, stream_info.config.name.as_str(), ConsumerConfig {
+                deliver_subject: Some(management::default_deliver_subject(&run_args)),
+                durable_name: Some(management::default_consumer_name(&run_args)),
                 deliver_policy: DeliverPolicy::All,
                 ack_policy: AckPolicy::Explicit,
-                // filter_subject: format!("{}_{}", run_args.subject, run_args.msg_format.to_string()),
+                max_deliver: run_args.max_deliver,
+                // One unacknowledged message at a time: messages are handled in order, and a NAK'd block stops the delivery of next ones
+                max_ack_pending: 1,
+                filter_subject: management::default_subject(&run_args),
                 replay_policy: ReplayPolicy::Instant,
                 ..Default::default()
-            }).expect("IO error, something went wrong while creating a new consumer, maybe consumer already exist");
+            }, &zstd_dictionaries).expect("Error while resolving start position of the consumer: maybe wrong `--start-height`");
+
+            let consumer_info = management::consumer_create_or_update(&nats_connection, stream_info.config.name.as_str(), consumer_config)
+                .expect("IO error, something went wrong while creating or updating the consumer");
+
+            // Dead-letter stream, if dead-letter subject is set
+            let dlq_stream_info = management::dlq_stream_create_or_bind(&nats_connection, &run_args)
+                .expect("IO error, something went wrong while creating or updating the dead-letter stream");
 
             info!(
                 target: "borealis_consumer",
-                "Initialized:\nStream:\n{:?}\nConsumer:\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+                "Initialized:\nStream:\n{:?}\nConsumer:\n{:?}\nDead-letter stream:\n{:?}",
                 stream_info,
-                consumer.nc,
-                consumer.stream,
-                consumer.cfg,
-                consumer.push_subscriber,
-                consumer.timeout
+                consumer_info,
+                dlq_stream_info
             );
         }
+        // JetStream stream management
+        SubCommand::Stream(stream_command) => {
+            let nats_connection = nats_connect(stream_command.run_args().to_owned());
+            management::stream_command(&nats_connection, stream_command)
+                .unwrap_or_else(|error| error!(target: "borealis_consumer", "Main(): Stream management error: {:?}", error));
+        }
+        // JetStream consumer management
+        SubCommand::Consumer(consumer_command) => {
+            let nats_connection = nats_connect(consumer_command.run_args().to_owned());
+            management::consumer_command(&nats_connection, consumer_command)
+                .unwrap_or_else(|error| error!(target: "borealis_consumer", "Main(): Consumer management error: {:?}", error));
+        }
+        // Dead-letter messages reprocessing
+        SubCommand::Dlq(DlqCommand::Replay(dlq_replay_args)) => {
+            let zstd_dictionaries = ZstdDictionaries::load(&dlq_replay_args.run_args.zstd_dictionary)
+                .expect("Main(): Dlq(): Error while loading Zstd dictionaries for payload decompression");
+            let mut sink = sink::sink(&dlq_replay_args.run_args, opts.verbose)
+                .expect("Main(): Dlq(): Error while creating sink for consumed blocks");
+            let nats_connection = nats_connect(dlq_replay_args.run_args.to_owned());
+            dead_letter::replay(&nats_connection, &dlq_replay_args, &zstd_dictionaries, sink.as_mut())
+                .unwrap_or_else(|error| error!(target: "borealis_consumer", "Main(): Dlq(): Dead-letter messages replay error: {:?}", error));
+        }
         SubCommand::Run(run_args) => {
+            if let (Some(partition_count), Some(partition_index)) = (run_args.partition_count, run_args.partition_index) {
+                assert!(
+                    partition_index < partition_count,
+                    "Main(): Run(): `--partition-index` should be less than `--partition-count`"
+                );
+            }
+            health::set_staleness_window(run_args.readiness_staleness);
+            if let Some(metrics_address) = run_args.metrics_address {
+                metrics::metrics_server(metrics_address);
+            }
+            let zstd_dictionaries = ZstdDictionaries::load(&run_args.zstd_dictionary)
+                .expect("Main(): Run(): Error while loading Zstd dictionaries for payload decompression");
             let nats_connection = nats_connect(run_args.to_owned());
-            let system = actix::System::new();
-            system.block_on(async move {
+            let verbosity_level = opts.verbose;
+            let consumer = spawn_consumer(move || {
+                // Sink is created on the consumer thread, where its blocking clients are used
+                let mut sink = sink::sink(&run_args, verbosity_level)
+                    .expect("Main(): Run(): Error while creating sink for consumed blocks");
                 match run_args.work_mode {
+                    WorkMode::Subscriber if !run_args.shards.is_empty() => {
+                        // Subscription only to subjects of particular shards, with reassembly of partial `StreamerMessage` view
+                        if run_args.queue_group.is_some() || run_args.partition_count.is_some() {
+                            info!(target: "borealis_consumer", "Per-shard messages of a block are reassembled by a single consumer instance, `--queue-group` and `--partition-count` options are ignored\n");
+                        }
+                        let (shard_messages_tx, shard_messages_rx) = std::sync::mpsc::channel::<nats::Message>();
+                        let _shard_subscriptions: Vec<nats::Handler> = run_args
+                            .shards
+                            .iter()
+                            .map(|shard_id| {
+                                let shard_messages_tx = shard_messages_tx.clone();
+                                nats_connection
+                                    .subscribe(
+                                        format!("{}_{}.shard.{}", run_args.subject, run_args.msg_format.to_string(), shard_id).as_str(),
+                                    )
+                                    .expect(
+                                        "Subscription error: maybe wrong or nonexistent `--subject` name",
+                                    )
+                                    .with_handler(move |msg| {
+                                        shard_messages_tx.send(msg).map_err(|error| {
+                                            std::io::Error::new(std::io::ErrorKind::Other, error.to_string())
+                                        })
+                                    })
+                            })
+                            .collect();
+                        health::observe_subscribed();
+                        let mut shards_assembler = ShardsAssembler::new(&run_args.shards);
+                        loop {
+                            info!(
+                                target: "borealis_consumer",
+                                "Message consumer loop started: listening for new messages of shards {:?}\n", run_args.shards
+                            );
+                            if let Ok(msg) = shard_messages_rx.recv_timeout(std::time::Duration::from_millis(10000)) {
+                                info!(target: "borealis_consumer", "Received message:\n{}", &msg);
+                                match message_decoder(&msg, &run_args, &zstd_dictionaries) {
+                                    Ok(shard_message) => {
+                                        // Errors are already logged, and there is no redelivery for subscriber
+                                        for streamer_message in shards_assembler.push(shard_message) {
+                                            message_processor(streamer_message, sink.as_mut()).ok();
+                                        }
+                                        sink_flush(sink.as_mut()).ok();
+                                    }
+                                    Err(error) => {
+                                        error!(target: "borealis_consumer", "Message decoding error: {:?}", error);
+                                        if let Err(error) = dead_letter::publish(&nats_connection, &msg, &ProcessingError::Decoding(error), &run_args) {
+                                            error!(target: "borealis_consumer", "Message is lost for the subscriber: {:?}", error);
+                                        }
+                                    }
+                                }
+                            } else {
+                                info!(
+                                    target: "borealis_consumer",
+                                    "Message wasn't received within 10s timeframe: Error occured due to waiting timeout for message receiving was elapsed\n"
+                                );
+                            };
+                        };
+                    },
                     WorkMode::Subscriber => {
-                        let subscription = nats_connection
-                            .subscribe(
-                                format!("{}_{}", run_args.subject, run_args.msg_format.to_string()).as_str(),
-                            )
-                            .expect(
-                                "Subscription error: maybe wrong or nonexistent `--subject` name",
+                        let subject = format!("{}_{}", run_args.subject, run_args.msg_format.to_string());
+                        let subscription = match run_args.queue_group.as_ref() {
+                            Some(queue_group) => nats_connection.queue_subscribe(subject.as_str(), queue_group),
+                            None => nats_connection.subscribe(subject.as_str()),
+                        }
+                        .expect(
+                            "Subscription error: maybe wrong or nonexistent `--subject` name",
+                        );
+                        health::observe_subscribed();
+                        // Blocks are split between scaled consumer instances, so gaps aren't tracked and backfilled
+                        let scaled = run_args.queue_group.is_some() || run_args.partition_count.is_some();
+                        if scaled {
+                            info!(
+                                target: "borealis_consumer",
+                                "Scaled subscriber started (queue group: {:?}, partition: {:?} of {:?}), gaps in blocks sequence aren't backfilled\n",
+                                run_args.queue_group, run_args.partition_index, run_args.partition_count
                             );
+                        }
+                        let mut gap_filler = GapFiller::new(nats_connection.clone(), &run_args);
                         loop {
                             info!(
                                 target: "borealis_consumer",
@@ -622,7 +738,18 @@ fn main() {
                             );
                             if let Ok(msg) = subscription.next_timeout(std::time::Duration::from_millis(10000)) {
                                 info!(target: "borealis_consumer", "Received message:\n{}", &msg);
-                                message_consumer(msg, run_args.clone(), opts.verbose);
+                                let result = if scaled {
+                                    scaled_message_consumer(&msg, &run_args, &zstd_dictionaries, sink.as_mut())
+                                } else {
+                                    message_consumer(&msg, run_args.clone(), &zstd_dictionaries, &mut gap_filler, sink.as_mut())
+                                };
+                                // There is no redelivery for subscriber, so failed message is republished to dead-letter subject
+                                if let Err(processing_error) = result {
+                                    error!(target: "borealis_consumer", "Message processing error: {:?}", processing_error);
+                                    if let Err(error) = dead_letter::publish(&nats_connection, &msg, &processing_error, &run_args) {
+                                        error!(target: "borealis_consumer", "Message is lost for the subscriber: {:?}", error);
+                                    }
+                                }
                             } else {
                                 info!(
                                     target: "borealis_consumer",
@@ -636,44 +763,170 @@ fn main() {
                             target: "borealis_consumer",
                             "JetStream consumer started\n"
                         );
+                        if !run_args.shards.is_empty() {
+                            info!(target: "borealis_consumer", "Per-shard subjects are consumed only in `Subscriber` work mode, `--shards` option is ignored\n");
+                        }
 
-                        let mut consumer = Consumer::create_or_open(nats_connection, format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()).as_str(), ConsumerConfig {
+                        let consumer_info_connection = nats_connection.clone();
+                        let mut gap_filler = GapFiller::new(nats_connection.clone(), &run_args);
+                        let mut consumer_info_polled = std::time::Instant::now();
+
+                        let consumer_config = ConsumerConfig {
                             deliver_subject: Some(format!("JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())),
                             durable_name: Some(format!("Borealis_Consumer_JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())),
                             deliver_policy: DeliverPolicy::All,
                             ack_policy: AckPolicy::Explicit,
-                            // filter_subject: format!("{}_{}", run_args.subject, run_args.msg_format.to_string()),
+                            max_deliver: run_args.max_deliver,
+                            // One unacknowledged message at a time: messages are handled in order, and a NAK'd block stops the delivery of next ones
+                            max_ack_pending: 1,
+                            filter_subject: management::default_subject(&run_args),
                             replay_policy: ReplayPolicy::Instant,
                             ..Default::default()
-                        }).expect("IO error, something went wrong while creating a new consumer or returning an existent consumer");
+                        };
+                        let consumer_config = start_position(&nats_connection, &run_args, format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()).as_str(), consumer_config, &zstd_dictionaries)
+                            .expect("Error while resolving start position of the consumer: maybe wrong `--start-height`");
+
+                        let mut consumer = Consumer::create_or_open(nats_connection, format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()).as_str(), consumer_config)
+                            .expect("IO error, something went wrong while creating a new consumer or returning an existent consumer");
 
                         consumer.timeout = std::time::Duration::from_millis(10000);
+                        let push_subscriber = consumer
+                            .push_subscriber
+                            .as_ref()
+                            .expect("JetStream consumer isn't subscribed to its deliver subject");
+                        health::observe_subscribed();
 
                         loop {
                             info!(
                                 target: "borealis_consumer",
                                 "Message JetStream consumer loop started: listening for new messages\n"
                             );
-                            if let Ok(message) = consumer.process_timeout(|msg| {
-                                info!(target: "borealis_consumer", "Received message:\n{}", msg);
-                                Ok(msg.to_owned())
-                            }) {
-                                message_consumer(message, run_args.clone(), opts.verbose);
-                            } else {
-                                info!(
-                                    target: "borealis_consumer",
-                                    "Message wasn't received within {:?} timeframe: Error occured due to waiting timeout for message receiving was elapsed\n",
-                                    consumer.timeout
-                                );
+                            // JetStream pending and ack-pending messages counts, polled from consumer info
+                            if consumer_info_polled.elapsed() >= std::time::Duration::from_millis(10000) {
+                                consumer_info_polled = std::time::Instant::now();
+                                match consumer_info_connection.consumer_info(
+                                    format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string()),
+                                    format!("Borealis_Consumer_JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string()),
+                                ) {
+                                    Ok(consumer_info) => {
+                                        metrics::JETSTREAM_PENDING.set(consumer_info.num_pending as i64);
+                                        metrics::JETSTREAM_ACK_PENDING.set(consumer_info.num_ack_pending as i64);
+                                    }
+                                    Err(error) => {
+                                        error!(target: "borealis_consumer", "JetStream consumer info request error: {:?}", error);
+                                    }
+                                }
+                            }
+                            // Message is acknowledged only after its blocks are handled by the sink (e.g. committed to the database),
+                            // poison messages are terminated, and messages failed to be handled are redelivered after `--nak-delay`
+                            match push_subscriber.next_timeout(consumer.timeout) {
+                                Ok(msg) => {
+                                    info!(target: "borealis_consumer", "Received message:\n{}", &msg);
+                                    let result = message_consumer(&msg, run_args.clone(), &zstd_dictionaries, &mut gap_filler, sink.as_mut());
+                                    if let Err(error) = acknowledgement::acknowledge(&consumer_info_connection, &msg, result, &run_args) {
+                                        error!(target: "borealis_consumer", "Message acknowledgement error, message will be redelivered after acknowledgement waiting time: {:?}", error);
+                                    }
+                                }
+                                Err(_) => {
+                                    info!(
+                                        target: "borealis_consumer",
+                                        "Message wasn't received within {:?} timeframe: Error occured due to waiting timeout for message receiving was elapsed\n",
+                                        consumer.timeout
+                                    );
+                                }
                             };
                         };
                     },
+                    WorkMode::JetstreamPull => {
+                        info!(
+                            target: "borealis_consumer",
+                            "JetStream pull consumer started\n"
+                        );
+                        if !run_args.shards.is_empty() {
+                            info!(target: "borealis_consumer", "Per-shard subjects are consumed only in `Subscriber` work mode, `--shards` option is ignored\n");
+                        }
+                        // Blocks are split between consumer processes sharing the durable pull consumer, so gaps aren't tracked and backfilled
+                        if run_args.backfill.is_some() {
+                            info!(target: "borealis_consumer", "Gaps in blocks sequence aren't backfilled in `JetStreamPull` work mode, `--backfill` option is ignored\n");
+                        }
+
+                        let mut pull_consumer = PullConsumer::open(nats_connection, &run_args, zstd_dictionaries)
+                            .expect("IO error, something went wrong while creating a new pull consumer or opening an existent consumer");
+                        health::observe_subscribed();
+
+                        loop {
+                            info!(
+                                target: "borealis_consumer",
+                                "Message JetStream pull consumer loop started: fetching batch of messages\n"
+                            );
+                            if let Err(error) = pull_consumer.process_batch(sink.as_mut()) {
+                                error!(target: "borealis_consumer", "Batch processing error, messages which weren't handled will be redelivered: {:?}", error);
+                            }
+                        };
+                    },
                 }
             });
-            system.run()
-                .unwrap_or_else(|error|
-                    error!(target: "borealis_consumer", "Main(): Run(): NATS' messages consuming and processing loop returned run-time error: {:?}", error)
-                );
+            consumer
+                .join()
+                .expect("Main(): Run(): NATS' messages consuming and processing loop panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_args(args: &[&str]) -> RunArgs {
+        RunArgs::parse_from(std::iter::once("run").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn blocks_routed_to_partition_by_height_modulo() {
+        let partitions: Vec<RunArgs> = (0..3)
+            .map(|partition_index| {
+                run_args(&["--partition-count", "3", "--partition-index", &partition_index.to_string()])
+            })
+            .collect();
+        for block_height in 1000..1100 {
+            let matched: Vec<usize> = partitions
+                .iter()
+                .enumerate()
+                .filter(|(_, context)| in_partition(block_height, context))
+                .map(|(partition_index, _)| partition_index)
+                .collect();
+            assert_eq!(matched, vec![(block_height % 3) as usize]);
         }
     }
+
+    #[test]
+    fn all_blocks_handled_without_partitions() {
+        let context = run_args(&[]);
+        assert!((0..100).all(|block_height| in_partition(block_height, &context)));
+        let context = run_args(&["--queue-group", "borealis"]);
+        assert!((0..100).all(|block_height| in_partition(block_height, &context)));
+    }
+
+    #[test]
+    fn partition_routed_by_block_height_header() {
+        let context = run_args(&["--partition-count", "2", "--partition-index", "1"]);
+        let headers: nats::Headers = vec![(borealis_envelope::HEADER_BLOCK_HEIGHT.to_string(), "1001".to_string())]
+            .into_iter()
+            .collect();
+        let block_height = envelope::block_height(Some(&headers)).unwrap();
+        assert!(in_partition(block_height, &context));
+        assert!(!in_partition(block_height + 1, &context));
+    }
+
+    #[test]
+    fn postgres_sink_is_driven_from_consumer_thread() {
+        // Blocking PostgreSQL client runs its own runtime, which panics if started from within another runtime,
+        // so the connection failure should be returned as an error from the thread, where sinks are driven by `Run`
+        let consumer = spawn_consumer(|| {
+            let context = run_args(&["--sink", "postgres", "--postgres-url", "postgresql://borealis@127.0.0.1:1/borealis"]);
+            sink::sink(&context, None).map(|_| ()).map_err(|error| error.to_string())
+        });
+        let result = consumer.join().expect("Consumer thread panicked while driving PostgreSQL sink");
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,359 @@
+use crate::configs::{
+    ConsumerArgs, ConsumerCommand, DeliverKind, DiscardKind, Error, RetentionKind, RunArgs,
+    StorageKind, StreamArgs, StreamCommand,
+};
+use crate::dead_letter::dead_letter_subject;
+use borealis_envelope::ZstdDictionaries;
+use crate::start_position::start_position;
+use nats::jetstream::{
+    AckPolicy, ConsumerConfig, ConsumerInfo, DeliverPolicy, DiscardPolicy, ReplayPolicy,
+    RetentionPolicy, StorageType, StreamConfig, StreamInfo,
+};
+use tracing::info;
+
+/// Default stream name: `JS_<subject>_<msg-format>`
+pub(crate) fn default_stream_name(run_args: &RunArgs) -> String {
+    format!("JS_{}_{}", run_args.subject, run_args.msg_format.to_string())
+}
+
+/// Default durable consumer name: `Borealis_Consumer_JetStream_<subject>_<msg-format>`
+pub(crate) fn default_consumer_name(run_args: &RunArgs) -> String {
+    format!("Borealis_Consumer_JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())
+}
+
+/// Default deliver subject of push consumer: `JetStream_<subject>_<msg-format>`
+pub(crate) fn default_deliver_subject(run_args: &RunArgs) -> String {
+    format!("JetStream_{}_{}", run_args.subject, run_args.msg_format.to_string())
+}
+
+/// Default dead-letter stream name: `DLQ_<subject>_<msg-format>`
+pub(crate) fn default_dlq_stream_name(run_args: &RunArgs) -> String {
+    format!("DLQ_{}_{}", run_args.subject, run_args.msg_format.to_string())
+}
+
+/// Subject of full block messages: `<subject>_<msg-format>`
+pub(crate) fn default_subject(run_args: &RunArgs) -> String {
+    format!("{}_{}", run_args.subject, run_args.msg_format.to_string())
+}
+
+/// Default stream subjects: full block messages subject, and subjects of additional messages published by the indexer
+/// in addition to it (`<subject>_<msg-format>.account.<account_token>`, `<subject>_<msg-format>.headers`,
+/// `<subject>_<msg-format>.shard.<shard_id>`, `<subject>_<msg-format>.shard.<shard_id>.<entity>`,
+/// `<subject>_<msg-format>.anomalies`), so they're stored by the stream in JetStream publish mode.
+/// Dead-letter subject (`<subject>_<msg-format>.dlq`) isn't overlapped, as it's stored by its own stream.
+pub(crate) fn default_stream_subjects(run_args: &RunArgs) -> Vec<String> {
+    let subject = default_subject(run_args);
+    vec![
+        subject.clone(),
+        format!("{}.account.>", subject),
+        format!("{}.headers", subject),
+        format!("{}.shard.*", subject),
+        format!("{}.shard.*.*", subject),
+        format!("{}.anomalies", subject),
+    ]
+}
+
+/// JetStream durations (`max_age`, `duplicate_window`, `ack_wait`) are in nanoseconds
+pub(crate) const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// Stream configuration accordingly to CLI options
+pub(crate) fn stream_config(args: &StreamArgs) -> StreamConfig {
+    let subjects = if args.stream_subjects.is_empty() {
+        default_stream_subjects(&args.run_args)
+    } else {
+        args.stream_subjects.clone()
+    };
+    StreamConfig {
+        name: args
+            .stream_name
+            .clone()
+            .unwrap_or_else(|| default_stream_name(&args.run_args)),
+        subjects: Some(subjects),
+        retention: match args.retention {
+            RetentionKind::Limits => RetentionPolicy::Limits,
+            RetentionKind::Interest => RetentionPolicy::Interest,
+            RetentionKind::WorkQueue => RetentionPolicy::WorkQueue,
+        },
+        max_age: args.max_age as i64 * NANOS_PER_SECOND,
+        max_bytes: args.max_bytes,
+        max_msgs: args.max_msgs,
+        num_replicas: args.replicas.max(1),
+        storage: match args.storage {
+            StorageKind::File => StorageType::File,
+            StorageKind::Memory => StorageType::Memory,
+        },
+        discard: match args.discard {
+            DiscardKind::Old => DiscardPolicy::Old,
+            DiscardKind::New => DiscardPolicy::New,
+        },
+        duplicate_window: args.duplicate_window as i64 * NANOS_PER_SECOND,
+        ..Default::default()
+    }
+}
+
+/// Durable push consumer configuration accordingly to CLI options
+pub(crate) fn consumer_config(args: &ConsumerArgs) -> ConsumerConfig {
+    ConsumerConfig {
+        durable_name: Some(
+            args.consumer_name
+                .clone()
+                .unwrap_or_else(|| default_consumer_name(&args.run_args)),
+        ),
+        deliver_subject: Some(
+            args.deliver_subject
+                .clone()
+                .unwrap_or_else(|| default_deliver_subject(&args.run_args)),
+        ),
+        deliver_policy: match args.deliver_policy {
+            DeliverKind::All => DeliverPolicy::All,
+            DeliverKind::Last => DeliverPolicy::Last,
+            DeliverKind::New => DeliverPolicy::New,
+        },
+        ack_policy: AckPolicy::Explicit,
+        ack_wait: args.ack_wait as i64 * NANOS_PER_SECOND,
+        max_deliver: args.run_args.max_deliver,
+        // One unacknowledged message at a time, so blocks are handled in order and a NAK'd block is redelivered before next ones
+        max_ack_pending: 1,
+        // Stream stores other subjects as well, so only full block messages are delivered by default
+        filter_subject: args
+            .filter_subject
+            .clone()
+            .unwrap_or_else(|| default_subject(&args.run_args)),
+        replay_policy: ReplayPolicy::Instant,
+        ..Default::default()
+    }
+}
+
+/// Create the stream, or update its configuration if the stream already exists
+pub(crate) fn stream_create_or_update(
+    nats_connection: &nats::Connection,
+    config: StreamConfig,
+) -> Result<StreamInfo, Error> {
+    match nats_connection.stream_info(&config.name) {
+        Ok(_) => {
+            info!(target: "borealis_consumer", "Stream {} already exists, updating its configuration", config.name);
+            Ok(nats_connection.update_stream(&config)?)
+        }
+        Err(_) => {
+            info!(target: "borealis_consumer", "Creating stream {}", config.name);
+            Ok(nats_connection.create_stream(config)?)
+        }
+    }
+}
+
+/// Create dead-letter stream (`DLQ_<subject>_<msg-format>`) storing dead-letter subject, if it's set (`--dlq` or `--dead-letter-subject`),
+/// or bind dead-letter subject to the existing stream, so dead-letter messages are acknowledged by JetStream and never lost
+pub(crate) fn dlq_stream_create_or_bind(
+    nats_connection: &nats::Connection,
+    run_args: &RunArgs,
+) -> Result<Option<StreamInfo>, Error> {
+    let dead_letter_subject = match dead_letter_subject(run_args) {
+        Some(dead_letter_subject) => dead_letter_subject,
+        None => return Ok(None),
+    };
+    let stream_name = default_dlq_stream_name(run_args);
+    match nats_connection.stream_info(&stream_name) {
+        Ok(stream_info) => {
+            let mut config = stream_info.config.clone();
+            let subjects = config.subjects.get_or_insert_with(Vec::new);
+            if subjects.contains(&dead_letter_subject) {
+                return Ok(Some(stream_info));
+            }
+            info!(target: "borealis_consumer", "Binding dead-letter subject {} to stream {}", dead_letter_subject, stream_name);
+            subjects.push(dead_letter_subject);
+            Ok(Some(nats_connection.update_stream(&config)?))
+        }
+        Err(_) => {
+            info!(target: "borealis_consumer", "Creating dead-letter stream {} for subject {}", stream_name, dead_letter_subject);
+            Ok(Some(nats_connection.create_stream(StreamConfig {
+                name: stream_name,
+                subjects: Some(vec![dead_letter_subject]),
+                retention: RetentionPolicy::Limits,
+                storage: StorageType::File,
+                discard: DiscardPolicy::Old,
+                ..Default::default()
+            })?))
+        }
+    }
+}
+
+/// Create durable consumer, or update its configuration if the consumer already exists
+/// (NATS server updates editable fields of existing durable consumer, and rejects changes of immutable ones)
+pub(crate) fn consumer_create_or_update(
+    nats_connection: &nats::Connection,
+    stream_name: &str,
+    config: ConsumerConfig,
+) -> Result<ConsumerInfo, Error> {
+    let consumer_name = config.durable_name.clone().unwrap_or_default();
+    let config = match nats_connection.consumer_info(stream_name, &consumer_name) {
+        Ok(consumer_info) => {
+            info!(target: "borealis_consumer", "Consumer {} of stream {} already exists, updating its configuration", consumer_name, stream_name);
+            consumer_config_update(&consumer_info.config, config)
+        }
+        Err(_) => {
+            info!(target: "borealis_consumer", "Creating consumer {} of stream {}", consumer_name, stream_name);
+            config
+        }
+    };
+    nats_connection
+        .create_consumer(stream_name, config)
+        .map_err(|error| format!("Consumer {} of stream {} creating or updating error: {:?}", consumer_name, stream_name, error).into())
+}
+
+/// Configuration update of existing consumer: fields, which NATS server doesn't allow to change
+/// (deliver policy with its start position, ack and replay policies, filter subject, max waiting pulls),
+/// are kept from the current configuration of the consumer, to change them the consumer should be deleted and created again
+pub(crate) fn consumer_config_update(existing: &ConsumerConfig, config: ConsumerConfig) -> ConsumerConfig {
+    let update = ConsumerConfig {
+        deliver_policy: existing.deliver_policy,
+        opt_start_seq: existing.opt_start_seq,
+        opt_start_time: existing.opt_start_time,
+        ack_policy: existing.ack_policy,
+        replay_policy: existing.replay_policy,
+        filter_subject: existing.filter_subject.clone(),
+        max_waiting: existing.max_waiting,
+        ..config.clone()
+    };
+    if update != config {
+        info!(
+            target: "borealis_consumer",
+            "Immutable configuration of consumer {:?} is kept (deliver policy {:?}, ack policy {:?}, replay policy {:?}, filter subject {:?}), delete the consumer to change it",
+            existing.durable_name, existing.deliver_policy, existing.ack_policy, existing.replay_policy, existing.filter_subject
+        );
+    }
+    update
+}
+
+/// Run stream management subcommand
+pub(crate) fn stream_command(nats_connection: &nats::Connection, command: StreamCommand) -> Result<(), Error> {
+    match command {
+        StreamCommand::Create(args) => {
+            let stream_info = stream_create_or_update(nats_connection, stream_config(&args))?;
+            info!(target: "borealis_consumer", "Stream:\n{:?}", stream_info);
+            if let Some(dlq_stream_info) = dlq_stream_create_or_bind(nats_connection, &args.run_args)? {
+                info!(target: "borealis_consumer", "Dead-letter stream:\n{:?}", dlq_stream_info);
+            }
+        }
+        StreamCommand::Update(args) => {
+            let stream_info = nats_connection.update_stream(&stream_config(&args))?;
+            info!(target: "borealis_consumer", "Stream updated:\n{:?}", stream_info);
+        }
+        StreamCommand::Info(args) => {
+            let stream_name = args.stream_name.unwrap_or_else(|| default_stream_name(&args.run_args));
+            let stream_info = nats_connection.stream_info(&stream_name)?;
+            info!(target: "borealis_consumer", "Stream:\n{:?}", stream_info);
+        }
+        StreamCommand::Delete(args) => {
+            let stream_name = args.stream_name.unwrap_or_else(|| default_stream_name(&args.run_args));
+            nats_connection.delete_stream(&stream_name)?;
+            info!(target: "borealis_consumer", "Stream {} deleted", stream_name);
+        }
+        StreamCommand::Purge(args) => {
+            let stream_name = args.stream_name.unwrap_or_else(|| default_stream_name(&args.run_args));
+            let purge_response = nats_connection.purge_stream(&stream_name)?;
+            info!(target: "borealis_consumer", "Stream {} purged: {:?}", stream_name, purge_response);
+        }
+    }
+    Ok(())
+}
+
+/// Run consumer management subcommand
+pub(crate) fn consumer_command(nats_connection: &nats::Connection, command: ConsumerCommand) -> Result<(), Error> {
+    match command {
+        ConsumerCommand::Create(args) => {
+            let stream_name = args.stream_name.clone().unwrap_or_else(|| default_stream_name(&args.run_args));
+            let zstd_dictionaries = ZstdDictionaries::load(&args.run_args.zstd_dictionary)?;
+            let config = start_position(nats_connection, &args.run_args, &stream_name, consumer_config(&args), &zstd_dictionaries)?;
+            let consumer_info = consumer_create_or_update(nats_connection, &stream_name, config)?;
+            info!(target: "borealis_consumer", "Consumer:\n{:?}", consumer_info);
+        }
+        ConsumerCommand::Info(args) => {
+            let stream_name = args.stream_name.clone().unwrap_or_else(|| default_stream_name(&args.run_args));
+            let consumer_name = args.consumer_name.clone().unwrap_or_else(|| default_consumer_name(&args.run_args));
+            let consumer_info = nats_connection.consumer_info(&stream_name, &consumer_name)?;
+            info!(target: "borealis_consumer", "Consumer:\n{:?}", consumer_info);
+        }
+        ConsumerCommand::Delete(args) => {
+            let stream_name = args.stream_name.clone().unwrap_or_else(|| default_stream_name(&args.run_args));
+            let consumer_name = args.consumer_name.clone().unwrap_or_else(|| default_consumer_name(&args.run_args));
+            nats_connection.delete_consumer(&stream_name, &consumer_name)?;
+            info!(target: "borealis_consumer", "Consumer {} of stream {} deleted", consumer_name, stream_name);
+        }
+        ConsumerCommand::List(args) => {
+            let stream_name = args.stream_name.clone().unwrap_or_else(|| default_stream_name(&args.run_args));
+            for consumer_info in nats_connection.list_consumers(&stream_name)? {
+                let consumer_info = consumer_info?;
+                info!(
+                    target: "borealis_consumer",
+                    "Consumer {} of stream {}: pending messages: {}, ack-pending messages: {}, redelivered messages: {}",
+                    consumer_info.name, stream_name, consumer_info.num_pending, consumer_info.num_ack_pending, consumer_info.num_redelivered
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use nats::jetstream::DateTime;
+
+    fn existing_consumer_config() -> ConsumerConfig {
+        ConsumerConfig {
+            deliver_subject: Some(String::from("JetStream_BlockIndex_CBOR")),
+            durable_name: Some(String::from("Borealis_Consumer_JetStream_BlockIndex_CBOR")),
+            deliver_policy: DeliverPolicy::ByStartSeq,
+            opt_start_seq: 1000,
+            ack_policy: AckPolicy::Explicit,
+            max_deliver: 5,
+            filter_subject: String::from("BlockIndex_CBOR"),
+            replay_policy: ReplayPolicy::Instant,
+            max_ack_pending: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_duplicate_window_is_the_same_as_set_by_init() {
+        let args = StreamArgs::parse_from(["create"]);
+        assert_eq!(stream_config(&args).duplicate_window, 86400 * NANOS_PER_SECOND);
+    }
+
+    #[test]
+    fn update_keeps_immutable_fields_of_existing_consumer() {
+        let existing = existing_consumer_config();
+        // Default start options of `init` and `consumer create` (no `--start-*` options for existing consumer)
+        let config = ConsumerConfig {
+            deliver_policy: DeliverPolicy::All,
+            opt_start_seq: 0,
+            ack_policy: AckPolicy::All,
+            max_deliver: 10,
+            ..existing.clone()
+        };
+        let update = consumer_config_update(&existing, config);
+        assert_eq!(update.deliver_policy, DeliverPolicy::ByStartSeq);
+        assert_eq!(update.opt_start_seq, 1000);
+        assert_eq!(update.ack_policy, AckPolicy::Explicit);
+        // Editable fields are updated
+        assert_eq!(update.max_deliver, 10);
+    }
+
+    #[test]
+    fn update_keeps_start_time_of_existing_consumer() {
+        let start_time = DateTime(chrono::Utc::now());
+        let existing = ConsumerConfig {
+            deliver_policy: DeliverPolicy::ByStartTime,
+            opt_start_seq: 0,
+            opt_start_time: Some(start_time),
+            ..existing_consumer_config()
+        };
+        let config = ConsumerConfig {
+            deliver_policy: DeliverPolicy::All,
+            opt_start_time: None,
+            ..existing.clone()
+        };
+        assert_eq!(consumer_config_update(&existing, config), existing);
+    }
+}
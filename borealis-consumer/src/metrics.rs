@@ -0,0 +1,219 @@
+use crate::health;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Gauge, Histogram, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use tracing::{error, info};
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+pub(crate) static RECEIVED_MESSAGES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_received_messages_total",
+        "Number of received messages"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static DECODED_MESSAGES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_decoded_messages_total",
+        "Number of successfully decoded messages"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static FAILED_MESSAGES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_failed_messages_total",
+        "Number of messages failed to decode"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static DECODE_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "borealis_consumer_decode_latency_seconds",
+        "Time of message decoding (including payload decompression)",
+        exponential_buckets(0.0001, 2.0, 16).unwrap()
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static LAST_PROCESSED_BLOCK_HEIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_consumer_last_processed_block_height",
+        "Height of the last processed block"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static BLOCK_LAG: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "borealis_consumer_block_lag_seconds",
+        "Lag between timestamp of the last processed block and its processing time"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static BLOCK_GAPS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_block_gaps_total",
+        "Number of detected gaps in blocks sequence (previous block hash of the block doesn't match hash of the last processed block)"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static LAST_BLOCK_GAP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_consumer_last_block_gap_heights",
+        "Difference of heights between the block and the last processed block at the last detected gap"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static JETSTREAM_PENDING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_consumer_jetstream_pending_messages",
+        "Number of messages in a stream, not delivered to the consumer yet (from JetStream consumer info)"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static JETSTREAM_ACK_PENDING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_consumer_jetstream_ack_pending_messages",
+        "Number of delivered messages, awaiting for acknowledgement (from JetStream consumer info)"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static HANDLING_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_handling_failures_total",
+        "Number of failures of blocks handling or flushing by the sink"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static DEAD_LETTER_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "borealis_consumer_dead_letter_messages_total",
+        "Number of messages republished to dead-letter subject, by failure class (`Decoding` or `Handling`)",
+        &["class"]
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static DEAD_LETTER_PUBLISH_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_consumer_dead_letter_publish_failures_total",
+        "Number of messages failed to be republished to dead-letter subject"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+/// Height and hash of the last processed block, for gaps detection
+static LAST_PROCESSED_BLOCK: Lazy<Mutex<Option<(u64, String)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Update metrics of the processed block, and detect gap in blocks sequence.
+/// Heights of NEAR blocks aren't contiguous (some heights are skipped), so the gap is detected by previous block hash.
+/// Returns the height of the last processed block, if there is a gap.
+pub(crate) fn observe_processed_block(
+    block_height: u64,
+    block_hash: String,
+    prev_block_hash: String,
+    block_timestamp_nanosec: u64,
+) -> Option<u64> {
+    LAST_PROCESSED_BLOCK_HEIGHT.set(block_height as i64);
+    let now_nanosec = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    BLOCK_LAG.set(now_nanosec.saturating_sub(block_timestamp_nanosec) as f64 / 1_000_000_000.0);
+
+    let mut last_processed_block = LAST_PROCESSED_BLOCK.lock().unwrap();
+    let gap = match last_processed_block.as_ref() {
+        Some((last_block_height, last_block_hash)) if *last_block_hash != prev_block_hash => {
+            BLOCK_GAPS.inc();
+            LAST_BLOCK_GAP.set(block_height as i64 - *last_block_height as i64);
+            Some(*last_block_height)
+        }
+        _ => None,
+    };
+    *last_processed_block = Some((block_height, block_hash));
+    gap
+}
+
+async fn metrics_handler(request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(()) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(buffer)),
+                Err(error) => {
+                    error!(target: "borealis_consumer", "Metrics: Error while encoding metrics: {:?}", error);
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                }
+            }
+        }
+        (&Method::GET, "/healthz") => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("OK")),
+        (&Method::GET, "/readyz") if health::is_ready() => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("Ready")),
+        (&Method::GET, "/readyz") => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Not ready")),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("Metrics: Error while building HTTP response"))
+}
+
+/// Run HTTP server with Prometheus metrics endpoint `/metrics` and health (`/healthz`) and readiness (`/readyz`) endpoints
+/// in a separate thread, with its own runtime
+pub(crate) fn metrics_server(address: SocketAddr) {
+    std::thread::Builder::new()
+        .name(String::from("metrics-server"))
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Metrics: Error while creating metrics server runtime");
+            runtime.block_on(async move {
+                let make_service = make_service_fn(|_connection| async {
+                    Ok::<_, hyper::Error>(service_fn(metrics_handler))
+                });
+                match Server::try_bind(&address) {
+                    Ok(server) => {
+                        info!(target: "borealis_consumer", "Metrics: HTTP server listening on http://{} (`/metrics`, `/healthz`, `/readyz`)", address);
+                        server
+                            .serve(make_service)
+                            .await
+                            .unwrap_or_else(|error|
+                                error!(target: "borealis_consumer", "Metrics: HTTP server error: {:?}", error)
+                            );
+                    }
+                    Err(error) => {
+                        error!(target: "borealis_consumer", "Metrics: Error while binding HTTP server to {}: {:?}", address, error);
+                    }
+                }
+            });
+        })
+        .expect("Metrics: Error while spawning metrics server thread");
+}
@@ -0,0 +1,256 @@
+use crate::configs::Error;
+use crate::sink::Sink;
+use borealis_types::types::StreamerMessage;
+use postgres::{Client, NoTls, Transaction};
+use serde_json;
+use tracing::info;
+
+/// Database schema, created (if not exists) on sink creation
+const SCHEMA: &str = include_str!("../schema/postgres.sql");
+
+/// PostgreSQL sink: blocks, chunks, transactions, receipts, execution outcomes and state changes
+/// are upserted (keyed by hashes) and committed in a single transaction per block
+pub(crate) struct PostgresSink {
+    client: Client,
+    last_committed: Option<u64>,
+}
+
+impl PostgresSink {
+    pub(crate) fn new(postgres_url: &str) -> Result<Self, Error> {
+        let mut client = Client::connect(postgres_url, NoTls)?;
+        client.batch_execute(SCHEMA)?;
+        info!(target: "borealis_consumer", "Sink: Connected to PostgreSQL database, schema is created or already exists");
+        Ok(Self {
+            client,
+            last_committed: None,
+        })
+    }
+}
+
+impl Sink for PostgresSink {
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error> {
+        let mut transaction = self.client.transaction()?;
+        upsert_block(&mut transaction, streamer_message)?;
+        transaction.commit()?;
+        self.last_committed = Some(streamer_message.block.header.height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Option<u64> {
+        self.last_committed
+    }
+}
+
+fn upsert_block(
+    transaction: &mut Transaction,
+    streamer_message: &StreamerMessage,
+) -> Result<(), Error> {
+    let block = &streamer_message.block;
+    let block_hash = block.header.hash.to_string();
+
+    transaction.execute(
+        "INSERT INTO blocks (hash, height, prev_hash, epoch_id, author, timestamp_nanosec, header)
+        VALUES ($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7)
+        ON CONFLICT (hash) DO UPDATE SET
+            height = EXCLUDED.height, prev_hash = EXCLUDED.prev_hash, epoch_id = EXCLUDED.epoch_id,
+            author = EXCLUDED.author, timestamp_nanosec = EXCLUDED.timestamp_nanosec, header = EXCLUDED.header",
+        &[
+            &block_hash,
+            &(block.header.height as i64),
+            &block.header.prev_hash.to_string(),
+            &block.header.epoch_id.to_string(),
+            &block.author.to_string(),
+            &block.header.timestamp_nanosec.to_string(),
+            &serde_json::to_value(&block.header)?,
+        ],
+    )?;
+
+    for shard in streamer_message.shards.iter() {
+        let shard_id = shard.shard_id as i64;
+
+        if let Some(chunk) = &shard.chunk {
+            let chunk_hash = chunk.header.chunk_hash.to_string();
+            transaction.execute(
+                "INSERT INTO chunks (chunk_hash, block_hash, shard_id, author, height_included, header)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (chunk_hash) DO UPDATE SET
+                    block_hash = EXCLUDED.block_hash, shard_id = EXCLUDED.shard_id, author = EXCLUDED.author,
+                    height_included = EXCLUDED.height_included, header = EXCLUDED.header",
+                &[
+                    &chunk_hash,
+                    &block_hash,
+                    &shard_id,
+                    &chunk.author.to_string(),
+                    &(chunk.header.height_included as i64),
+                    &serde_json::to_value(&chunk.header)?,
+                ],
+            )?;
+
+            for transaction_with_outcome in chunk.transactions.iter() {
+                let signed_transaction = &transaction_with_outcome.transaction;
+                transaction.execute(
+                    "INSERT INTO transactions (hash, block_hash, chunk_hash, shard_id, signer_id, receiver_id, nonce, transaction)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7::TEXT::NUMERIC, $8)
+                    ON CONFLICT (hash) DO UPDATE SET
+                        block_hash = EXCLUDED.block_hash, chunk_hash = EXCLUDED.chunk_hash, shard_id = EXCLUDED.shard_id,
+                        signer_id = EXCLUDED.signer_id, receiver_id = EXCLUDED.receiver_id, nonce = EXCLUDED.nonce,
+                        transaction = EXCLUDED.transaction",
+                    &[
+                        &signed_transaction.hash.to_string(),
+                        &block_hash,
+                        &chunk_hash,
+                        &shard_id,
+                        &signed_transaction.signer_id.to_string(),
+                        &signed_transaction.receiver_id.to_string(),
+                        &signed_transaction.nonce.to_string(),
+                        &serde_json::to_value(signed_transaction)?,
+                    ],
+                )?;
+                let execution_outcome = &transaction_with_outcome.outcome.execution_outcome;
+                upsert_execution_outcome(
+                    transaction,
+                    ExecutionOutcomeRow {
+                        block_hash: &block_hash,
+                        shard_id,
+                        id: execution_outcome.id.to_string(),
+                        executed_in_block_hash: execution_outcome.block_hash.to_string(),
+                        executor_id: execution_outcome.outcome.executor_id.to_string(),
+                        gas_burnt: execution_outcome.outcome.gas_burnt,
+                        status: serde_json::to_value(&execution_outcome.outcome.status)?,
+                        outcome: serde_json::to_value(execution_outcome)?,
+                    },
+                )?;
+            }
+
+            for receipt in chunk.receipts.iter() {
+                upsert_receipt(
+                    transaction,
+                    ReceiptRow {
+                        block_hash: &block_hash,
+                        chunk_hash: Some(&chunk_hash),
+                        shard_id,
+                        receipt_id: receipt.receipt_id.to_string(),
+                        predecessor_id: receipt.predecessor_id.to_string(),
+                        receiver_id: receipt.receiver_id.to_string(),
+                        receipt: serde_json::to_value(receipt)?,
+                    },
+                )?;
+            }
+        }
+
+        for outcome_with_receipt in shard.receipt_execution_outcomes.iter() {
+            let receipt = &outcome_with_receipt.receipt;
+            upsert_receipt(
+                transaction,
+                ReceiptRow {
+                    block_hash: &block_hash,
+                    chunk_hash: None,
+                    shard_id,
+                    receipt_id: receipt.receipt_id.to_string(),
+                    predecessor_id: receipt.predecessor_id.to_string(),
+                    receiver_id: receipt.receiver_id.to_string(),
+                    receipt: serde_json::to_value(receipt)?,
+                },
+            )?;
+            let execution_outcome = &outcome_with_receipt.execution_outcome;
+            upsert_execution_outcome(
+                transaction,
+                ExecutionOutcomeRow {
+                    block_hash: &block_hash,
+                    shard_id,
+                    id: execution_outcome.id.to_string(),
+                    executed_in_block_hash: execution_outcome.block_hash.to_string(),
+                    executor_id: execution_outcome.outcome.executor_id.to_string(),
+                    gas_burnt: execution_outcome.outcome.gas_burnt,
+                    status: serde_json::to_value(&execution_outcome.outcome.status)?,
+                    outcome: serde_json::to_value(execution_outcome)?,
+                },
+            )?;
+        }
+
+        for (index, state_change) in shard.state_changes.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO state_changes (block_hash, shard_id, index, state_change)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (block_hash, shard_id, index) DO UPDATE SET state_change = EXCLUDED.state_change",
+                &[
+                    &block_hash,
+                    &shard_id,
+                    &(index as i32),
+                    &serde_json::to_value(state_change)?,
+                ],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Receipt row, with fields of `ReceiptView` extracted into columns
+struct ReceiptRow<'a> {
+    block_hash: &'a str,
+    chunk_hash: Option<&'a str>,
+    shard_id: i64,
+    receipt_id: String,
+    predecessor_id: String,
+    receiver_id: String,
+    receipt: serde_json::Value,
+}
+
+/// Upsert of receipt, keeping the chunk hash if the receipt has been already stored from the chunk
+fn upsert_receipt(transaction: &mut Transaction, row: ReceiptRow) -> Result<(), Error> {
+    transaction.execute(
+        "INSERT INTO receipts (receipt_id, block_hash, chunk_hash, shard_id, predecessor_id, receiver_id, receipt)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (receipt_id) DO UPDATE SET
+            chunk_hash = COALESCE(receipts.chunk_hash, EXCLUDED.chunk_hash), receipt = EXCLUDED.receipt",
+        &[
+            &row.receipt_id,
+            &row.block_hash,
+            &row.chunk_hash,
+            &row.shard_id,
+            &row.predecessor_id,
+            &row.receiver_id,
+            &row.receipt,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Execution outcome row, with fields of `ExecutionOutcomeWithIdView` extracted into columns
+struct ExecutionOutcomeRow<'a> {
+    block_hash: &'a str,
+    shard_id: i64,
+    id: String,
+    executed_in_block_hash: String,
+    executor_id: String,
+    gas_burnt: u64,
+    status: serde_json::Value,
+    outcome: serde_json::Value,
+}
+
+fn upsert_execution_outcome(transaction: &mut Transaction, row: ExecutionOutcomeRow) -> Result<(), Error> {
+    transaction.execute(
+        "INSERT INTO execution_outcomes (id, block_hash, executed_in_block_hash, shard_id, executor_id, gas_burnt, status, outcome)
+        VALUES ($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7, $8)
+        ON CONFLICT (id) DO UPDATE SET
+            block_hash = EXCLUDED.block_hash, executed_in_block_hash = EXCLUDED.executed_in_block_hash,
+            shard_id = EXCLUDED.shard_id, executor_id = EXCLUDED.executor_id, gas_burnt = EXCLUDED.gas_burnt,
+            status = EXCLUDED.status, outcome = EXCLUDED.outcome",
+        &[
+            &row.id,
+            &row.block_hash,
+            &row.executed_in_block_hash,
+            &row.shard_id,
+            &row.executor_id,
+            &row.gas_burnt.to_string(),
+            &row.status,
+            &row.outcome,
+        ],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use crate::acknowledgement::{self, ProcessingError};
+use crate::configs::{Error, RunArgs};
+use borealis_envelope::ZstdDictionaries;
+use crate::management;
+use crate::sink::Sink;
+use crate::start_position::StartPosition;
+use crate::{message_decoder, message_processor, metrics, sink_flush};
+use borealis_types::types::StreamerMessage;
+use nats::jetstream::{AckPolicy, ConsumerConfig, DeliverPolicy, ReplayPolicy};
+use tracing::{debug, error, info};
+
+use std::sync::Arc;
+
+/// Additional time for awaiting of fetched messages after expiration of fetch request
+const FETCH_EXPIRES_MARGIN: core::time::Duration = core::time::Duration::from_millis(1000);
+/// Interval of JetStream consumer info polling, for pending and ack-pending messages metrics
+const CONSUMER_INFO_INTERVAL: core::time::Duration = core::time::Duration::from_millis(10000);
+
+/// Durable pull consumer, fetching batches of messages. Several consumer processes may share one durable pull consumer,
+/// each message is delivered to one of them, and it's acknowledged only after its block is handled by the sink.
+pub(crate) struct PullConsumer {
+    nats_connection: nats::Connection,
+    stream: String,
+    consumer: String,
+    inbox: String,
+    subscription: nats::Subscription,
+    requests: u64,
+    context: Arc<RunArgs>,
+    zstd_dictionaries: Arc<ZstdDictionaries>,
+    consumer_info_polled: std::time::Instant,
+}
+
+impl PullConsumer {
+    /// Open existing durable pull consumer, or create it (starting accordingly to start options)
+    pub(crate) fn open(
+        nats_connection: nats::Connection,
+        context: &RunArgs,
+        zstd_dictionaries: ZstdDictionaries,
+    ) -> Result<Self, Error> {
+        let stream = format!("JS_{}_{}", context.subject, context.msg_format.to_string());
+        let consumer = context.pull_consumer_name.clone().unwrap_or_else(|| {
+            format!("Borealis_Consumer_JetStreamPull_{}_{}", context.subject, context.msg_format.to_string())
+        });
+
+        // Start options are applied only to newly created consumer, existing consumer continues from its acknowledged position
+        if nats_connection.consumer_info(&stream, &consumer).is_err() {
+            let consumer_config = ConsumerConfig {
+                durable_name: Some(consumer.clone()),
+                deliver_policy: DeliverPolicy::All,
+                ack_policy: AckPolicy::Explicit,
+                max_deliver: context.max_deliver,
+                filter_subject: management::default_subject(context),
+                replay_policy: ReplayPolicy::Instant,
+                ..Default::default()
+            };
+            let consumer_config = match StartPosition::resolve(&nats_connection, context, &stream, &zstd_dictionaries)? {
+                Some(start_position) => start_position.apply(consumer_config),
+                None => consumer_config,
+            };
+            nats_connection.create_consumer(&stream, consumer_config)?;
+            info!(target: "borealis_consumer", "Pull consumer {} of stream {} created", consumer, stream);
+        } else if context.start_height.is_some() || context.start_time.is_some() || context.start_sequence.is_some() {
+            info!(target: "borealis_consumer", "Pull consumer {} of stream {} already exists, start options are ignored", consumer, stream);
+        }
+
+        // Each fetch request has its own reply subject `<inbox>.<request>`, so replies to expired requests are recognized
+        let inbox = nats_connection.new_inbox();
+        let subscription = nats_connection.subscribe(format!("{}.*", inbox).as_str())?;
+        Ok(Self {
+            nats_connection,
+            stream,
+            consumer,
+            inbox,
+            subscription,
+            requests: 0,
+            context: Arc::new(context.clone()),
+            zstd_dictionaries: Arc::new(zstd_dictionaries),
+            consumer_info_polled: std::time::Instant::now(),
+        })
+    }
+
+    /// Fetch the next batch of messages: up to `--pull-batch-size` messages, which are available within `--pull-expires` time.
+    /// Status messages (`404` no messages, `408` request expired, `409` request rejected) finish the batch only if they're
+    /// replies to this request, status messages of previous requests are ignored. Stream messages are fetched regardless
+    /// of the request they've been delivered for, as they're awaiting for acknowledgement anyway.
+    fn fetch(&mut self) -> Result<Vec<nats::Message>, Error> {
+        let batch_size = self.context.pull_batch_size.max(1);
+        let expires = core::time::Duration::from_millis(self.context.pull_expires);
+        self.requests += 1;
+        let reply_subject = format!("{}.{}", self.inbox, self.requests);
+        self.nats_connection.publish_request(
+            format!("$JS.API.CONSUMER.MSG.NEXT.{}.{}", self.stream, self.consumer).as_str(),
+            &reply_subject,
+            serde_json::to_vec(&serde_json::json!({
+                "batch": batch_size,
+                "expires": expires.as_nanos() as u64,
+            }))?,
+        )?;
+
+        let deadline = std::time::Instant::now() + expires + FETCH_EXPIRES_MARGIN;
+        let mut messages = Vec::with_capacity(batch_size);
+        while messages.len() < batch_size {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.subscription.next_timeout(remaining) {
+                // Stream messages have acknowledgement reply subject
+                Ok(msg) if msg.reply.is_some() => messages.push(msg),
+                Ok(msg) if msg.subject != reply_subject => {
+                    debug!(target: "borealis_consumer", "Pull consumer: Status message of previous fetch request is ignored: {:?}", status(&msg));
+                }
+                Ok(msg) => match status(&msg) {
+                    // Idle heartbeat
+                    Some("100") => continue,
+                    status => {
+                        debug!(target: "borealis_consumer", "Pull consumer: Fetch request finished with status {:?}", status);
+                        break;
+                    }
+                },
+                Err(_) => break,
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Decode messages with bounded concurrency (`--pull-concurrency` threads), keeping the order of messages
+    fn decode(&self, messages: &[nats::Message]) -> Vec<Result<StreamerMessage, Error>> {
+        let concurrency = self.context.pull_concurrency.max(1);
+        let chunk_size = (messages.len() + concurrency - 1) / concurrency;
+        if concurrency == 1 || chunk_size <= 1 {
+            return messages
+                .iter()
+                .map(|msg| message_decoder(msg, &self.context, &self.zstd_dictionaries))
+                .collect();
+        }
+        let workers: Vec<std::thread::JoinHandle<Vec<Result<StreamerMessage, Error>>>> = messages
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let chunk = chunk.to_vec();
+                let context = self.context.clone();
+                let zstd_dictionaries = self.zstd_dictionaries.clone();
+                std::thread::spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|msg| message_decoder(msg, &context, &zstd_dictionaries))
+                        .collect()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|_| vec![Err("Pull consumer: Message decoding thread panicked".into())])
+            })
+            .collect()
+    }
+
+    /// Fetch and handle the next batch of messages. Blocks are handled in order of messages, messages are acknowledged
+    /// after the sink is flushed, poison messages are terminated, and messages which weren't handled are negatively
+    /// acknowledged for redelivery.
+    pub(crate) fn process_batch(&mut self, sink: &mut dyn Sink) -> Result<(), Error> {
+        self.poll_consumer_info();
+        let messages = self.fetch()?;
+        if messages.is_empty() {
+            info!(
+                target: "borealis_consumer",
+                "Messages weren't received within {:?} timeframe: fetch request expired\n",
+                core::time::Duration::from_millis(self.context.pull_expires)
+            );
+            return Ok(());
+        }
+        info!(target: "borealis_consumer", "Fetched batch of {} messages\n", messages.len());
+
+        // Processing results of messages, which were attempted to be handled, in order of messages
+        let mut results: Vec<Result<(), ProcessingError>> = Vec::with_capacity(messages.len());
+        for decoded_message in self.decode(&messages) {
+            let result = decoded_message
+                .map_err(ProcessingError::Decoding)
+                .and_then(|streamer_message| {
+                    message_processor(streamer_message, sink).map_err(ProcessingError::Handling)
+                });
+            let failed = matches!(result, Err(ProcessingError::Handling(_)));
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+        if let Err(error) = sink_flush(sink) {
+            results = results
+                .into_iter()
+                .map(|result| {
+                    result.and_then(|_| {
+                        Err(ProcessingError::Handling(format!("Sink flush error: {:?}", error).into()))
+                    })
+                })
+                .collect();
+        }
+
+        let attempted = results.len();
+        for (msg, result) in messages.iter().zip(results) {
+            acknowledgement::acknowledge(&self.nats_connection, msg, result, &self.context)?;
+        }
+        for msg in messages[attempted..].iter() {
+            acknowledgement::nak(msg, self.context.nak_delay)?;
+        }
+        Ok(())
+    }
+
+    /// JetStream pending and ack-pending messages counts, polled from consumer info
+    fn poll_consumer_info(&mut self) {
+        if self.consumer_info_polled.elapsed() < CONSUMER_INFO_INTERVAL {
+            return;
+        }
+        self.consumer_info_polled = std::time::Instant::now();
+        match self.nats_connection.consumer_info(&self.stream, &self.consumer) {
+            Ok(consumer_info) => {
+                metrics::JETSTREAM_PENDING.set(consumer_info.num_pending as i64);
+                metrics::JETSTREAM_ACK_PENDING.set(consumer_info.num_ack_pending as i64);
+            }
+            Err(error) => {
+                error!(target: "borealis_consumer", "JetStream consumer info request error: {:?}", error);
+            }
+        }
+    }
+}
+
+/// Status code of JetStream status message (`Status` header)
+fn status(msg: &nats::Message) -> Option<&str> {
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get("Status"))
+        .and_then(|values| values.iter().next())
+        .map(|value| value.as_str())
+}
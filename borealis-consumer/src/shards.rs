@@ -0,0 +1,167 @@
+use borealis_types::types::StreamerMessage;
+use tracing::info;
+
+use std::collections::BTreeMap;
+
+/// Maximum number of blocks with pending (not all received yet) shards,
+/// after which the oldest block is released as is, with the shards received so far
+const MAX_PENDING_BLOCKS: usize = 16;
+
+/// Block published as per-shard messages, which are merged back into one block
+pub(crate) trait ShardedBlock {
+    fn height(&self) -> u64;
+    fn shard_ids(&self) -> Vec<u64>;
+    /// Add shards of the other message of the same block, which haven't been received yet
+    fn merge_shards(&mut self, other: Self);
+}
+
+impl ShardedBlock for StreamerMessage {
+    fn height(&self) -> u64 {
+        self.block.header.height
+    }
+
+    fn shard_ids(&self) -> Vec<u64> {
+        self.shards.iter().map(|shard| shard.shard_id).collect()
+    }
+
+    fn merge_shards(&mut self, other: Self) {
+        for shard in other.shards {
+            if !self
+                .shards
+                .iter()
+                .any(|pending_shard| pending_shard.shard_id == shard.shard_id)
+            {
+                self.shards.push(shard);
+            }
+        }
+        self.shards.sort_by_key(|shard| shard.shard_id);
+    }
+}
+
+/// Reassembly of partial `StreamerMessage` view from per-shard messages, published to `<subject>.shard.<shard_id>` subjects.
+/// Each per-shard message is a `StreamerMessage` with the whole block and only one shard.
+pub(crate) struct ShardsAssembler<B: ShardedBlock = StreamerMessage> {
+    shards: Vec<u64>,
+    pending_blocks: BTreeMap<u64, B>,
+}
+
+impl<B: ShardedBlock> ShardsAssembler<B> {
+    pub(crate) fn new(shards: &[u64]) -> Self {
+        Self {
+            shards: shards.to_vec(),
+            pending_blocks: BTreeMap::new(),
+        }
+    }
+
+    fn is_complete(&self, block: &B) -> bool {
+        let shard_ids = block.shard_ids();
+        self.shards.iter().all(|shard_id| shard_ids.contains(shard_id))
+    }
+
+    /// Add per-shard message, and return blocks with all subscribed shards received (in block height order)
+    pub(crate) fn push(&mut self, shard_message: B) -> Vec<B> {
+        let block_height = shard_message.height();
+        match self.pending_blocks.get_mut(&block_height) {
+            Some(block) => block.merge_shards(shard_message),
+            None => {
+                self.pending_blocks.insert(block_height, shard_message);
+            }
+        }
+
+        let mut assembled_blocks = Vec::new();
+        if self.is_complete(&self.pending_blocks[&block_height]) {
+            // Previous blocks with missing shards won't be completed anymore
+            let later_blocks = self.pending_blocks.split_off(&(block_height + 1));
+            let released_blocks = std::mem::replace(&mut self.pending_blocks, later_blocks);
+            for (height, block) in released_blocks {
+                if height != block_height {
+                    info!(target: "borealis_consumer", "Shards Assembler: Block #{} released with partial shards: {:?}", height, block.shard_ids());
+                }
+                assembled_blocks.push(block);
+            }
+        }
+
+        while self.pending_blocks.len() > MAX_PENDING_BLOCKS {
+            let height = *self.pending_blocks.keys().next().unwrap();
+            let block = self.pending_blocks.remove(&height).unwrap();
+            info!(target: "borealis_consumer", "Shards Assembler: Block #{} released with partial shards: {:?}", height, block.shard_ids());
+            assembled_blocks.push(block);
+        }
+
+        assembled_blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block height with IDs of its shards
+    #[derive(Debug, PartialEq)]
+    struct Block(u64, Vec<u64>);
+
+    impl ShardedBlock for Block {
+        fn height(&self) -> u64 {
+            self.0
+        }
+
+        fn shard_ids(&self) -> Vec<u64> {
+            self.1.clone()
+        }
+
+        fn merge_shards(&mut self, other: Self) {
+            for shard_id in other.1 {
+                if !self.1.contains(&shard_id) {
+                    self.1.push(shard_id);
+                }
+            }
+            self.1.sort_unstable();
+        }
+    }
+
+    #[test]
+    fn block_assembled_from_all_shards() {
+        let mut assembler = ShardsAssembler::new(&[0, 2, 3]);
+        assert!(assembler.push(Block(100, vec![3])).is_empty());
+        assert!(assembler.push(Block(100, vec![0])).is_empty());
+        // Duplicate shard doesn't complete the block
+        assert!(assembler.push(Block(100, vec![3])).is_empty());
+        assert_eq!(assembler.push(Block(100, vec![2])), vec![Block(100, vec![0, 2, 3])]);
+        assert!(assembler.pending_blocks.is_empty());
+    }
+
+    #[test]
+    fn single_shard_block_released_at_once() {
+        let mut assembler = ShardsAssembler::new(&[1]);
+        assert_eq!(assembler.push(Block(100, vec![1])), vec![Block(100, vec![1])]);
+        assert_eq!(assembler.push(Block(101, vec![1])), vec![Block(101, vec![1])]);
+    }
+
+    #[test]
+    fn incomplete_previous_blocks_released_in_order() {
+        let mut assembler = ShardsAssembler::new(&[0, 1]);
+        assert!(assembler.push(Block(100, vec![0])).is_empty());
+        assert!(assembler.push(Block(101, vec![1])).is_empty());
+        assert!(assembler.push(Block(103, vec![0])).is_empty());
+        assert_eq!(
+            assembler.push(Block(101, vec![0])),
+            vec![Block(100, vec![0]), Block(101, vec![0, 1])]
+        );
+        // Later blocks remain pending
+        assert_eq!(assembler.pending_blocks.keys().copied().collect::<Vec<_>>(), vec![103]);
+        assert_eq!(assembler.push(Block(103, vec![1])), vec![Block(103, vec![0, 1])]);
+    }
+
+    #[test]
+    fn oldest_pending_block_released_over_limit() {
+        let mut assembler = ShardsAssembler::new(&[0, 1]);
+        for height in 0..MAX_PENDING_BLOCKS as u64 {
+            assert!(assembler.push(Block(height, vec![0])).is_empty());
+        }
+        assert_eq!(
+            assembler.push(Block(MAX_PENDING_BLOCKS as u64, vec![1])),
+            vec![Block(0, vec![0])]
+        );
+        assert_eq!(assembler.pending_blocks.len(), MAX_PENDING_BLOCKS);
+    }
+}
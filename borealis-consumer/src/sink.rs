@@ -0,0 +1,380 @@
+use crate::configs::{Error, RunArgs, SinkKind, VerbosityLevel};
+use crate::postgres_sink::PostgresSink;
+use borealis_types::types::StreamerMessage;
+use serde_cbor as cbor;
+use serde_json;
+use tracing::{error, info};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Maximum delay between retries of webhook requests
+const WEBHOOK_MAX_RETRY_DELAY: core::time::Duration = core::time::Duration::from_millis(10000);
+/// Timeout for webhook requests
+const WEBHOOK_REQUEST_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(30000);
+
+/// Destination of consumed blocks (`StreamerMessage`), selected with `--sink` option
+pub(crate) trait Sink {
+    /// Handle the block, in order of heights
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error>;
+    /// Flush buffered blocks to the destination
+    fn flush(&mut self) -> Result<(), Error>;
+    /// Height of the last block, which has been handled and flushed (committed) to the destination
+    fn checkpoint(&self) -> Option<u64>;
+}
+
+/// Create the sink accordingly to `--sink` option
+pub(crate) fn sink(
+    context: &RunArgs,
+    verbosity_level: Option<VerbosityLevel>,
+) -> Result<Box<dyn Sink>, Error> {
+    let sink: Box<dyn Sink> = match context.sink {
+        SinkKind::Dump => Box::new(DumpSink::new(verbosity_level)),
+        SinkKind::Stdout => Box::new(NdjsonSink::new(Box::new(std::io::stdout()))),
+        SinkKind::File => {
+            let path = context
+                .sink_path
+                .as_ref()
+                .ok_or("Sink: `--sink-path` option is required for `File` sink")?;
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(NdjsonSink::new(Box::new(file)))
+        }
+        SinkKind::Segments => {
+            let directory = context
+                .sink_path
+                .clone()
+                .ok_or("Sink: `--sink-path` option is required for `Segments` sink")?;
+            Box::new(SegmentsSink::new(directory, context.segment_blocks)?)
+        }
+        SinkKind::Webhook => {
+            let url = context
+                .webhook_url
+                .clone()
+                .ok_or("Sink: `--webhook-url` option is required for `Webhook` sink")?;
+            Box::new(WebhookSink::new(url, context.webhook_retries))
+        }
+        SinkKind::Postgres => {
+            let postgres_url = context
+                .postgres_url
+                .as_ref()
+                .ok_or("Sink: `--postgres-url` option is required for `Postgres` sink")?;
+            Box::new(PostgresSink::new(postgres_url)?)
+        }
+    };
+    Ok(sink)
+}
+
+/// Dump of blocks to stdout, accordingly to `--verbose` level
+pub(crate) struct DumpSink {
+    verbosity_level: Option<VerbosityLevel>,
+    last_handled: Option<u64>,
+}
+
+impl DumpSink {
+    pub(crate) fn new(verbosity_level: Option<VerbosityLevel>) -> Self {
+        Self {
+            verbosity_level,
+            last_handled: None,
+        }
+    }
+}
+
+impl Sink for DumpSink {
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error> {
+        // Data handling from `StreamerMessage` data structure. For custom filtering purposes.
+        // Same as: jq '{block_height: .block.header.height, block_hash: .block.header.hash, block_header_chunk: .block.chunks[0], shard_chunk_header: .shards[0].chunk.header, transactions: .shards[0].chunk.transactions, receipts: .shards[0].chunk.receipts, receipt_execution_outcomes: .shards[0].receipt_execution_outcomes, state_changes: .state_changes}'
+
+        if let Some(_verbosity_level) = self.verbosity_level {
+            println!(
+                "block_height: #{}, block_hash: {}\n",
+                &streamer_message.block.header.height, &streamer_message.block.header.hash
+            );
+        };
+
+        if let Some(VerbosityLevel::WithStreamerMessageDump)
+        | Some(VerbosityLevel::WithStreamerMessageParse) = self.verbosity_level
+        {
+            println!(
+                "streamer_message: {}\n",
+                serde_json::to_string_pretty(&streamer_message).unwrap()
+            );
+            println!(
+                "streamer_message: {}\n",
+                serde_json::to_string(&streamer_message).unwrap()
+            );
+        };
+
+        if let Some(VerbosityLevel::WithStreamerMessageParse) = self.verbosity_level {
+            println!(
+                "streamer_message: {}\n",
+                serde_json::to_value(&streamer_message).unwrap()
+            );
+            println!(
+                "streamer_message: {:?}\n",
+                cbor::to_vec(&streamer_message).unwrap()
+            );
+
+            println!(
+                "block_header: {}\n",
+                serde_json::to_value(&streamer_message.block.header).unwrap()
+            );
+            println!(
+                "block_header: {:?}\n",
+                cbor::to_vec(&streamer_message.block.header).unwrap()
+            );
+
+            println!(
+                "block_header_chunks#: {}\n",
+                streamer_message.block.chunks.len()
+            );
+            streamer_message.block.chunks.iter().for_each(|chunk| {
+                println!(
+                    "block_header_chunk: {}\n",
+                    serde_json::to_value(&chunk).unwrap()
+                );
+                println!("block_header_chunk: {:?}\n", cbor::to_vec(&chunk).unwrap());
+            });
+
+            println!("shards#: {}\n", streamer_message.shards.len());
+            streamer_message.shards.iter().for_each(|shard| {
+                if let Some(chunk) = &shard.chunk {
+                    println!(
+                        "shard_chunk_header: {}\n",
+                        serde_json::to_value(&chunk.header).unwrap()
+                    );
+                    println!(
+                        "shard_chunk_header: {:?}\n",
+                        cbor::to_vec(&chunk.header).unwrap()
+                    );
+
+                    println!("shard_chunk_transactions#: {}\n", chunk.transactions.len());
+                    println!(
+                        "shard_chunk_transactions: {}\n",
+                        serde_json::to_value(&chunk.transactions).unwrap()
+                    );
+                    println!(
+                        "shard_chunk_transactions: {:?}\n",
+                        cbor::to_vec(&chunk.transactions).unwrap()
+                    );
+
+                    println!("shard_chunk_receipts#: {}\n", chunk.receipts.len());
+                    println!(
+                        "shard_chunk_receipts: {}\n",
+                        serde_json::to_value(&chunk.receipts).unwrap()
+                    );
+                    println!(
+                        "shard_chunk_receipts: {:?}\n",
+                        cbor::to_vec(&chunk.receipts).unwrap()
+                    );
+                } else {
+                    println!("shard_chunk_header: None\n");
+
+                    println!("shard_chunk_transactions#: None\n");
+                    println!("shard_chunk_transactions: None\n");
+
+                    println!("shard_chunk_receipts#: None\n");
+                    println!("shard_chunk_receipts: None\n");
+                };
+
+                println!(
+                    "shard_receipt_execution_outcomes#: {}\n",
+                    shard.receipt_execution_outcomes.len()
+                );
+                println!(
+                    "shard_receipt_execution_outcomes: {}\n",
+                    serde_json::to_value(&shard.receipt_execution_outcomes).unwrap()
+                );
+                println!(
+                    "shard_receipt_execution_outcomes: {:?}\n",
+                    cbor::to_vec(&shard.receipt_execution_outcomes).unwrap()
+                );
+
+                println!("StateChanges#: {}\n", shard.state_changes.len());
+                shard.state_changes.iter().for_each(|state_change| {
+                    println!(
+                        "StateChange: {}\n",
+                        serde_json::to_value(&state_change).unwrap()
+                    );
+                    println!("StateChange: {:?}\n", cbor::to_vec(&state_change).unwrap());
+                });
+            });
+        };
+
+        self.last_handled = Some(streamer_message.block.header.height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Option<u64> {
+        self.last_handled
+    }
+}
+
+/// Newline-delimited JSON (one `StreamerMessage` per line) to stdout or to a file
+pub(crate) struct NdjsonSink {
+    writer: BufWriter<Box<dyn Write>>,
+    last_handled: Option<u64>,
+    last_flushed: Option<u64>,
+}
+
+impl NdjsonSink {
+    pub(crate) fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            last_handled: None,
+            last_flushed: None,
+        }
+    }
+}
+
+impl Sink for NdjsonSink {
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, streamer_message)?;
+        self.writer.write_all(b"\n")?;
+        self.last_handled = Some(streamer_message.block.header.height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.last_flushed = self.last_handled;
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Option<u64> {
+        self.last_flushed
+    }
+}
+
+/// Rotating segment files of CBOR encoded blocks (sequence of `StreamerMessage`s), up to `--segment-blocks` blocks per segment.
+/// Segment file is named by the height of its first block: `<sink-path>/<height>.cbor`.
+pub(crate) struct SegmentsSink {
+    directory: PathBuf,
+    segment_blocks: u64,
+    segment: Option<(BufWriter<File>, u64)>,
+    last_handled: Option<u64>,
+    last_flushed: Option<u64>,
+}
+
+impl SegmentsSink {
+    pub(crate) fn new(directory: PathBuf, segment_blocks: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            segment_blocks: segment_blocks.max(1),
+            segment: None,
+            last_handled: None,
+            last_flushed: None,
+        })
+    }
+
+    fn rotate(&mut self, block_height: u64) -> Result<(), Error> {
+        self.flush()?;
+        let path = self.directory.join(format!("{:012}.cbor", block_height));
+        info!(target: "borealis_consumer", "Sink: Starting new segment {}", path.display());
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.segment = Some((BufWriter::new(file), 0));
+        Ok(())
+    }
+}
+
+impl Sink for SegmentsSink {
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error> {
+        match &self.segment {
+            Some((_, blocks)) if *blocks < self.segment_blocks => {}
+            _ => self.rotate(streamer_message.block.header.height)?,
+        }
+        if let Some((writer, blocks)) = self.segment.as_mut() {
+            cbor::to_writer(writer, streamer_message)?;
+            *blocks += 1;
+        }
+        self.last_handled = Some(streamer_message.block.header.height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some((writer, _)) = self.segment.as_mut() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        self.last_flushed = self.last_handled;
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Option<u64> {
+        self.last_flushed
+    }
+}
+
+/// HTTP POST of JSON encoded blocks to a webhook, with up to `--webhook-retries` retries and exponential backoff
+pub(crate) struct WebhookSink {
+    agent: ureq::Agent,
+    url: String,
+    retries: u32,
+    last_handled: Option<u64>,
+}
+
+impl WebhookSink {
+    pub(crate) fn new(url: String, retries: u32) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(WEBHOOK_REQUEST_TIMEOUT)
+                .build(),
+            url,
+            retries,
+            last_handled: None,
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn handle(&mut self, streamer_message: &StreamerMessage) -> Result<(), Error> {
+        let body = serde_json::to_vec(streamer_message)?;
+        let mut attempt = 0;
+        loop {
+            match self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json")
+                .send_bytes(&body)
+            {
+                Ok(_) => break,
+                Err(error) if attempt < self.retries => {
+                    attempt += 1;
+                    let delay = std::cmp::min(
+                        core::time::Duration::from_millis(100 * 2u64.pow(attempt.min(16))),
+                        WEBHOOK_MAX_RETRY_DELAY,
+                    );
+                    error!(
+                        target: "borealis_consumer",
+                        "Sink: Webhook request error for block #{}, retry #{} within delay of {:?}: {:?}",
+                        streamer_message.block.header.height, attempt, delay, error
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(error) => {
+                    return Err(format!(
+                        "Sink: Webhook request error for block #{}, retries exhausted: {:?}",
+                        streamer_message.block.header.height, error
+                    )
+                    .into());
+                }
+            }
+        }
+        self.last_handled = Some(streamer_message.block.header.height);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> Option<u64> {
+        self.last_handled
+    }
+}
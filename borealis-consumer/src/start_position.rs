@@ -0,0 +1,86 @@
+use crate::configs::{Error, RunArgs};
+use borealis_envelope::ZstdDictionaries;
+use borealis_envelope::HeightSearch;
+use nats::jetstream::{ConsumerConfig, DateTime, DeliverPolicy};
+use tracing::info;
+
+/// Start position of newly created JetStream consumer, accordingly to `--start-height`, `--start-time` or `--start-sequence` options
+pub(crate) enum StartPosition {
+    Sequence(u64),
+    Time(chrono::DateTime<chrono::Utc>),
+}
+
+impl StartPosition {
+    /// Resolve start position for the consumer of the stream, `None` if no start options are set.
+    /// Start height is mapped to the stream sequence by binary search over stream messages.
+    pub(crate) fn resolve(
+        nats_connection: &nats::Connection,
+        context: &RunArgs,
+        stream: &str,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<Option<Self>, Error> {
+        if let Some(start_sequence) = context.start_sequence {
+            return Ok(Some(Self::Sequence(start_sequence)));
+        }
+        if let Some(start_time) = context.start_time {
+            return Ok(Some(Self::Time(start_time)));
+        }
+        match context.start_height {
+            Some(start_height) => {
+                let subject = format!("{}_{}", context.subject, context.msg_format.to_string());
+                let start_sequence = HeightSearch {
+                    nats_connection,
+                    stream,
+                    subject: &subject,
+                    default_msg_format: context.msg_format,
+                    zstd_dictionaries,
+                }
+                .sequence_of_height(start_height)?;
+                info!(target: "borealis_consumer", "Start height #{} is mapped to stream {} sequence {}", start_height, stream, start_sequence);
+                Ok(Some(Self::Sequence(start_sequence)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Consumer configuration with deliver policy set to start from this position
+    pub(crate) fn apply(&self, config: ConsumerConfig) -> ConsumerConfig {
+        match self {
+            Self::Sequence(start_sequence) => ConsumerConfig {
+                deliver_policy: DeliverPolicy::ByStartSeq,
+                // JetStream API represents the start sequence as a signed integer
+                opt_start_seq: *start_sequence as i64,
+                ..config
+            },
+            Self::Time(start_time) => ConsumerConfig {
+                deliver_policy: DeliverPolicy::ByStartTime,
+                opt_start_time: Some(DateTime(*start_time)),
+                ..config
+            },
+        }
+    }
+}
+
+/// Consumer configuration with deliver policy accordingly to start options. Start options are applied only
+/// to newly created durable consumer, existing consumer continues from its acknowledged position.
+pub(crate) fn start_position(
+    nats_connection: &nats::Connection,
+    context: &RunArgs,
+    stream: &str,
+    config: ConsumerConfig,
+    zstd_dictionaries: &ZstdDictionaries,
+) -> Result<ConsumerConfig, Error> {
+    if context.start_height.is_none() && context.start_time.is_none() && context.start_sequence.is_none() {
+        return Ok(config);
+    }
+    if let Some(durable_name) = config.durable_name.as_ref() {
+        if nats_connection.consumer_info(stream, durable_name).is_ok() {
+            info!(target: "borealis_consumer", "Consumer {} of stream {} already exists, start options are ignored", durable_name, stream);
+            return Ok(config);
+        }
+    }
+    match StartPosition::resolve(nats_connection, context, stream, zstd_dictionaries)? {
+        Some(start_position) => Ok(start_position.apply(config)),
+        None => Ok(config),
+    }
+}
@@ -0,0 +1,61 @@
+use crate::Error;
+use tracing::info;
+use zstd::dict::DecoderDictionary;
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Zstandard dictionary format magic number (`0xEC30A437`, little endian)
+const ZSTD_DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+/// Dictionary ID from Zstandard dictionary header (4 bytes after magic number, little endian)
+pub fn dictionary_id(dictionary: &[u8]) -> Result<u32, Error> {
+    match (dictionary.get(..4), dictionary.get(4..8)) {
+        (Some(magic), Some(id)) if magic == ZSTD_DICTIONARY_MAGIC => {
+            Ok(u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+        }
+        _ => Err("Dictionary: Wrong Zstd dictionary format, dictionary header magic number is absent".into()),
+    }
+}
+
+/// Zstd dictionaries for payload decompression, by dictionary ID
+#[derive(Default)]
+pub struct ZstdDictionaries {
+    dictionaries: HashMap<u32, DecoderDictionary<'static>>,
+}
+
+impl ZstdDictionaries {
+    /// Load dictionary files, the same as used by Indexer for payload compression (`--zstd-dictionary`)
+    pub fn load(dictionary_paths: &[PathBuf]) -> Result<Self, Error> {
+        let mut zstd_dictionaries = Self::default();
+        for dictionary_path in dictionary_paths {
+            let dictionary = std::fs::read(dictionary_path).map_err(|error| {
+                format!("Dictionary: Error while reading Zstd dictionary {:?}: {:?}", dictionary_path, error)
+            })?;
+            let id = zstd_dictionaries.insert(&dictionary)?;
+            info!(target: "borealis_envelope", "Dictionary: Loaded Zstd dictionary {:?} with ID {} for decompression", dictionary_path, id);
+        }
+        Ok(zstd_dictionaries)
+    }
+
+    /// Add dictionary (content of dictionary file, trained by `train-dictionary` subcommand or by `zstd --train`),
+    /// returns its ID
+    pub fn insert(&mut self, dictionary: &[u8]) -> Result<u32, Error> {
+        let id = dictionary_id(dictionary)?;
+        self.dictionaries.insert(id, DecoderDictionary::copy(dictionary));
+        Ok(id)
+    }
+
+    /// Decompress Zstd payload with dictionary by its ID
+    pub fn payload_decompress_zstd(&self, id: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let dictionary = self
+            .dictionaries
+            .get(&id)
+            .ok_or_else(|| format!("Dictionary: Zstd dictionary with ID {} isn't loaded", id))?;
+        let mut decoder = zstd::stream::read::Decoder::with_prepared_dictionary(payload, dictionary)?;
+        let mut payload_bytes = Vec::new();
+        decoder.read_to_end(&mut payload_bytes)?;
+        Ok(payload_bytes)
+    }
+}
@@ -0,0 +1,357 @@
+use crate::dictionary::ZstdDictionaries;
+use crate::Error;
+use borealis_types::types::BorealisMessage;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor as cbor;
+
+use core::str::FromStr;
+
+/// Current version of Borealis Message envelope (wire format), the latest one supported for decoding.
+/// Version 0: legacy messages without headers, payload is JSON encoded `StreamerMessage` bytes vector,
+/// embedded into CBOR or JSON Borealis Message, optionally compressed.
+/// Version 1: payload is natively encoded in the same format as the message itself (CBOR or JSON),
+/// optionally compressed, and envelope description is carried in NATS message headers.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// NATS message header with envelope version
+pub const HEADER_ENVELOPE_VERSION: &str = "Borealis-Envelope-Version";
+/// NATS message header with message and payload encoding (`CBOR` or `JSON`)
+pub const HEADER_PAYLOAD_ENCODING: &str = "Borealis-Payload-Encoding";
+/// NATS message header with payload compression codec (`None`, `LZ4F` or `Zstd`)
+pub const HEADER_PAYLOAD_COMPRESSION: &str = "Borealis-Payload-Compression";
+/// NATS message header with ID of Zstd dictionary, which has been used for payload compression
+pub const HEADER_ZSTD_DICTIONARY_ID: &str = "Borealis-Zstd-Dictionary-Id";
+/// NATS message header with height of the block, so messages can be routed (e.g. partitioned) without decoding
+pub const HEADER_BLOCK_HEIGHT: &str = "Borealis-Block-Height";
+
+/// Messages format (should be upper case, 'cause it's a suffix for `subject` name, and NATS subject is case sensitive)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MsgFormat {
+    Cbor,
+    Json,
+}
+
+impl FromStr for MsgFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.to_lowercase();
+        match input.as_str() {
+            "cbor" => Ok(MsgFormat::Cbor),
+            "json" => Ok(MsgFormat::Json),
+            _ => Err(
+                "Unknown message format: `--msg-fomat` should contain `CBOR` or `JSON`"
+                    .to_string()
+                    .into(),
+            ),
+        }
+    }
+}
+
+impl ToString for MsgFormat {
+    fn to_string(&self) -> String {
+        match self {
+            MsgFormat::Cbor => String::from("CBOR"),
+            MsgFormat::Json => String::from("JSON"),
+        }
+    }
+}
+
+/// Compression mode for NATS Message payload (`--payload-compression` option, `Borealis-Payload-Compression` header
+/// or detected by payload magic number)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CompressionMode {
+    Lz4f,
+    Zstd,
+}
+
+impl FromStr for CompressionMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.to_lowercase();
+        match input.as_str() {
+            "lz4f" => Ok(CompressionMode::Lz4f),
+            "zstd" => Ok(CompressionMode::Zstd),
+            _ => Err(
+                "Unknown payload compression mode: should be `LZ4F` or `Zstd`"
+                    .to_string()
+                    .into(),
+            ),
+        }
+    }
+}
+
+impl ToString for CompressionMode {
+    fn to_string(&self) -> String {
+        match self {
+            CompressionMode::Lz4f => String::from("LZ4F"),
+            CompressionMode::Zstd => String::from("Zstd"),
+        }
+    }
+}
+
+/// LZ4 frame format magic number (`0x184D2204`, little endian)
+const LZ4F_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+/// Zstandard frame format magic number (`0xFD2FB528`, little endian)
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Detect compression codec of the payload by frame format magic number
+pub fn detect_compression(payload: &[u8]) -> Option<CompressionMode> {
+    match payload.get(..4) {
+        Some(magic) if magic == LZ4F_MAGIC => Some(CompressionMode::Lz4f),
+        Some(magic) if magic == ZSTD_MAGIC => Some(CompressionMode::Zstd),
+        _ => None,
+    }
+}
+
+/// Detect encoding of the message: JSON message is an object, and CBOR message is a map
+pub fn detect_msg_format(message_bytes: &[u8]) -> Option<MsgFormat> {
+    match message_bytes.first() {
+        Some(b'{') => Some(MsgFormat::Json),
+        Some(0xA0..=0xBF) => Some(MsgFormat::Cbor),
+        _ => None,
+    }
+}
+
+/// Value of NATS message header, if it's present (the first one, if the header has several values)
+pub fn header_value<'a>(headers: Option<&'a nats::Headers>, header: &str) -> Option<&'a str> {
+    headers
+        .and_then(|headers| headers.get(header))
+        .and_then(|values| values.iter().next())
+        .map(|value| value.as_str())
+}
+
+/// Height of the block from NATS message headers, if the indexer has set it
+pub fn block_height(headers: Option<&nats::Headers>) -> Option<u64> {
+    header_value(headers, HEADER_BLOCK_HEIGHT).and_then(|block_height| block_height.parse().ok())
+}
+
+/// Description of how Borealis Message and its payload are encoded
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope {
+    pub version: u8,
+    pub msg_format: MsgFormat,
+    pub payload_compression: Option<CompressionMode>,
+    pub zstd_dictionary_id: Option<u32>,
+}
+
+impl Envelope {
+    /// Read envelope description from NATS headers of the message. Encoding of legacy messages without headers
+    /// is detected from the message itself (`default_msg_format`, if it can't be detected), and compression codec
+    /// of their payload is detected by frame format magic number while decoding.
+    pub fn from_headers(
+        headers: Option<&nats::Headers>,
+        message_bytes: &[u8],
+        default_msg_format: MsgFormat,
+    ) -> Result<Self, Error> {
+        let version = match header_value(headers, HEADER_ENVELOPE_VERSION) {
+            Some(version) => version
+                .parse::<u8>()
+                .map_err(|error| format!("Envelope: Wrong envelope version `{}`: {:?}", version, error))?,
+            None => {
+                return Ok(Self {
+                    version: 0,
+                    msg_format: detect_msg_format(message_bytes).unwrap_or(default_msg_format),
+                    payload_compression: None,
+                    zstd_dictionary_id: None,
+                })
+            }
+        };
+
+        if version > ENVELOPE_VERSION {
+            return Err(format!(
+                "Envelope: Unsupported envelope version {}, latest supported version is {}",
+                version, ENVELOPE_VERSION
+            )
+            .into());
+        }
+
+        let msg_format = match header_value(headers, HEADER_PAYLOAD_ENCODING) {
+            Some(msg_format) => MsgFormat::from_str(msg_format)?,
+            None => detect_msg_format(message_bytes).unwrap_or(default_msg_format),
+        };
+
+        let payload_compression = match header_value(headers, HEADER_PAYLOAD_COMPRESSION) {
+            Some(compression_mode) if compression_mode.eq_ignore_ascii_case("none") => None,
+            Some(compression_mode) => Some(CompressionMode::from_str(compression_mode)?),
+            None => None,
+        };
+
+        let zstd_dictionary_id = match header_value(headers, HEADER_ZSTD_DICTIONARY_ID) {
+            Some(id) => Some(id.parse::<u32>().map_err(|error| {
+                format!("Envelope: Wrong Zstd dictionary ID `{}`: {:?}", id, error)
+            })?),
+            None => None,
+        };
+
+        Ok(Self {
+            version,
+            msg_format,
+            payload_compression,
+            zstd_dictionary_id,
+        })
+    }
+
+    /// Decode payload (e.g. `StreamerMessage`) from Borealis Message bytes, accordingly to the envelope description
+    pub fn decode<T: Serialize + DeserializeOwned>(
+        &self,
+        message_bytes: &[u8],
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<T, Error> {
+        // Native (uncompressed) payload, embedded into the message itself
+        if self.version >= 1 && self.payload_compression.is_none() {
+            let borealis_message: BorealisMessage<T> = self.decode_borealis_message(message_bytes)?;
+            return Ok(borealis_message.payload);
+        }
+
+        // Payload embedded as bytes vector, compressed or legacy JSON encoded
+        let borealis_message: BorealisMessage<Vec<u8>> = self.decode_borealis_message(message_bytes)?;
+        let payload_compression = self
+            .payload_compression
+            .or_else(|| detect_compression(&borealis_message.payload));
+        let payload_bytes = match payload_compression {
+            Some(CompressionMode::Lz4f) => {
+                BorealisMessage::<Vec<u8>>::payload_decompress_lz4(&borealis_message.payload)
+                    .map_err(|error| format!("Envelope: LZ4F payload decompression error: {:?}", error))?
+                    .0
+            }
+            Some(CompressionMode::Zstd) => match self.zstd_dictionary_id {
+                Some(zstd_dictionary_id) => zstd_dictionaries
+                    .payload_decompress_zstd(zstd_dictionary_id, &borealis_message.payload)
+                    .map_err(|error| format!("Envelope: Zstd payload decompression error: {:?}", error))?,
+                // Streaming decoder accepts any Zstd frame: compressed with or without configured level,
+                // and with or without content size in the frame header
+                None => zstd::stream::decode_all(borealis_message.payload.as_slice())
+                    .map_err(|error| format!("Envelope: Zstd payload decompression error: {:?}", error))?,
+            },
+            None => borealis_message.payload,
+        };
+
+        let payload: T = match (self.version, self.msg_format) {
+            (0, _) | (_, MsgFormat::Json) => serde_json::from_slice(&payload_bytes)?,
+            (_, MsgFormat::Cbor) => cbor::from_slice(&payload_bytes)?,
+        };
+        Ok(payload)
+    }
+
+    fn decode_borealis_message<T: Serialize + DeserializeOwned>(&self, message_bytes: &[u8]) -> Result<BorealisMessage<T>, Error> {
+        let borealis_message = match self.msg_format {
+            MsgFormat::Cbor => BorealisMessage::from_cbor(message_bytes)
+                .ok_or("[From CBOR bytes vector: message empty] Message decoding error")?,
+            MsgFormat::Json => BorealisMessage::from_json_bytes(message_bytes)
+                .ok_or("[From JSON bytes vector: message empty] Message decoding error")?,
+        }
+        .map_err(|error| format!("Envelope: Message decoding error: {:?}", error))?;
+        Ok(borealis_message)
+    }
+}
+
+/// Decode payload (e.g. `StreamerMessage`) from Borealis Message (received from NATS subject, stored in a stream or archived),
+/// accordingly to envelope description from its NATS headers
+pub fn decode_message<T: Serialize + DeserializeOwned>(
+    headers: Option<&nats::Headers>,
+    message_bytes: &[u8],
+    default_msg_format: MsgFormat,
+    zstd_dictionaries: &ZstdDictionaries,
+) -> Result<T, Error> {
+    Envelope::from_headers(headers, message_bytes, default_msg_format)?.decode(message_bytes, zstd_dictionaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Payload {
+        height: u64,
+        hash: String,
+    }
+
+    fn payload() -> Payload {
+        Payload {
+            height: 63596,
+            hash: String::from("5X37niQWWcihDGQjsvDMHYKLCurNJyQLxCeLgneDb8mk"),
+        }
+    }
+
+    fn nats_headers(pairs: &[(&str, &str)]) -> nats::Headers {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn compression_detected_by_magic_number() {
+        let zstd_payload = zstd::bulk::compress(b"payload", 3).unwrap();
+        assert_eq!(detect_compression(&zstd_payload), Some(CompressionMode::Zstd));
+        assert_eq!(
+            detect_compression(&[0x04, 0x22, 0x4D, 0x18, 0x64, 0x40]),
+            Some(CompressionMode::Lz4f)
+        );
+        assert_eq!(detect_compression(b"{\"height\":1}"), None);
+        assert_eq!(detect_compression(&[0x28, 0xB5, 0x2F]), None);
+        assert_eq!(detect_compression(&[]), None);
+    }
+
+    #[test]
+    fn msg_format_detected_by_first_byte() {
+        assert_eq!(detect_msg_format(&serde_json::to_vec(&payload()).unwrap()), Some(MsgFormat::Json));
+        assert_eq!(detect_msg_format(&cbor::to_vec(&payload()).unwrap()), Some(MsgFormat::Cbor));
+        assert_eq!(detect_msg_format(&[0x82, 0x01, 0x02]), None);
+        assert_eq!(detect_msg_format(&[]), None);
+    }
+
+    #[test]
+    fn legacy_envelope_without_headers() {
+        let message_bytes = serde_json::to_vec(&payload()).unwrap();
+        let envelope = Envelope::from_headers(None, &message_bytes, MsgFormat::Cbor).unwrap();
+        assert_eq!(envelope.version, 0);
+        assert_eq!(envelope.msg_format, MsgFormat::Json);
+        assert_eq!(envelope.payload_compression, None);
+
+        // Encoding isn't detected, so the default one is used
+        let envelope = Envelope::from_headers(None, &[0x82], MsgFormat::Cbor).unwrap();
+        assert_eq!(envelope.msg_format, MsgFormat::Cbor);
+    }
+
+    #[test]
+    fn envelope_from_headers() {
+        let headers = nats_headers(&[
+            (HEADER_ENVELOPE_VERSION, "1"),
+            (HEADER_PAYLOAD_ENCODING, "JSON"),
+            (HEADER_PAYLOAD_COMPRESSION, "Zstd"),
+            (HEADER_ZSTD_DICTIONARY_ID, "42"),
+        ]);
+        let envelope = Envelope::from_headers(Some(&headers), b"", MsgFormat::Cbor).unwrap();
+        assert_eq!(envelope.version, 1);
+        assert_eq!(envelope.msg_format, MsgFormat::Json);
+        assert_eq!(envelope.payload_compression, Some(CompressionMode::Zstd));
+        assert_eq!(envelope.zstd_dictionary_id, Some(42));
+
+        let headers = nats_headers(&[(HEADER_ENVELOPE_VERSION, "1"), (HEADER_PAYLOAD_COMPRESSION, "None")]);
+        let envelope = Envelope::from_headers(Some(&headers), b"{}", MsgFormat::Cbor).unwrap();
+        assert_eq!(envelope.msg_format, MsgFormat::Json);
+        assert_eq!(envelope.payload_compression, None);
+
+        let headers = nats_headers(&[(HEADER_ENVELOPE_VERSION, "2")]);
+        assert!(Envelope::from_headers(Some(&headers), b"", MsgFormat::Cbor).is_err());
+    }
+
+    #[test]
+    fn legacy_message_with_detected_compression() {
+        let payload_bytes = zstd::bulk::compress(&serde_json::to_vec(&payload()).unwrap(), 19).unwrap();
+        let message_bytes = BorealisMessage::new(1, payload_bytes).to_cbor().unwrap();
+        let decoded: Payload = decode_message(None, &message_bytes, MsgFormat::Json, &ZstdDictionaries::default()).unwrap();
+        assert_eq!(decoded, payload());
+    }
+
+    #[test]
+    fn block_height_from_headers() {
+        let headers = nats_headers(&[(HEADER_BLOCK_HEIGHT, "63596")]);
+        assert_eq!(block_height(Some(&headers)), Some(63596));
+        assert_eq!(block_height(Some(&nats_headers(&[(HEADER_BLOCK_HEIGHT, "unknown")]))), None);
+        assert_eq!(block_height(None), None);
+    }
+}
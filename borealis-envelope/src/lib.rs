@@ -0,0 +1,16 @@
+//! Borealis Message envelope (wire format), shared by Borealis Indexer and Borealis Consumer,
+//! so messages published by the indexer are decoded the same way by the consumer and by the indexer's replay.
+
+pub mod dictionary;
+pub mod envelope;
+pub mod stream;
+
+pub use dictionary::{dictionary_id, ZstdDictionaries};
+pub use envelope::{
+    block_height, decode_message, detect_compression, detect_msg_format, header_value, CompressionMode, Envelope,
+    MsgFormat, ENVELOPE_VERSION, HEADER_BLOCK_HEIGHT, HEADER_ENVELOPE_VERSION, HEADER_PAYLOAD_COMPRESSION,
+    HEADER_PAYLOAD_ENCODING, HEADER_ZSTD_DICTIONARY_ID,
+};
+pub use stream::{stream_message_get, HeightSearch, RawStreamMessage};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
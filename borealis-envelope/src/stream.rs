@@ -0,0 +1,258 @@
+use crate::dictionary::ZstdDictionaries;
+use crate::envelope::{self, MsgFormat};
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Timeout for requests to JetStream API
+pub const STREAM_REQUEST_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10000);
+
+/// JetStream API error code for stream message, which isn't found (deleted, or the sequence is out of the stream)
+const NO_MESSAGE_FOUND: u16 = 10037;
+
+/// Reply of JetStream API for direct stream message get request
+#[derive(Deserialize, Debug)]
+struct StreamMessageGetResponse {
+    message: Option<RawStreamMessage>,
+    error: Option<ApiError>,
+}
+
+/// Error of JetStream API request
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    code: u16,
+    err_code: Option<u16>,
+    description: Option<String>,
+}
+
+/// Stream message with base64 encoded headers and data
+#[derive(Deserialize, Debug)]
+pub struct RawStreamMessage {
+    pub subject: String,
+    pub seq: u64,
+    hdrs: Option<String>,
+    data: Option<String>,
+}
+
+/// Only the block height of `StreamerMessage`, other fields are skipped while decoding
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHeight {
+    block: BlockHeightBlock,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHeightBlock {
+    header: BlockHeightHeader,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHeightHeader {
+    height: u64,
+}
+
+impl RawStreamMessage {
+    /// NATS headers of the message, if it has any
+    pub fn headers(&self) -> Result<Option<nats::Headers>, Error> {
+        match self.hdrs.as_deref() {
+            Some(hdrs) if !hdrs.is_empty() => {
+                Ok(Some(nats::Headers::try_from(base64::decode(hdrs)?.as_slice())?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Data of the message
+    pub fn data(&self) -> Result<Vec<u8>, Error> {
+        Ok(base64::decode(self.data.as_deref().unwrap_or_default())?)
+    }
+
+    /// Decode payload (e.g. `StreamerMessage`) from the stream message, accordingly to envelope description from its headers
+    pub fn decode<T: Serialize + DeserializeOwned>(
+        &self,
+        default_msg_format: MsgFormat,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<T, Error> {
+        let headers = self.headers()?;
+        let message_bytes = self.data()?;
+        envelope::decode_message(headers.as_ref(), &message_bytes, default_msg_format, zstd_dictionaries)
+    }
+
+    /// Height of the block from message headers, or decoded from the message (published by older indexers without the header)
+    pub fn block_height(
+        &self,
+        default_msg_format: MsgFormat,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<u64, Error> {
+        let headers = self.headers()?;
+        match envelope::block_height(headers.as_ref()) {
+            Some(block_height) => Ok(block_height),
+            None => Ok(self
+                .decode::<BlockHeight>(default_msg_format, zstd_dictionaries)?
+                .block
+                .header
+                .height),
+        }
+    }
+}
+
+/// Get stream message by JetStream API request (`{"seq": <sequence>}` or `{"last_by_subj": <subject>}`),
+/// `None` if there is no such message in the stream
+pub fn stream_message_get(
+    nats_connection: &nats::Connection,
+    stream: &str,
+    request: serde_json::Value,
+) -> Result<Option<RawStreamMessage>, Error> {
+    let reply = nats_connection.request_timeout(
+        format!("$JS.API.STREAM.MSG.GET.{}", stream).as_str(),
+        serde_json::to_vec(&request)?,
+        STREAM_REQUEST_TIMEOUT,
+    )?;
+    let response: StreamMessageGetResponse = serde_json::from_slice(&reply.data)?;
+    match (response.message, response.error) {
+        (Some(raw_message), None) => Ok(Some(raw_message)),
+        (_, Some(ApiError { err_code: Some(NO_MESSAGE_FOUND), .. })) => Ok(None),
+        (_, Some(error)) => Err(format!(
+            "Stream message get error {} ({:?}): {}",
+            error.code,
+            error.err_code,
+            error.description.unwrap_or_default()
+        )
+        .into()),
+        (None, None) => Err("Stream message get error: reply without message".into()),
+    }
+}
+
+/// Lookup of stream sequence by block height, relying on blocks being stored in the stream in order of heights
+pub struct HeightSearch<'a> {
+    pub nats_connection: &'a nats::Connection,
+    pub stream: &'a str,
+    /// Subject of block messages, messages of other subjects stored by the stream are skipped
+    pub subject: &'a str,
+    pub default_msg_format: MsgFormat,
+    pub zstd_dictionaries: &'a ZstdDictionaries,
+}
+
+impl HeightSearch<'_> {
+    /// Sequence of the first message with block at height not less than the given one
+    /// (sequence next to the last one, if all stored blocks are below the height)
+    pub fn sequence_of_height(&self, block_height: u64) -> Result<u64, Error> {
+        let stream_state = self.nats_connection.stream_info(self.stream)?.state;
+        sequence_of_height(stream_state.first_seq, stream_state.last_seq, block_height, |sequence| {
+            self.block_at_or_after(sequence, stream_state.last_seq)
+        })
+    }
+
+    /// Sequence and block height of the first message of the subject, starting from the sequence
+    /// (messages of other subjects and deleted messages are skipped, other errors are returned)
+    fn block_at_or_after(&self, sequence: u64, last_sequence: u64) -> Result<Option<(u64, u64)>, Error> {
+        for sequence in sequence..=last_sequence {
+            let raw_message =
+                match stream_message_get(self.nats_connection, self.stream, serde_json::json!({ "seq": sequence }))? {
+                    Some(raw_message) => raw_message,
+                    None => continue,
+                };
+            if raw_message.subject != self.subject {
+                continue;
+            }
+            let height = raw_message.block_height(self.default_msg_format, self.zstd_dictionaries)?;
+            return Ok(Some((sequence, height)));
+        }
+        Ok(None)
+    }
+}
+
+/// Binary search of the first sequence in `first_sequence..=last_sequence` with block at height not less than the given one,
+/// `block_at_or_after` returns sequence and height of the first block stored at or after the sequence (`None` if there is no such block)
+pub fn sequence_of_height<F>(
+    first_sequence: u64,
+    last_sequence: u64,
+    block_height: u64,
+    mut block_at_or_after: F,
+) -> Result<u64, Error>
+where
+    F: FnMut(u64) -> Result<Option<(u64, u64)>, Error>,
+{
+    let mut low = first_sequence;
+    let mut high = last_sequence + 1;
+    // Sequence of the block found at or after `high`, rather than `high` itself, which may be a skipped message
+    let mut found = last_sequence + 1;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match block_at_or_after(middle)? {
+            Some((sequence, height)) if height >= block_height => {
+                high = middle;
+                found = sequence;
+            }
+            Some((sequence, _)) => low = sequence + 1,
+            None => high = middle,
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stream from sequence 1, with block heights of messages (`None` for deleted messages and messages of other subjects)
+    fn search(stream: &[Option<u64>], block_height: u64) -> u64 {
+        let last_sequence = stream.len() as u64;
+        sequence_of_height(1, last_sequence, block_height, |sequence| {
+            Ok((sequence..=last_sequence)
+                .find_map(|sequence| stream[sequence as usize - 1].map(|height| (sequence, height))))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn sequence_of_stored_height() {
+        let stream: Vec<Option<u64>> = (100..200).map(Some).collect();
+        assert_eq!(search(&stream, 100), 1);
+        assert_eq!(search(&stream, 150), 51);
+        assert_eq!(search(&stream, 199), 100);
+    }
+
+    #[test]
+    fn sequence_of_height_out_of_stream() {
+        let stream: Vec<Option<u64>> = (100..200).map(Some).collect();
+        assert_eq!(search(&stream, 0), 1);
+        // Next to the last sequence, if all stored blocks are below the height
+        assert_eq!(search(&stream, 200), 101);
+    }
+
+    #[test]
+    fn sequence_of_skipped_height() {
+        let stream = vec![Some(100), Some(101), Some(105), Some(106), Some(110)];
+        assert_eq!(search(&stream, 102), 3);
+        assert_eq!(search(&stream, 107), 5);
+    }
+
+    #[test]
+    fn sequence_of_height_with_messages_skipped() {
+        let stream = vec![
+            Some(100),
+            None,
+            None,
+            Some(101),
+            None,
+            Some(102),
+            Some(103),
+            None,
+            None,
+            None,
+            Some(104),
+            None,
+        ];
+        assert_eq!(search(&stream, 100), 1);
+        assert_eq!(search(&stream, 101), 4);
+        assert_eq!(search(&stream, 102), 6);
+        assert_eq!(search(&stream, 104), 11);
+        assert_eq!(search(&stream, 105), 13);
+    }
+
+    #[test]
+    fn lookup_errors_are_returned() {
+        let result = sequence_of_height(1, 100, 50, |_| Err("Stream message get error 503".into()));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use crate::configs::{Error, RunArgs};
+use crate::envelope::Envelope;
+use tracing::{debug, info};
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Archive directory name, inside of Indexer's home directory
+pub(crate) const ARCHIVE_DIR_NAME: &str = "archive";
+/// Segment data file extension: sequence of Zstd compressed records
+pub(crate) const SEGMENT_DATA_EXTENSION: &str = "seg";
+/// Segment index file extension: fixed-size entries, one per height of the segment's heights range
+pub(crate) const SEGMENT_INDEX_EXTENSION: &str = "idx";
+/// Size of segment index entry: record offset and record length in segment data file (little-endian `u64`s).
+/// Entry with zero length means that there is no block at this height.
+pub(crate) const INDEX_ENTRY_SIZE: u64 = 16;
+
+/// Archive directory path inside of Indexer's home directory
+pub(crate) fn archive_dir(home_dir: &Path) -> PathBuf {
+    home_dir.join(ARCHIVE_DIR_NAME)
+}
+
+/// First heights of existing segments in the archive directory (segment files are named by the height of their first block)
+pub(crate) fn segments(archive_dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(archive_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(SEGMENT_INDEX_EXTENSION) {
+            continue;
+        }
+        if let Some(first_height) = path
+            .file_stem()
+            .and_then(|file_stem| file_stem.to_str())
+            .and_then(|file_stem| file_stem.parse::<u64>().ok())
+        {
+            segments.push(first_height);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+pub(crate) fn segment_paths(archive_dir: &Path, first_height: u64) -> (PathBuf, PathBuf) {
+    (
+        archive_dir.join(format!("{:012}.{}", first_height, SEGMENT_DATA_EXTENSION)),
+        archive_dir.join(format!("{:012}.{}", first_height, SEGMENT_INDEX_EXTENSION)),
+    )
+}
+
+/// Segment, open for appending
+struct Segment {
+    first_height: u64,
+    data: File,
+    index: File,
+    data_size: u64,
+    last_height: Option<u64>,
+}
+
+impl Segment {
+    fn open(archive_dir: &Path, first_height: u64) -> Result<Self, Error> {
+        let (data_path, index_path) = segment_paths(archive_dir, first_height);
+        let data = OpenOptions::new().create(true).append(true).open(&data_path)?;
+        let index = OpenOptions::new().create(true).write(true).open(&index_path)?;
+        let data_size = data.metadata()?.len();
+        let index_size = index.metadata()?.len();
+        let last_height = if index_size >= INDEX_ENTRY_SIZE {
+            Some(first_height + index_size / INDEX_ENTRY_SIZE - 1)
+        } else {
+            None
+        };
+        Ok(Self {
+            first_height,
+            data,
+            index,
+            data_size,
+            last_height,
+        })
+    }
+
+    /// Append record to data file first, and then its entry to index file,
+    /// so a record which isn't completely written is never referenced by the index
+    fn append(&mut self, block_height: u64, record: &[u8]) -> Result<(), Error> {
+        let offset = self.data_size;
+        self.data.write_all(record)?;
+        self.data_size += record.len() as u64;
+
+        let mut entry = [0u8; INDEX_ENTRY_SIZE as usize];
+        entry[..8].copy_from_slice(&offset.to_le_bytes());
+        entry[8..].copy_from_slice(&(record.len() as u64).to_le_bytes());
+        // Entries of skipped heights are left zeroed
+        self.index
+            .seek(SeekFrom::Start((block_height - self.first_height) * INDEX_ENTRY_SIZE))?;
+        self.index.write_all(&entry)?;
+        self.last_height = Some(block_height);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.data.sync_data()?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Append-only local archive of published Borealis Messages (full block messages), under `<home-dir>/archive`.
+/// Each record is a separate Zstd frame with NATS headers block length (little-endian `u32`), NATS headers block
+/// (`Name: Value` lines) and Borealis Message bytes, exactly as it's been published.
+/// Segments are rotated by heights range (`--archive-segment-heights`) and by data file size (`--archive-segment-size`),
+/// segment index file allows lookup of record by height with a single read.
+pub(crate) struct ArchiveWriter {
+    archive_dir: PathBuf,
+    segment_heights: u64,
+    segment_size: u64,
+    zstd_level: i32,
+    headers_block: Vec<u8>,
+    segment: Option<Segment>,
+}
+
+impl ArchiveWriter {
+    /// Open archive for appending, continuing the last existing segment
+    pub(crate) fn open(home_dir: &Path, envelope: &Envelope, context: &RunArgs) -> Result<Self, Error> {
+        Self::open_dir(
+            archive_dir(home_dir),
+            envelope,
+            context.archive_segment_heights,
+            context.archive_segment_size.max(1) * 1024 * 1024,
+        )
+    }
+
+    /// Open archive in the directory, with segments rotation by heights range and by data file size (in bytes)
+    fn open_dir(archive_dir: PathBuf, envelope: &Envelope, segment_heights: u64, segment_size: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(&archive_dir)?;
+        let segment = match segments(&archive_dir)?.last() {
+            Some(first_height) => Some(Segment::open(&archive_dir, *first_height)?),
+            None => None,
+        };
+        info!(
+            target: "borealis_indexer",
+            "Archive: Archive {:?} opened, last archived block: {:?}",
+            archive_dir, segment.as_ref().and_then(|segment| segment.last_height)
+        );
+
+        let headers_block = envelope
+            .header_pairs()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect::<String>()
+            .into_bytes();
+
+        Ok(Self {
+            archive_dir,
+            segment_heights: segment_heights.max(1),
+            segment_size: segment_size.max(1),
+            zstd_level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            headers_block,
+            segment,
+        })
+    }
+
+    /// Append published message of the block. Blocks at heights, which are already archived, are skipped.
+    pub(crate) fn append(&mut self, block_height: u64, message_bytes: &[u8]) -> Result<(), Error> {
+        let rotate = match &self.segment {
+            Some(segment) => {
+                if let Some(last_height) = segment.last_height {
+                    if block_height <= last_height {
+                        debug!(target: "borealis_indexer", "Archive: Block #{} is already archived, skipped", block_height);
+                        return Ok(());
+                    }
+                }
+                block_height < segment.first_height
+                    || block_height - segment.first_height >= self.segment_heights
+                    || segment.data_size >= self.segment_size
+            }
+            None => true,
+        };
+        if rotate {
+            if let Some(segment) = self.segment.as_mut() {
+                segment.sync()?;
+            }
+            info!(target: "borealis_indexer", "Archive: Starting new segment from block #{}", block_height);
+            self.segment = Some(Segment::open(&self.archive_dir, block_height)?);
+        }
+
+        let mut record_bytes = Vec::with_capacity(4 + self.headers_block.len() + message_bytes.len());
+        record_bytes.extend_from_slice(&(self.headers_block.len() as u32).to_le_bytes());
+        record_bytes.extend_from_slice(&self.headers_block);
+        record_bytes.extend_from_slice(message_bytes);
+        let record = zstd::bulk::compress(&record_bytes, self.zstd_level)?;
+
+        if let Some(segment) = self.segment.as_mut() {
+            segment.append(block_height, &record)?;
+        }
+        Ok(())
+    }
+
+    /// Sync written records to disk
+    pub(crate) fn sync(&mut self) -> Result<(), Error> {
+        if let Some(segment) = self.segment.as_mut() {
+            segment.sync()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse NATS headers block of archive record (`Name: Value` lines)
+fn parse_headers(headers_bytes: &[u8]) -> nats::Headers {
+    String::from_utf8_lossy(headers_bytes)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Reader of the local archive, with lookup of archived messages by height
+pub(crate) struct ArchiveReader {
+    archive_dir: PathBuf,
+    segments: Vec<u64>,
+}
+
+impl ArchiveReader {
+    pub(crate) fn open(archive_dir: &Path) -> Result<Self, Error> {
+        let segments = segments(archive_dir)?;
+        info!(target: "borealis_indexer", "Archive: Archive {:?} opened for reading, {} segments", archive_dir, segments.len());
+        Ok(Self {
+            archive_dir: archive_dir.to_path_buf(),
+            segments,
+        })
+    }
+
+    /// Height of the first archived block
+    pub(crate) fn first_height(&self) -> Option<u64> {
+        self.segments.first().copied()
+    }
+
+    /// Height of the last archived block
+    pub(crate) fn last_height(&self) -> Result<Option<u64>, Error> {
+        match self.segments.last() {
+            Some(first_height) => {
+                let (_, index_path) = segment_paths(&self.archive_dir, *first_height);
+                let index_size = std::fs::metadata(index_path)?.len();
+                Ok((index_size >= INDEX_ENTRY_SIZE).then(|| first_height + index_size / INDEX_ENTRY_SIZE - 1))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Archived message (NATS headers and Borealis Message bytes) of the block at the height,
+    /// or `None` if there is no block at this height in the archive
+    pub(crate) fn read(&self, block_height: u64) -> Result<Option<(nats::Headers, Vec<u8>)>, Error> {
+        // Segment with the greatest first height, which isn't greater than the block height
+        let first_height = match self.segments.partition_point(|first_height| *first_height <= block_height) {
+            0 => return Ok(None),
+            position => self.segments[position - 1],
+        };
+        let (data_path, index_path) = segment_paths(&self.archive_dir, first_height);
+
+        let mut index = File::open(index_path)?;
+        let entry_offset = (block_height - first_height) * INDEX_ENTRY_SIZE;
+        if entry_offset + INDEX_ENTRY_SIZE > index.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut entry = [0u8; INDEX_ENTRY_SIZE as usize];
+        index.seek(SeekFrom::Start(entry_offset))?;
+        index.read_exact(&mut entry)?;
+        let offset = u64::from_le_bytes(entry[..8].try_into()?);
+        let length = u64::from_le_bytes(entry[8..].try_into()?);
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = File::open(data_path)?;
+        let mut record = vec![0u8; length as usize];
+        data.seek(SeekFrom::Start(offset))?;
+        data.read_exact(&mut record)?;
+        let record_bytes = zstd::stream::decode_all(record.as_slice())?;
+
+        let headers_length = record_bytes
+            .get(..4)
+            .map(|length| u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize)
+            .ok_or("Archive: Wrong record format, headers length is absent")?;
+        let headers_bytes = record_bytes
+            .get(4..4 + headers_length)
+            .ok_or("Archive: Wrong record format, headers block is truncated")?;
+        Ok(Some((parse_headers(headers_bytes), record_bytes[4 + headers_length..].to_vec())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configs::MsgFormat;
+    use borealis_envelope::{header_value, HEADER_ENVELOPE_VERSION, HEADER_PAYLOAD_ENCODING};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("borealis-indexer-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn message(block_height: u64) -> Vec<u8> {
+        format!("message of block #{}", block_height).into_bytes()
+    }
+
+    fn writer(archive_dir: &Path, segment_heights: u64) -> ArchiveWriter {
+        let envelope = Envelope::new(MsgFormat::Cbor, None);
+        ArchiveWriter::open_dir(archive_dir.to_path_buf(), &envelope, segment_heights, 1024 * 1024).unwrap()
+    }
+
+    #[test]
+    fn write_and_read_by_height() {
+        let archive_dir = test_dir("read");
+        let mut archive_writer = writer(&archive_dir, 1000);
+        for block_height in [100, 101, 103, 104] {
+            archive_writer.append(block_height, &message(block_height)).unwrap();
+        }
+        archive_writer.sync().unwrap();
+
+        let archive_reader = ArchiveReader::open(&archive_dir).unwrap();
+        assert_eq!(archive_reader.first_height(), Some(100));
+        assert_eq!(archive_reader.last_height().unwrap(), Some(104));
+        for block_height in [100, 101, 103, 104] {
+            let (headers, message_bytes) = archive_reader.read(block_height).unwrap().unwrap();
+            assert_eq!(message_bytes, message(block_height));
+            assert_eq!(header_value(Some(&headers), HEADER_ENVELOPE_VERSION), Some("1"));
+            assert_eq!(header_value(Some(&headers), HEADER_PAYLOAD_ENCODING), Some("CBOR"));
+        }
+        // Skipped height, and heights out of the archive
+        for block_height in [99, 102, 105] {
+            assert!(archive_reader.read(block_height).unwrap().is_none());
+        }
+        std::fs::remove_dir_all(&archive_dir).unwrap();
+    }
+
+    #[test]
+    fn segments_rotation_by_heights() {
+        let archive_dir = test_dir("rotation");
+        let mut archive_writer = writer(&archive_dir, 3);
+        for block_height in 100..=107 {
+            archive_writer.append(block_height, &message(block_height)).unwrap();
+        }
+        archive_writer.sync().unwrap();
+
+        assert_eq!(segments(&archive_dir).unwrap(), vec![100, 103, 106]);
+        let archive_reader = ArchiveReader::open(&archive_dir).unwrap();
+        assert_eq!(archive_reader.last_height().unwrap(), Some(107));
+        for block_height in 100..=107 {
+            let (_, message_bytes) = archive_reader.read(block_height).unwrap().unwrap();
+            assert_eq!(message_bytes, message(block_height));
+        }
+        std::fs::remove_dir_all(&archive_dir).unwrap();
+    }
+
+    #[test]
+    fn reopened_archive_skips_archived_heights() {
+        let archive_dir = test_dir("reopen");
+        let mut archive_writer = writer(&archive_dir, 1000);
+        archive_writer.append(100, &message(100)).unwrap();
+        archive_writer.append(101, &message(101)).unwrap();
+        archive_writer.sync().unwrap();
+
+        let mut archive_writer = writer(&archive_dir, 1000);
+        archive_writer.append(101, b"republished block").unwrap();
+        archive_writer.append(102, &message(102)).unwrap();
+        archive_writer.sync().unwrap();
+
+        let archive_reader = ArchiveReader::open(&archive_dir).unwrap();
+        assert_eq!(segments(&archive_dir).unwrap(), vec![100]);
+        for block_height in 100..=102 {
+            let (_, message_bytes) = archive_reader.read(block_height).unwrap().unwrap();
+            assert_eq!(message_bytes, message(block_height));
+        }
+        std::fs::remove_dir_all(&archive_dir).unwrap();
+    }
+}
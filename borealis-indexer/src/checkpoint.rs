@@ -0,0 +1,63 @@
+use crate::configs::Error;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use std::path::{Path, PathBuf};
+
+/// Checkpoint file name, stored inside of Indexer's home directory
+pub(crate) const CHECKPOINT_FILE_NAME: &str = "last_published_block.json";
+
+/// Height and hash of the last block, which has been successfully published to NATS
+/// (and acknowledged by the server in `JetStream` publish mode)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct PublishCheckpoint {
+    pub block_height: u64,
+    pub block_hash: String,
+}
+
+impl PublishCheckpoint {
+    pub(crate) fn new(block_height: u64, block_hash: String) -> Self {
+        Self {
+            block_height,
+            block_hash,
+        }
+    }
+
+    /// Checkpoint file path inside of Indexer's home directory
+    pub(crate) fn path(home_dir: &Path) -> PathBuf {
+        home_dir.join(CHECKPOINT_FILE_NAME)
+    }
+
+    /// Load checkpoint from Indexer's home directory, returns `None` if nothing has been published yet
+    pub(crate) fn load(home_dir: &Path) -> Result<Option<Self>, Error> {
+        let checkpoint_path = Self::path(home_dir);
+        if !checkpoint_path.exists() {
+            info!(target: "borealis_indexer", "Publish checkpoint {:?} doesn't exist", checkpoint_path);
+            return Ok(None);
+        }
+        let checkpoint_bytes = std::fs::read(&checkpoint_path).map_err(|error| {
+            format!("Checkpoint: Error while reading publish checkpoint {:?}: {:?}", checkpoint_path, error)
+        })?;
+        let checkpoint: Self = serde_json::from_slice(&checkpoint_bytes).map_err(|error| {
+            format!("Checkpoint: Error while parsing publish checkpoint {:?}: {:?}", checkpoint_path, error)
+        })?;
+        debug!(target: "borealis_indexer", "Checkpoint: Loaded publish checkpoint: {:?}", &checkpoint);
+        Ok(Some(checkpoint))
+    }
+
+    /// Store checkpoint into Indexer's home directory.
+    /// Checkpoint is written into temporary file first and then renamed, so it's never left partially written.
+    pub(crate) fn store(&self, home_dir: &Path) -> Result<(), Error> {
+        let checkpoint_path = Self::path(home_dir);
+        let checkpoint_tmp_path = checkpoint_path.with_extension("json.tmp");
+        let checkpoint_bytes = serde_json::to_vec(self)?;
+        std::fs::write(&checkpoint_tmp_path, &checkpoint_bytes).map_err(|error| {
+            format!("Checkpoint: Error while writing publish checkpoint {:?}: {:?}", checkpoint_tmp_path, error)
+        })?;
+        std::fs::rename(&checkpoint_tmp_path, &checkpoint_path).map_err(|error| {
+            format!("Checkpoint: Error while renaming publish checkpoint {:?} to {:?}: {:?}", checkpoint_tmp_path, checkpoint_path, error)
+        })?;
+        debug!(target: "borealis_indexer", "Checkpoint: Stored publish checkpoint: {:?}", self);
+        Ok(())
+    }
+}
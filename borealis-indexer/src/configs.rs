@@ -1,3 +1,4 @@
+pub(crate) use borealis_envelope::{CompressionMode, MsgFormat};
 use clap::Parser;
 
 use near_indexer::near_primitives::types::Gas;
@@ -5,7 +6,6 @@ use near_indexer::near_primitives::types::Gas;
 use tracing_subscriber::EnvFilter;
 
 use core::str::FromStr;
-use std::string::ToString;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -38,6 +38,12 @@ pub(crate) enum SubCommand {
     Init(InitConfigArgs),
     /// Run Borealis Indexer with options and sync blocks from the network
     Run(RunArgs),
+    /// Run Borealis Indexer's request/reply query service for blocks, chunks, transactions and receipts (without messages streaming)
+    Serve(RunArgs),
+    /// Train Zstd dictionary for payload compression on samples of recent blocks
+    TrainDictionary(TrainDictionaryArgs),
+    /// Republish blocks from the local archive or from JetStream stream onto NATS subject (re-encoded accordingly to publishing options)
+    Replay(ReplayArgs),
 }
 
 /// CLI options to run Borealis Indexer
@@ -71,30 +77,144 @@ pub(crate) struct RunArgs {
     /// Compress the payload of Borealis Message
     #[clap(long)]
     pub payload_compression: Option<CompressionMode>,
+    /// Zstd compression level (1-22), for `--payload-compression Zstd`
+    #[clap(long)]
+    pub zstd_level: Option<i32>,
+    /// Path to Zstd dictionary (trained by `train-dictionary` subcommand), for `--payload-compression Zstd`
+    #[clap(long)]
+    pub zstd_dictionary: Option<std::path::PathBuf>,
+    /// Publishing mode (`Core` NATS publish or `JetStream` publish with acknowledgements and deduplication)
+    #[clap(long, default_value = "Core")]
+    pub publish_mode: PublishMode,
+    /// Timeout (in milliseconds) for awaiting of JetStream publish acknowledgement (`PubAck`)
+    #[clap(long, default_value = "10000")]
+    pub publish_ack_timeout: u64,
+    /// Number of workers for messages serialization and compression
+    #[clap(long, default_value = "4")]
+    pub encoding_workers: usize,
+    /// Maximum number of already encoded blocks, published within one batch (with one flush in `Core` publish mode)
+    #[clap(long, default_value = "16")]
+    pub batch_size: usize,
+    /// Maximum number of published messages, awaiting for JetStream publish acknowledgements at the same time
+    #[clap(long, default_value = "256")]
+    pub max_in_flight: usize,
+    /// Address for HTTP server with Prometheus metrics endpoint `/metrics` and health/readiness endpoints `/healthz` and `/readyz`, e.g. `0.0.0.0:9090`
+    #[clap(long)]
+    pub metrics_address: Option<std::net::SocketAddr>,
+    /// Write published messages into local archive of Zstd compressed segment files under `<home-dir>/archive`
+    #[clap(long)]
+    pub archive: bool,
+    /// Range of heights of archive segment, segment is rotated when block height is out of the range
+    #[clap(long, default_value = "100000")]
+    pub archive_segment_heights: u64,
+    /// Maximum size of archive segment data file (megabytes), segment is rotated when the size is exceeded
+    #[clap(long, default_value = "1024")]
+    pub archive_segment_size: u64,
     #[clap(long, default_value = "FromInterruption")]
     pub sync_mode: SyncMode,
     #[clap(long)]
     pub block_height: Option<u64>,
     #[clap(long, default_value = "StreamWhileSyncing")]
     pub await_synced: AwaitSynced,
+    /// Path to filter configuration (JSON) for publishing of trimmed messages to per-account subjects `<subject>.account.<account_token>`
+    /// (account ID with dots replaced by colons, e.g. `<subject>.account.factory:bridge:near`), in addition to the full messages subject
+    #[clap(long)]
+    pub filter_config: Option<std::path::PathBuf>,
+    /// Fan-out mode: in addition to the full messages subject, publish each entity of the block as its own message
+    /// to `<subject>.headers` and `<subject>.shard.<shard_id>.{chunk|txs|receipts|outcomes|state_changes}` subjects
+    #[clap(long)]
+    pub fan_out: bool,
+    /// Shard subjects mode: in addition to the full messages subject, publish each shard (with the whole block attached)
+    /// as `StreamerMessage` with only this shard to `<subject>.shard.<shard_id>` subject
+    #[clap(long)]
+    pub shard_subjects: bool,
+    /// Action on anomaly in blocks sequence (non-monotonic height or hash chain break), in addition to the alert message on `<subject>.anomalies` subject:
+    /// `Alert` - publish the block anyway, `Halt` - stop streaming before the block, `Backfill` - publish missing blocks before the block
+    #[clap(long, default_value = "Alert")]
+    pub on_anomaly: AnomalyAction,
+    /// Spawn request/reply query service alongside of messages streaming
+    #[clap(long)]
+    pub query_service: bool,
+    /// Subject prefix for query service requests, e.g. `Borealis.Query.Block.<height|hash>`
+    #[clap(long, default_value = "Borealis.Query")]
+    pub query_subject: String,
+    /// Maximum number of query service requests processed concurrently
+    #[clap(long, default_value = "64")]
+    pub query_concurrency: usize,
+}
+
+/// CLI options to train Zstd dictionary on samples of recent blocks
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct TrainDictionaryArgs {
+    /// Path to store trained dictionary file
+    #[clap(long)]
+    pub dictionary_path: std::path::PathBuf,
+    /// Number of blocks to sample
+    #[clap(long, default_value = "1000")]
+    pub samples: usize,
+    /// Maximum size of dictionary in bytes
+    #[clap(long, default_value = "112640")]
+    pub dictionary_size: usize,
+    /// Samples encoding format (`CBOR` or `JSON`), should be the same as `--msg-format` of streaming Indexer
+    #[clap(long, default_value = "CBOR")]
+    pub msg_format: MsgFormat,
+    #[clap(long, default_value = "LatestSynced")]
+    pub sync_mode: SyncMode,
+    #[clap(long)]
+    pub block_height: Option<u64>,
+    #[clap(long, default_value = "StreamWhileSyncing")]
+    pub await_synced: AwaitSynced,
+}
+
+/// CLI options to replay archived or stored blocks. Target subject, format, compression and publishing options are the same as for `run` subcommand.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct ReplayArgs {
+    /// Source of replayed blocks (`Archive` or `Stream`)
+    #[clap(long, default_value = "Archive")]
+    pub source: ReplaySource,
+    /// Local archive directory for `Archive` source. Defaults to `<home-dir>/archive`
+    #[clap(long)]
+    pub source_archive_dir: Option<std::path::PathBuf>,
+    /// Subject of full block messages in the source stream, for `Stream` source
+    #[clap(long)]
+    pub source_subject: Option<String>,
+    /// JetStream stream name for `Stream` source. Defaults to `JS_<source-subject>`
+    #[clap(long)]
+    pub source_stream: Option<String>,
+    /// Paths to Zstd dictionaries, which have been used for payload compression of source messages
+    #[clap(long)]
+    pub source_zstd_dictionary: Vec<std::path::PathBuf>,
+    /// Height of the first replayed block (inclusive)
+    #[clap(long)]
+    pub from_height: Option<u64>,
+    /// Height of the last replayed block (inclusive)
+    #[clap(long)]
+    pub to_height: Option<u64>,
+    /// Maximum number of replayed blocks per second
+    #[clap(long)]
+    pub rate_limit: Option<f64>,
+    #[clap(flatten)]
+    pub run_args: RunArgs,
 }
 
-/// Streaming messages format (should be upper case, 'cause it's a suffix for `subject` name, and NATS subject is case sensitive)
+/// Source of replayed blocks
 #[derive(Parser, Debug, Clone, Copy)]
-pub(crate) enum MsgFormat {
-    Cbor,
-    Json,
+pub(crate) enum ReplaySource {
+    /// Local archive, written with `--archive` flag
+    Archive,
+    /// Existing JetStream stream
+    Stream,
 }
 
-impl FromStr for MsgFormat {
+impl FromStr for ReplaySource {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let input = s.to_lowercase();
         match input.as_str() {
-            "cbor" => Ok(MsgFormat::Cbor),
-            "json" => Ok(MsgFormat::Json),
+            "archive" => Ok(ReplaySource::Archive),
+            "stream" => Ok(ReplaySource::Stream),
             _ => Err(
-                "Unknown message format: `--msg-fomat` should contain `CBOR` or `JSON`"
+                "Unknown replay source: `--source` should be `Archive` or `Stream`"
                     .to_string()
                     .into(),
             ),
@@ -102,31 +222,25 @@ impl FromStr for MsgFormat {
     }
 }
 
-impl ToString for MsgFormat {
-    fn to_string(&self) -> String {
-        match self {
-            MsgFormat::Cbor => String::from("CBOR"),
-            MsgFormat::Json => String::from("JSON"),
-        }
-    }
-}
-
-/// Compression mode for NATS Message payload
+/// Publishing mode for streaming messages to NATS
 #[derive(Parser, Debug, Clone, Copy)]
-pub(crate) enum CompressionMode {
-    Lz4f,
-    Zstd,
+pub(crate) enum PublishMode {
+    /// Core NATS publish, message considered delivered when it's written into the client buffer
+    Core,
+    /// JetStream publish, message considered delivered only after acknowledgement (`PubAck`) from the server,
+    /// retransmits are dropped by the server within stream's `duplicate_window` due to `Nats-Msg-Id` header
+    Jetstream,
 }
 
-impl FromStr for CompressionMode {
+impl FromStr for PublishMode {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let input = s.to_lowercase();
         match input.as_str() {
-            "lz4f" => Ok(CompressionMode::Lz4f),
-            "zstd" => Ok(CompressionMode::Zstd),
+            "core" => Ok(PublishMode::Core),
+            "jetstream" => Ok(PublishMode::Jetstream),
             _ => Err(
-                "Unknown payload compression mode: `--payload-compression` should be `LZ4F` or `Zstd`"
+                "Unknown publishing mode: `--publish-mode` should be `Core` or `JetStream`"
                     .to_string()
                     .into(),
             ),
@@ -141,6 +255,8 @@ pub(crate) enum SyncMode {
     LatestSynced,
     /// Starts syncing from the block NEAR Indexer was interrupted last time
     FromInterruption,
+    /// Starts syncing from the block next to the last one actually delivered to NATS (stored in publish checkpoint in `--home-dir`)
+    FromLastPublished,
     /// Specific block height to start syncing from, RunArgs.block_height should follow after it
     BlockHeight,
 }
@@ -152,8 +268,30 @@ impl FromStr for SyncMode {
         match input.as_str() {
             "latestsynced" => Ok(SyncMode::LatestSynced),
             "frominterruption" => Ok(SyncMode::FromInterruption),
+            "fromlastpublished" => Ok(SyncMode::FromLastPublished),
             "blockheight" => Ok(SyncMode::BlockHeight),
-            _ => Err("Unknown indexer synchronization mode: `--sync-mode` should be `LatestSynced`, `FromInterruption` or `BlockHeight` with --block-height explicit pointing".to_string().into()),
+            _ => Err("Unknown indexer synchronization mode: `--sync-mode` should be `LatestSynced`, `FromInterruption`, `FromLastPublished` or `BlockHeight` with --block-height explicit pointing".to_string().into()),
+        }
+    }
+}
+
+/// Action on anomaly in blocks sequence
+#[derive(Parser, Debug, Clone, Copy)]
+pub(crate) enum AnomalyAction {
+    Alert,
+    Halt,
+    Backfill,
+}
+
+impl FromStr for AnomalyAction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.to_lowercase();
+        match input.as_str() {
+            "alert" => Ok(AnomalyAction::Alert),
+            "halt" => Ok(AnomalyAction::Halt),
+            "backfill" => Ok(AnomalyAction::Backfill),
+            _ => Err("Unknown action on anomaly in blocks sequence: `--on-anomaly` should be `Alert`, `Halt` or `Backfill`".to_string().into()),
         }
     }
 }
@@ -269,7 +407,7 @@ impl From<InitConfigArgs> for near_indexer::InitConfigArgs {
 pub(crate) fn init_logging() {
     // Filters can be customized through RUST_LOG environment variable via CLI
     let mut env_filter = EnvFilter::new(
-        "tokio_reactor=info,near=info,near=error,stats=info,telemetry=info,near-performance-metrics=info,aggregated=info,near_indexer=info,borealis_indexer=info,borealis_consumer=info",
+        "tokio_reactor=info,near=info,near=error,stats=info,telemetry=info,near-performance-metrics=info,aggregated=info,near_indexer=info,borealis_indexer=info,borealis_consumer=info,borealis_envelope=info",
     );
 
     if let Ok(rust_log) = std::env::var("RUST_LOG") {
@@ -0,0 +1,213 @@
+use crate::checkpoint::PublishCheckpoint;
+use crate::configs::{Error, SyncMode};
+use actix::Addr;
+use near_client::{GetBlock, ViewClientActor};
+use near_indexer::near_primitives::hash::CryptoHash;
+use near_indexer::near_primitives::types::{BlockId, BlockReference};
+use near_indexer::near_primitives::views::{BlockHeaderView, BlockView};
+use near_indexer::StreamerMessage;
+use serde::Serialize;
+use tracing::info;
+
+use core::str::FromStr;
+
+/// Maximum number of missing blocks, which could be backfilled at once
+const MAX_BACKFILL_BLOCKS: usize = 1000;
+
+/// Kind of anomaly in the sequence of published blocks
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum AnomalyKind {
+    /// Block height isn't greater than the height of the last published block
+    NonMonotonicHeight,
+    /// Previous block hash doesn't match the hash of the last published block (missing blocks or fork)
+    HashChainBreak,
+}
+
+/// Alert message about anomaly, published to `<subject>.anomalies` subject
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct Anomaly {
+    pub kind: AnomalyKind,
+    pub last_published_height: u64,
+    pub last_published_hash: String,
+    pub block_height: u64,
+    pub block_hash: String,
+    pub prev_hash: String,
+}
+
+/// Tracking of the last published block header, for validation of heights monotonicity and hash chain continuity
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ContinuityTracker {
+    last_published: Option<(u64, String)>,
+}
+
+impl ContinuityTracker {
+    /// Tracker, continuing from the last published block of the previous run, if there is a checkpoint
+    /// and streaming resumes from it (`FromLastPublished` sync mode).
+    /// Other sync modes may start from any block (e.g. `LatestSynced` after downtime), so the first block isn't validated.
+    pub(crate) fn new(sync_mode: SyncMode, checkpoint: Option<PublishCheckpoint>) -> Self {
+        match sync_mode {
+            SyncMode::FromLastPublished => Self {
+                last_published: checkpoint
+                    .map(|checkpoint| (checkpoint.block_height, checkpoint.block_hash)),
+            },
+            SyncMode::LatestSynced | SyncMode::FromInterruption | SyncMode::BlockHeight => Self::default(),
+        }
+    }
+
+    /// Validate the block header against the last published block header
+    pub(crate) fn validate(&self, header: &BlockHeaderView) -> Option<Anomaly> {
+        self.validate_block(header.height, &header.hash.to_string(), &header.prev_hash.to_string())
+    }
+
+    /// Validate the block by its height, hash and previous block hash against the last published block
+    fn validate_block(&self, block_height: u64, block_hash: &str, prev_hash: &str) -> Option<Anomaly> {
+        let (last_published_height, last_published_hash) = self.last_published.as_ref()?;
+        let kind = if block_height <= *last_published_height {
+            AnomalyKind::NonMonotonicHeight
+        } else if prev_hash != last_published_hash {
+            AnomalyKind::HashChainBreak
+        } else {
+            return None;
+        };
+        Some(Anomaly {
+            kind,
+            last_published_height: *last_published_height,
+            last_published_hash: last_published_hash.clone(),
+            block_height,
+            block_hash: block_hash.to_string(),
+            prev_hash: prev_hash.to_string(),
+        })
+    }
+
+    pub(crate) fn observe(&mut self, header: &BlockHeaderView) {
+        self.observe_block(header.height, header.hash.to_string());
+    }
+
+    fn observe_block(&mut self, block_height: u64, block_hash: String) {
+        self.last_published = Some((block_height, block_hash));
+    }
+}
+
+async fn fetch_block(view_client: &Addr<ViewClientActor>, block_hash: CryptoHash) -> Result<BlockView, Error> {
+    let block = view_client
+        .send(GetBlock(BlockReference::BlockId(BlockId::Hash(block_hash))))
+        .await
+        .map_err(|error| format!("Continuity: Block {} request error: {:?}", block_hash, error))?
+        .map_err(|error| format!("Continuity: Block {} fetching error: {:?}", block_hash, error))?;
+    Ok(block)
+}
+
+/// Fetch blocks missing between the last published block and the block with hash chain break, walking back by previous block hashes.
+/// Returns missing blocks in order of heights, or error if the hash chain doesn't lead to the last published block (fork).
+pub(crate) async fn backfill(
+    view_client: &Addr<ViewClientActor>,
+    anomaly: &Anomaly,
+) -> Result<Vec<StreamerMessage>, Error> {
+    let mut missing_blocks: Vec<BlockView> = Vec::new();
+    let mut block_hash = CryptoHash::from_str(&anomaly.prev_hash)
+        .map_err(|error| format!("Continuity: Wrong block hash {}: {:?}", anomaly.prev_hash, error))?;
+    loop {
+        let block = fetch_block(view_client, block_hash).await?;
+        if block.header.height <= anomaly.last_published_height {
+            return Err(format!(
+                "Continuity: Fork detected, block #{} ({}) doesn't lead to the last published block #{} ({})",
+                anomaly.block_height, anomaly.block_hash, anomaly.last_published_height, anomaly.last_published_hash
+            )
+            .into());
+        }
+        block_hash = block.header.prev_hash;
+        missing_blocks.push(block);
+        if block_hash.to_string() == anomaly.last_published_hash {
+            break;
+        }
+        if missing_blocks.len() >= MAX_BACKFILL_BLOCKS {
+            return Err(format!(
+                "Continuity: More than {} blocks are missing between block #{} and the last published block #{}",
+                MAX_BACKFILL_BLOCKS, anomaly.block_height, anomaly.last_published_height
+            )
+            .into());
+        }
+    }
+
+    let mut streamer_messages = Vec::with_capacity(missing_blocks.len());
+    for block in missing_blocks.into_iter().rev() {
+        info!(target: "borealis_indexer", "Continuity: Backfilling of missing block #{} ({})", block.header.height, block.header.hash);
+        let streamer_message = near_indexer::build_streamer_message(view_client, block)
+            .await
+            .map_err(|error| format!("Continuity: Error while building message for missing block: {:?}", error))?;
+        streamer_messages.push(streamer_message);
+    }
+    Ok(streamer_messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_block_is_valid_without_checkpoint() {
+        let tracker = ContinuityTracker::new(SyncMode::FromLastPublished, None);
+        assert!(tracker.validate_block(100, "hash100", "hash99").is_none());
+    }
+
+    #[test]
+    fn next_block_continues_checkpoint() {
+        let tracker = ContinuityTracker::new(
+            SyncMode::FromLastPublished,
+            Some(PublishCheckpoint::new(100, String::from("hash100"))),
+        );
+        assert!(tracker.validate_block(101, "hash101", "hash100").is_none());
+        // Skipped heights without a block are still continuous by hash chain
+        assert!(tracker.validate_block(103, "hash103", "hash100").is_none());
+    }
+
+    #[test]
+    fn restart_with_other_sync_mode_ignores_checkpoint() {
+        for sync_mode in [SyncMode::LatestSynced, SyncMode::FromInterruption, SyncMode::BlockHeight] {
+            let mut tracker = ContinuityTracker::new(sync_mode, Some(PublishCheckpoint::new(100, String::from("hash100"))));
+            // Streaming starts far from the checkpoint, e.g. from the latest synced block or from an earlier height
+            assert!(tracker.validate_block(5000, "hash5000", "hash4999").is_none());
+            assert!(tracker.validate_block(50, "hash50", "hash49").is_none());
+            // Continuity is validated from the first streamed block
+            tracker.observe_block(5000, String::from("hash5000"));
+            assert!(tracker.validate_block(5002, "hash5002", "hash5001").is_some());
+        }
+        let tracker = ContinuityTracker::new(
+            SyncMode::FromLastPublished,
+            Some(PublishCheckpoint::new(100, String::from("hash100"))),
+        );
+        assert!(tracker.validate_block(5000, "hash5000", "hash4999").is_some());
+    }
+
+    #[test]
+    fn non_monotonic_height() {
+        let mut tracker = ContinuityTracker::default();
+        tracker.observe_block(100, String::from("hash100"));
+        for block_height in [99, 100] {
+            let anomaly = tracker.validate_block(block_height, "other", "hash100").unwrap();
+            assert_eq!(anomaly.kind, AnomalyKind::NonMonotonicHeight);
+            assert_eq!(anomaly.last_published_height, 100);
+            assert_eq!(anomaly.block_height, block_height);
+        }
+    }
+
+    #[test]
+    fn hash_chain_break() {
+        let mut tracker = ContinuityTracker::default();
+        tracker.observe_block(100, String::from("hash100"));
+        let anomaly = tracker.validate_block(102, "hash102", "hash101").unwrap();
+        assert_eq!(anomaly.kind, AnomalyKind::HashChainBreak);
+        assert_eq!(anomaly.last_published_hash, "hash100");
+        assert_eq!(anomaly.block_hash, "hash102");
+        assert_eq!(anomaly.prev_hash, "hash101");
+    }
+
+    #[test]
+    fn observed_block_becomes_the_last_published() {
+        let mut tracker = ContinuityTracker::default();
+        tracker.observe_block(100, String::from("hash100"));
+        tracker.observe_block(101, String::from("hash101"));
+        assert!(tracker.validate_block(102, "hash102", "hash101").is_none());
+        assert!(tracker.validate_block(102, "hash102", "hash100").is_some());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::configs::{Error, MsgFormat, TrainDictionaryArgs};
+use borealis_envelope::dictionary_id;
+use serde_cbor as cbor;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use zstd::dict::EncoderDictionary;
+
+use std::path::Path;
+
+/// Zstd dictionary, prepared for compression with particular compression level
+pub(crate) struct ZstdDictionary {
+    pub id: u32,
+    pub encoder_dictionary: EncoderDictionary<'static>,
+}
+
+impl std::fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdDictionary").field("id", &self.id).finish()
+    }
+}
+
+impl ZstdDictionary {
+    /// Load dictionary file, trained by `train-dictionary` subcommand (or by `zstd --train`)
+    pub(crate) fn load(dictionary_path: &Path, level: i32) -> Result<Self, Error> {
+        let dictionary = std::fs::read(dictionary_path).map_err(|error| {
+            format!("Dictionary: Error while reading Zstd dictionary {:?}: {:?}", dictionary_path, error)
+        })?;
+        let id = dictionary_id(&dictionary)?;
+        info!(target: "borealis_indexer", "Dictionary: Loaded Zstd dictionary {:?} with ID {}, compression level {}", dictionary_path, id, level);
+        Ok(Self {
+            id,
+            encoder_dictionary: EncoderDictionary::copy(&dictionary, level),
+        })
+    }
+}
+
+/// Collect samples of recent blocks (encoded in the streaming format), train Zstd dictionary on them and store it into the file
+pub(crate) async fn train_dictionary(
+    mut events_stream: mpsc::Receiver<near_indexer::StreamerMessage>,
+    context: TrainDictionaryArgs,
+) {
+    info!(
+        target: "borealis_indexer",
+        "Dictionary training started: collecting {} samples of blocks\n", context.samples
+    );
+
+    let mut samples: Vec<Vec<u8>> = Vec::with_capacity(context.samples);
+    while let Some(streamer_message) = events_stream.recv().await {
+        let sample = match context.msg_format {
+            MsgFormat::Cbor => cbor::to_vec(&streamer_message).unwrap(),
+            MsgFormat::Json => serde_json::to_vec(&streamer_message).unwrap(),
+        };
+        samples.push(sample);
+
+        info!(
+            target: "borealis_indexer",
+            "Dictionary training: sample #{} of {} collected, block_height: #{}, block_hash: {}\n",
+            samples.len(),
+            context.samples,
+            &streamer_message.block.header.height,
+            &streamer_message.block.header.hash
+        );
+
+        if samples.len() >= context.samples {
+            break;
+        }
+    }
+
+    match zstd::dict::from_samples(&samples, context.dictionary_size) {
+        Ok(dictionary) => match std::fs::write(&context.dictionary_path, &dictionary) {
+            Ok(()) => {
+                info!(
+                    target: "borealis_indexer",
+                    "Dictionary training: Zstd dictionary with ID {} ({} bytes) has been trained on {} samples and stored into {:?}\n",
+                    dictionary_id(&dictionary).unwrap_or_default(),
+                    dictionary.len(),
+                    samples.len(),
+                    context.dictionary_path
+                );
+            }
+            Err(error) => {
+                error!(target: "borealis_indexer", "Dictionary training: Error while storing Zstd dictionary into {:?}: {:?}", context.dictionary_path, error);
+            }
+        },
+        Err(error) => {
+            error!(target: "borealis_indexer", "Dictionary training: Error while training Zstd dictionary: {:?}", error);
+        }
+    }
+
+    actix::System::current().stop();
+}
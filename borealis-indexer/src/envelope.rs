@@ -0,0 +1,227 @@
+use crate::configs::{CompressionMode, Error, MsgFormat, RunArgs};
+use crate::dictionary::ZstdDictionary;
+use crate::metrics;
+use borealis_envelope::{
+    ENVELOPE_VERSION, HEADER_ENVELOPE_VERSION, HEADER_PAYLOAD_COMPRESSION, HEADER_PAYLOAD_ENCODING,
+    HEADER_ZSTD_DICTIONARY_ID,
+};
+use borealis_types::types::BorealisMessage;
+use nats::Headers;
+use serde::Serialize;
+use serde_cbor as cbor;
+
+use std::io::Write;
+use std::sync::Arc;
+
+pub(crate) use borealis_envelope::HEADER_BLOCK_HEIGHT;
+
+/// Description of how Borealis Message and its payload are encoded
+#[derive(Debug, Clone)]
+pub(crate) struct Envelope {
+    pub version: u8,
+    pub msg_format: MsgFormat,
+    pub payload_compression: Option<CompressionMode>,
+    pub zstd_level: Option<i32>,
+    pub zstd_dictionary: Option<Arc<ZstdDictionary>>,
+}
+
+impl Envelope {
+    pub(crate) fn new(msg_format: MsgFormat, payload_compression: Option<CompressionMode>) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            msg_format,
+            payload_compression,
+            zstd_level: None,
+            zstd_dictionary: None,
+        }
+    }
+
+    /// Envelope accordingly to CLI options, with Zstd dictionary loaded from `--zstd-dictionary` file
+    pub(crate) fn from_args(context: &RunArgs) -> Result<Self, Error> {
+        let mut envelope = Self::new(context.msg_format, context.payload_compression);
+        if let Some(CompressionMode::Zstd) = context.payload_compression {
+            envelope.zstd_level = context.zstd_level;
+            if let Some(dictionary_path) = &context.zstd_dictionary {
+                let zstd_level = context.zstd_level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                envelope.zstd_dictionary =
+                    Some(Arc::new(ZstdDictionary::load(dictionary_path, zstd_level)?));
+            }
+        }
+        Ok(envelope)
+    }
+
+    /// Names and values of headers describing the envelope
+    pub(crate) fn header_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut header_pairs = vec![
+            (HEADER_ENVELOPE_VERSION, self.version.to_string()),
+            (HEADER_PAYLOAD_ENCODING, self.msg_format.to_string()),
+            (
+                HEADER_PAYLOAD_COMPRESSION,
+                self.payload_compression
+                    .map(|compression_mode| compression_mode.to_string())
+                    .unwrap_or_else(|| String::from("None")),
+            ),
+        ];
+        if let Some(zstd_dictionary) = &self.zstd_dictionary {
+            header_pairs.push((HEADER_ZSTD_DICTIONARY_ID, zstd_dictionary.id.to_string()));
+        }
+        header_pairs
+    }
+
+    /// NATS message headers describing the envelope, so consumers are able to decode messages without any prior configuration
+    pub(crate) fn to_headers(&self) -> Headers {
+        self.header_pairs()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    /// Zstd compression with configured compression level and dictionary
+    fn payload_compress_zstd(&self, payload_bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match (&self.zstd_dictionary, self.zstd_level) {
+            (Some(zstd_dictionary), _) => {
+                let mut encoder = zstd::stream::Encoder::with_prepared_dictionary(
+                    Vec::new(),
+                    &zstd_dictionary.encoder_dictionary,
+                )?;
+                encoder.write_all(payload_bytes)?;
+                Ok(encoder.finish()?)
+            }
+            (None, Some(zstd_level)) => Ok(zstd::bulk::compress(payload_bytes, zstd_level)?),
+            (None, None) => {
+                let (payload_bytes_compressed, _payload_len) =
+                    BorealisMessage::<Vec<u8>>::payload_compress_zstd(payload_bytes)
+                        .map_err(|error| format!("Envelope: Payload compression error: {:?}", error))?;
+                Ok(payload_bytes_compressed)
+            }
+        }
+    }
+
+    /// Encode payload into Borealis Message.
+    /// Uncompressed payload is embedded into the message natively, compressed payload is encoded
+    /// in the message format (CBOR or JSON) first and then embedded as compressed bytes vector.
+    pub(crate) fn encode_message<T: Serialize>(&self, id: u64, payload: &T) -> Result<Vec<u8>, Error> {
+        let result = if let Some(compression_mode) = self.payload_compression {
+            let payload_bytes = match self.msg_format {
+                MsgFormat::Cbor => cbor::to_vec(payload)?,
+                MsgFormat::Json => serde_json::to_vec(payload)?,
+            };
+            let payload_bytes_compressed = match compression_mode {
+                CompressionMode::Lz4f => {
+                    let (payload_bytes_compressed, _payload_len) =
+                        BorealisMessage::<Vec<u8>>::payload_compress_lz4(&payload_bytes)
+                            .map_err(|error| format!("Envelope: Payload compression error: {:?}", error))?;
+                    payload_bytes_compressed
+                }
+                CompressionMode::Zstd => self.payload_compress_zstd(&payload_bytes)?,
+            };
+            metrics::PAYLOAD_SIZE
+                .with_label_values(&["before"])
+                .observe(payload_bytes.len() as f64);
+            metrics::PAYLOAD_SIZE
+                .with_label_values(&["after"])
+                .observe(payload_bytes_compressed.len() as f64);
+            let borealis_message = BorealisMessage::new(id, payload_bytes_compressed);
+            match self.msg_format {
+                MsgFormat::Cbor => borealis_message.to_cbor(),
+                MsgFormat::Json => borealis_message.to_json_bytes(),
+            }
+        } else {
+            let borealis_message = BorealisMessage::new(id, payload);
+            match self.msg_format {
+                MsgFormat::Cbor => borealis_message.to_cbor(),
+                MsgFormat::Json => borealis_message.to_json_bytes(),
+            }
+        };
+        let message_bytes =
+            result.map_err(|error| format!("Envelope: Message encoding error: {:?}", error))?;
+        if self.payload_compression.is_none() {
+            // Without compression payload size is the same before and after
+            for compression in ["before", "after"] {
+                metrics::PAYLOAD_SIZE
+                    .with_label_values(&[compression])
+                    .observe(message_bytes.len() as f64);
+            }
+        }
+        Ok(message_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use borealis_envelope::ZstdDictionaries;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Payload {
+        height: u64,
+        hash: String,
+        accounts: Vec<String>,
+    }
+
+    fn payload(height: u64) -> Payload {
+        Payload {
+            height,
+            hash: format!("{:064x}", height * 7919),
+            accounts: (0..height % 8)
+                .map(|index| format!("account{}.aurora.near", height + index))
+                .collect(),
+        }
+    }
+
+    fn round_trip(envelope: &Envelope, zstd_dictionaries: &ZstdDictionaries) {
+        let expected = payload(63596);
+        let message_bytes = envelope.encode_message(1, &expected).unwrap();
+        let headers = envelope.to_headers();
+        let decoded: Payload =
+            borealis_envelope::decode_message(Some(&headers), &message_bytes, MsgFormat::Cbor, zstd_dictionaries).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn zstd_round_trip_with_default_level() {
+        for msg_format in [MsgFormat::Cbor, MsgFormat::Json] {
+            let envelope = Envelope::new(msg_format, Some(CompressionMode::Zstd));
+            round_trip(&envelope, &ZstdDictionaries::default());
+        }
+    }
+
+    #[test]
+    fn zstd_round_trip_with_configured_level() {
+        for msg_format in [MsgFormat::Cbor, MsgFormat::Json] {
+            let envelope = Envelope {
+                zstd_level: Some(19),
+                ..Envelope::new(msg_format, Some(CompressionMode::Zstd))
+            };
+            round_trip(&envelope, &ZstdDictionaries::default());
+        }
+    }
+
+    #[test]
+    fn zstd_round_trip_with_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..1000).map(|height| cbor::to_vec(&payload(height)).unwrap()).collect();
+        let dictionary = zstd::dict::from_samples(&samples, 16 * 1024).unwrap();
+        let dictionary_path =
+            std::env::temp_dir().join(format!("borealis-indexer-envelope-test-{}.dict", std::process::id()));
+        std::fs::write(&dictionary_path, &dictionary).unwrap();
+        let zstd_dictionary = ZstdDictionary::load(&dictionary_path, 19);
+        std::fs::remove_file(&dictionary_path).unwrap();
+
+        let mut zstd_dictionaries = ZstdDictionaries::default();
+        zstd_dictionaries.insert(&dictionary).unwrap();
+        let envelope = Envelope {
+            zstd_dictionary: Some(Arc::new(zstd_dictionary.unwrap())),
+            ..Envelope::new(MsgFormat::Cbor, Some(CompressionMode::Zstd))
+        };
+        round_trip(&envelope, &zstd_dictionaries);
+    }
+
+    #[test]
+    fn lz4f_and_uncompressed_round_trip() {
+        for payload_compression in [Some(CompressionMode::Lz4f), None] {
+            let envelope = Envelope::new(MsgFormat::Cbor, payload_compression);
+            round_trip(&envelope, &ZstdDictionaries::default());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::configs::Error;
+use crate::envelope::Envelope;
+use near_indexer::StreamerMessage;
+
+/// Message with a single entity of `StreamerMessage`, published to its own subject
+#[derive(Debug, Clone)]
+pub(crate) struct FanOutMessage {
+    /// Subject suffix, e.g. `headers` or `shard.0.txs`, appended to the messages subject
+    pub subject_suffix: String,
+    pub message_bytes: Vec<u8>,
+}
+
+/// Split `StreamerMessage` into granular per-entity Borealis Messages:
+/// `<subject>.headers` - block header;
+/// `<subject>.shard.<shard_id>.chunk` - chunk header;
+/// `<subject>.shard.<shard_id>.txs` - chunk transactions with outcomes;
+/// `<subject>.shard.<shard_id>.receipts` - chunk receipts;
+/// `<subject>.shard.<shard_id>.outcomes` - receipt execution outcomes;
+/// `<subject>.shard.<shard_id>.state_changes` - state changes.
+/// Block header is always published, while per-shard entities only if they aren't empty.
+pub(crate) fn fan_out(
+    envelope: &Envelope,
+    streamer_message: &StreamerMessage,
+) -> Result<Vec<FanOutMessage>, Error> {
+    let block_height = streamer_message.block.header.height;
+    let mut messages = vec![FanOutMessage {
+        subject_suffix: String::from("headers"),
+        message_bytes: envelope.encode_message(block_height, &streamer_message.block.header)?,
+    }];
+
+    for shard in streamer_message.shards.iter() {
+        if let Some(chunk) = &shard.chunk {
+            messages.push(FanOutMessage {
+                subject_suffix: format!("shard.{}.chunk", shard.shard_id),
+                message_bytes: envelope.encode_message(block_height, &chunk.header)?,
+            });
+            if !chunk.transactions.is_empty() {
+                messages.push(FanOutMessage {
+                    subject_suffix: format!("shard.{}.txs", shard.shard_id),
+                    message_bytes: envelope.encode_message(block_height, &chunk.transactions)?,
+                });
+            }
+            if !chunk.receipts.is_empty() {
+                messages.push(FanOutMessage {
+                    subject_suffix: format!("shard.{}.receipts", shard.shard_id),
+                    message_bytes: envelope.encode_message(block_height, &chunk.receipts)?,
+                });
+            }
+        }
+        if !shard.receipt_execution_outcomes.is_empty() {
+            messages.push(FanOutMessage {
+                subject_suffix: format!("shard.{}.outcomes", shard.shard_id),
+                message_bytes: envelope
+                    .encode_message(block_height, &shard.receipt_execution_outcomes)?,
+            });
+        }
+        if !shard.state_changes.is_empty() {
+            messages.push(FanOutMessage {
+                subject_suffix: format!("shard.{}.state_changes", shard.shard_id),
+                message_bytes: envelope.encode_message(block_height, &shard.state_changes)?,
+            });
+        }
+    }
+
+    Ok(messages)
+}
@@ -0,0 +1,232 @@
+use crate::configs::Error;
+use near_indexer::near_primitives::views::{
+    ActionView, ReceiptEnumView, ReceiptView, SignedTransactionView, StateChangeValueView,
+    StateChangeWithCauseView,
+};
+use near_indexer::{IndexerShard, StreamerMessage};
+use serde::Deserialize;
+use tracing::info;
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Filter configuration for publishing of trimmed `StreamerMessage`s to per-account subjects `<subject>.account.<account_token>`,
+/// where account token is the account ID with dots replaced by colons (see `account_token()`).
+///
+/// Example (JSON file passed with `--filter-config`):
+/// {
+///     "receiver_ids": ["aurora", "factory.bridge.near"],
+///     "signer_ids": [],
+///     "predecessor_ids": ["aurora"],
+///     "action_kinds": ["FunctionCall"],
+///     "method_names": ["submit", "call", "deploy_code", "withdraw"]
+/// }
+///
+/// Empty `action_kinds` and `method_names` lists mean any action or method.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct FilterConfig {
+    /// Transactions and receipts with these receiver accounts
+    pub receiver_ids: Vec<String>,
+    /// Transactions and action receipts signed by these accounts
+    pub signer_ids: Vec<String>,
+    /// Receipts with these predecessor accounts
+    pub predecessor_ids: Vec<String>,
+    /// Action kinds (`CreateAccount`, `DeployContract`, `FunctionCall`, `Transfer`, `Stake`, `AddKey`, `DeleteKey`, `DeleteAccount`)
+    pub action_kinds: Vec<String>,
+    /// Method names of `FunctionCall` actions
+    pub method_names: Vec<String>,
+}
+
+/// Single NATS subject token for the account ID: dots (NATS subject tokens separator) are replaced by colons,
+/// which aren't allowed in NEAR account IDs, so the mapping is reversible (`factory.bridge.near` -> `factory:bridge:near`)
+pub(crate) fn account_token(account_id: &str) -> String {
+    account_id.replace('.', ":")
+}
+
+fn action_kind(action: &ActionView) -> &'static str {
+    match action {
+        ActionView::CreateAccount => "CreateAccount",
+        ActionView::DeployContract { .. } => "DeployContract",
+        ActionView::FunctionCall { .. } => "FunctionCall",
+        ActionView::Transfer { .. } => "Transfer",
+        ActionView::Stake { .. } => "Stake",
+        ActionView::AddKey { .. } => "AddKey",
+        ActionView::DeleteKey { .. } => "DeleteKey",
+        ActionView::DeleteAccount { .. } => "DeleteAccount",
+    }
+}
+
+fn state_change_account_id(state_change: &StateChangeWithCauseView) -> &str {
+    match &state_change.value {
+        StateChangeValueView::AccountUpdate { account_id, .. }
+        | StateChangeValueView::AccountDeletion { account_id }
+        | StateChangeValueView::AccessKeyUpdate { account_id, .. }
+        | StateChangeValueView::AccessKeyDeletion { account_id, .. }
+        | StateChangeValueView::DataUpdate { account_id, .. }
+        | StateChangeValueView::DataDeletion { account_id, .. }
+        | StateChangeValueView::ContractCodeUpdate { account_id, .. }
+        | StateChangeValueView::ContractCodeDeletion { account_id } => account_id.as_str(),
+    }
+}
+
+impl FilterConfig {
+    /// Load filter configuration from JSON file
+    pub(crate) fn load(filter_config_path: &Path) -> Result<Self, Error> {
+        let filter_config_bytes = std::fs::read(filter_config_path).map_err(|error| {
+            format!("Filter: Error while reading filter configuration {:?}: {:?}", filter_config_path, error)
+        })?;
+        let filter_config: Self = serde_json::from_slice(&filter_config_bytes).map_err(|error| {
+            format!("Filter: Error while parsing filter configuration {:?}: {:?}", filter_config_path, error)
+        })?;
+        filter_config.validate().map_err(|error| {
+            format!("Filter: Wrong filter configuration {:?}: {}", filter_config_path, error)
+        })?;
+        info!(target: "borealis_indexer", "Filter: Loaded filter configuration: {:?}", &filter_config);
+        Ok(filter_config)
+    }
+
+    /// Account IDs should consist of NEAR account ID characters (lowercase alphanumerics and `.`, `_`, `-` separators),
+    /// so their subject tokens don't contain wildcards, whitespaces or colons
+    fn validate(&self) -> Result<(), Error> {
+        for account_id in self.accounts() {
+            let valid = !account_id.is_empty()
+                && account_id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '_' || c == '-');
+            if !valid {
+                return Err(format!("Account ID {:?} isn't a valid NEAR account ID", account_id).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// All accounts from allowlists, each of them has its own derived subject
+    pub(crate) fn accounts(&self) -> BTreeSet<&str> {
+        self.receiver_ids
+            .iter()
+            .chain(self.signer_ids.iter())
+            .chain(self.predecessor_ids.iter())
+            .map(|account_id| account_id.as_str())
+            .collect()
+    }
+
+    fn actions_match(&self, actions: &[ActionView]) -> bool {
+        if self.action_kinds.is_empty() && self.method_names.is_empty() {
+            return true;
+        }
+        actions.iter().any(|action| {
+            let action_kind_match = self.action_kinds.is_empty()
+                || self.action_kinds.iter().any(|kind| kind == action_kind(action));
+            let method_name_match = self.method_names.is_empty()
+                || match action {
+                    ActionView::FunctionCall { method_name, .. } => {
+                        self.method_names.contains(method_name)
+                    }
+                    _ => false,
+                };
+            action_kind_match && method_name_match
+        })
+    }
+
+    fn transaction_match(&self, account_id: &str, transaction: &SignedTransactionView) -> bool {
+        let account_match = (transaction.receiver_id.as_str() == account_id
+            && self.receiver_ids.iter().any(|id| id == account_id))
+            || (transaction.signer_id.as_str() == account_id
+                && self.signer_ids.iter().any(|id| id == account_id));
+        account_match && self.actions_match(&transaction.actions)
+    }
+
+    fn receipt_match(&self, account_id: &str, receipt: &ReceiptView) -> bool {
+        let (signer_id, actions) = match &receipt.receipt {
+            ReceiptEnumView::Action {
+                signer_id, actions, ..
+            } => (Some(signer_id.as_str()), actions.as_slice()),
+            ReceiptEnumView::Data { .. } => (None, &[][..]),
+        };
+        let account_match = (receipt.receiver_id.as_str() == account_id
+            && self.receiver_ids.iter().any(|id| id == account_id))
+            || (receipt.predecessor_id.as_str() == account_id
+                && self.predecessor_ids.iter().any(|id| id == account_id))
+            || (signer_id == Some(account_id)
+                && self.signer_ids.iter().any(|id| id == account_id));
+        account_match && self.actions_match(actions)
+    }
+
+    /// Trimmed `StreamerMessage` with transactions, receipts, execution outcomes and state changes touching the account.
+    /// Block (header and chunk headers) is kept as is. Returns `None` if nothing in the block touches the account.
+    pub(crate) fn trim(
+        &self,
+        account_id: &str,
+        streamer_message: &StreamerMessage,
+    ) -> Option<StreamerMessage> {
+        let shards: Vec<IndexerShard> = streamer_message
+            .shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.clone();
+                if let Some(chunk) = shard.chunk.as_mut() {
+                    chunk.transactions.retain(|transaction| {
+                        self.transaction_match(account_id, &transaction.transaction)
+                    });
+                    chunk
+                        .receipts
+                        .retain(|receipt| self.receipt_match(account_id, receipt));
+                }
+                shard
+                    .receipt_execution_outcomes
+                    .retain(|outcome| self.receipt_match(account_id, &outcome.receipt));
+                shard
+                    .state_changes
+                    .retain(|state_change| state_change_account_id(state_change) == account_id);
+                shard
+            })
+            .collect();
+
+        let is_empty = shards.iter().all(|shard| {
+            shard.chunk.as_ref().map_or(true, |chunk| {
+                chunk.transactions.is_empty() && chunk.receipts.is_empty()
+            }) && shard.receipt_execution_outcomes.is_empty()
+                && shard.state_changes.is_empty()
+        });
+
+        if is_empty {
+            None
+        } else {
+            Some(StreamerMessage {
+                block: streamer_message.block.clone(),
+                shards,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_id_is_a_single_subject_token() {
+        assert_eq!(account_token("aurora"), "aurora");
+        assert_eq!(account_token("factory.bridge.near"), "factory:bridge:near");
+        assert_eq!(account_token("factory.bridge.near").replace(':', "."), "factory.bridge.near");
+        assert!(!account_token("relay.aurora").contains('.'));
+    }
+
+    #[test]
+    fn account_ids_are_validated() {
+        let filter_config = FilterConfig {
+            receiver_ids: vec![String::from("aurora"), String::from("factory.bridge.near")],
+            predecessor_ids: vec![String::from("user_1-test.near")],
+            ..Default::default()
+        };
+        assert!(filter_config.validate().is_ok());
+        for account_id in ["*", "aurora.>", "Aurora", "aurora near", "aurora:near", ""] {
+            let filter_config = FilterConfig {
+                signer_ids: vec![String::from(account_id)],
+                ..Default::default()
+            };
+            assert!(filter_config.validate().is_err(), "{:?}", account_id);
+        }
+    }
+}
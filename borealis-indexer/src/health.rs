@@ -0,0 +1,60 @@
+use crate::configs::AwaitSynced;
+use crate::NATSConnection;
+use actix::Addr;
+use near_client::{ClientActor, Status};
+use tokio::sync::watch;
+use tracing::debug;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Interval between readiness probes
+const READINESS_PROBE_INTERVAL: core::time::Duration = core::time::Duration::from_millis(5000);
+
+/// Readiness of the indexer, reported by `/readyz` endpoint
+static READY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+/// Periodical readiness probe: the indexer is ready when the node is synced (or is syncing, with `StreamWhileSyncing`)
+/// and the current NATS connection is workable
+pub(crate) async fn readiness_probe(
+    client: Addr<ClientActor>,
+    actual_connection_rx: watch::Receiver<NATSConnection>,
+    await_synced: AwaitSynced,
+) {
+    loop {
+        let node_ready = match client
+            .send(Status {
+                is_health_check: false,
+                detailed: false,
+            })
+            .await
+        {
+            Ok(Ok(status)) => match await_synced {
+                AwaitSynced::WaitForFullSync => !status.sync_info.syncing,
+                AwaitSynced::StreamWhileSyncing => true,
+            },
+            Ok(Err(error)) => {
+                debug!(target: "borealis_indexer", "Readiness Probe: Node status error: {:?}", error);
+                false
+            }
+            Err(error) => {
+                debug!(target: "borealis_indexer", "Readiness Probe: Node status request error: {:?}", error);
+                false
+            }
+        };
+
+        let nats_connection = actual_connection_rx.borrow().clone();
+        let connection_ready =
+            tokio::task::spawn_blocking(move || nats_connection.nats_probe_connection().is_ok())
+                .await
+                .unwrap_or(false);
+
+        debug!(target: "borealis_indexer", "Readiness Probe: node ready: {}, NATS connection ready: {}", node_ready, connection_ready);
+        READY.store(node_ready && connection_ready, Ordering::SeqCst);
+
+        tokio::time::sleep(READINESS_PROBE_INTERVAL).await;
+    }
+}
@@ -1,13 +1,22 @@
 use actix;
-use borealis_types::types::BorealisMessage;
+use actix::Addr;
+use archive::ArchiveWriter;
+use checkpoint::PublishCheckpoint;
+use continuity::{AnomalyKind, ContinuityTracker};
+use envelope::Envelope;
+use filter::FilterConfig;
+use pipeline::{BlockEncoder, EncodedBlock, OutgoingMessage};
 use clap::Parser;
 use configs::{
-    init_logging, AwaitSynced, Error, MsgFormat, Opts, RunArgs, SubCommand, SyncMode,
-    VerbosityLevel, CompressionMode,
+    init_logging, AnomalyAction, AwaitSynced, Error, Opts, RunArgs, SubCommand, SyncMode,
+    VerbosityLevel, PublishMode,
 };
 use core::sync::atomic::{AtomicUsize, Ordering};
+use futures::StreamExt;
 use nats;
+use near_client::ViewClientActor;
 use near_indexer;
+use serde::Deserialize;
 use serde_cbor as cbor;
 use serde_json;
 use tokio::runtime::{Runtime, Builder};
@@ -15,9 +24,25 @@ use tokio::signal::{ctrl_c, unix::{signal, SignalKind}};
 use tokio::sync::{mpsc, watch};
 use tracing:: {info, error, debug};
 
+use std::sync::Arc;
+
+pub mod archive;
+pub mod checkpoint;
 pub mod configs;
+pub mod continuity;
+pub mod dictionary;
+pub mod envelope;
+pub mod fanout;
+pub mod filter;
+pub mod health;
+pub mod metrics;
+pub mod pipeline;
+pub mod query;
+pub mod replay;
 
 static SIGNAL: AtomicUsize = AtomicUsize::new(0);
+/// `SIGNAL` value for streaming halted due to anomaly in blocks sequence: the indexer isn't restarted and exits with error
+const HALT_SIGNAL: usize = 602437501;
 
 async fn kill_switch_usr1() -> Result<(), Error> {
     let mut kill_signal_stream = signal(SignalKind::from_raw(10))?;
@@ -102,206 +127,409 @@ async fn key_switch() -> Result<(), Error> {
     Ok(())
 }
 
+/// Publish batch of messages to NATS subjects, retrying over a new connection until all messages will be delivered.
+/// In `Core` publish mode the connection is flushed once per batch.
+/// In `JetStream` publish mode up to `--max-in-flight` messages are awaiting for acknowledgements from the server at the same time,
+/// and after failure publishing is retried from the first unacknowledged message. Each message expects the previous one
+/// to be the last message stored in the stream, so messages following the failed one are rejected by the server
+/// instead of being stored out of order (retransmitted messages, already stored in a stream, are dropped by the server as duplicates).
+/// Publishing and awaiting for acknowledgements are blocking operations, so they're executed on the blocking threads pool.
+async fn message_publisher(
+    actual_connection_rx: &watch::Receiver<NATSConnection>,
+    connection_event_tx: &mpsc::Sender<ConnectionEvent>,
+    context: &RunArgs,
+    message_headers: &nats::Headers,
+    messages: Arc<Vec<OutgoingMessage>>,
+) {
+    let mut delivered = 0;
+    while delivered < messages.len() {
+        let nats_connection = actual_connection_rx.borrow().clone();
+        debug!(target: "borealis_indexer", "Message Publisher [{} bytes vector]: Current Connection: NATS Connection: {:?}", context.msg_format.to_string(), &nats_connection);
+
+        let in_flight = match context.publish_mode {
+            PublishMode::Core => messages.len(),
+            PublishMode::Jetstream => std::cmp::min(messages.len(), delivered + context.max_in_flight.max(1)),
+        };
+        let result = {
+            let nats_connection = nats_connection.clone();
+            let message_headers = message_headers.clone();
+            let messages = messages.clone();
+            let publish_mode = context.publish_mode;
+            let ack_timeout = core::time::Duration::from_millis(context.publish_ack_timeout);
+            tokio::task::spawn_blocking(move || match publish_mode {
+                PublishMode::Core => nats_connection.publish_batch(&message_headers, &messages[delivered..in_flight]),
+                PublishMode::Jetstream => nats_connection.jetstream_publish_batch(
+                    &message_headers,
+                    &messages[delivered..in_flight],
+                    delivered.checked_sub(1).map(|previous| messages[previous].message_id.as_str()),
+                    ack_timeout,
+                ),
+            })
+            .await
+            .unwrap_or_else(|error| Err(format!("Publishing task panicked: {:?}", error).into()))
+        };
+
+        match &result {
+            Ok(published) if delivered + published == in_flight => {
+                debug!(target: "borealis_indexer", "Message Publisher [{} bytes vector]: Actual Connection: NATS Connection: {:?}", context.msg_format.to_string(), &nats_connection);
+                delivered = in_flight;
+                drop(result);
+                drop(nats_connection);
+                continue;
+            }
+            Ok(published) => {
+                delivered += published;
+                error!(target: "borealis_indexer", "Message Publisher [{} bytes vector]: Messages weren't acknowledged, retrying in order from message ID {}", context.msg_format.to_string(), messages[delivered].message_id);
+            }
+            Err(error) => {
+                error!(target: "borealis_indexer", "Message Publisher [{} bytes vector]: Message passing error, NATS connection error or wrong credentials: {:?}", context.msg_format.to_string(), error);
+            }
+        }
+
+        connection_event_tx
+            .send(ConnectionEvent::NewConnectionRequest(nats_connection.cid))
+            .await
+            .unwrap_or_else(|error|
+                error!(target: "borealis_indexer", "Message Publisher [{} bytes vector]: New Connection Request: NATS Connection with CID {} event send error: {:?}", context.msg_format.to_string(), nats_connection.cid, error)
+            );
+        drop(result);
+        drop(nats_connection);
+        tokio::time::sleep(core::time::Duration::from_millis(500)).await;
+    }
+}
+
 async fn message_producer(
-    mut events_stream: mpsc::Receiver<near_indexer::StreamerMessage>,
+    events_stream: mpsc::Receiver<near_indexer::StreamerMessage>,
+    view_client: Addr<ViewClientActor>,
     actual_connection_rx: watch::Receiver<NATSConnection>,
     connection_event_tx: mpsc::Sender<ConnectionEvent>,
     context: RunArgs,
     verbosity_level: Option<VerbosityLevel>,
+    home_dir: std::path::PathBuf,
 ) {
     info!(
         target: "borealis_indexer",
         "Message producer loop started: listening for new messages\n"
     );
 
-    while let Some(streamer_message) = events_stream.recv().await {
-        /*
-            Example of `StreamerMessage` with all data fields (filled with synthetic data, as an example):
-
-            Note that `outcomes` for a given transaction won't be included into the same block.
-            Execution outcomes are included into the blocks after the transaction or receipt
-            are recorded on a chain; in most cases, it is the next block after the one that has
-            the transaction or receipt.
-
-            StreamerMessage {
-                block: BlockView {
-                    author: "test.near",
-                    header: BlockHeaderView {
-                        height: 63596,
-                        epoch_id: `Bk7pvZWUTfHRRZtfgTDjnQ6y5cV8yG2h3orCqJvUbiym`,
-                        next_epoch_id: `3JuBZ4Gz5Eauf7PzQegfqSEDyvws3eKJYPbfGHAYmeR5`,
-                        hash: `5X37niQWWcihDGQjsvDMHYKLCurNJyQLxCeLgneDb8mk`,
-                        prev_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
-                        prev_state_root: `GkdxSBf4Kfq8V16N4Kqn3YdcThG1f5KG1KLBmXpMzP1k`,
-                        chunk_receipts_root: `9ETNjrt6MkwTgSVMMbpukfxRshSD1avBUUa4R4NuqwHv`,
-                        chunk_headers_root: `C7dVr9KdXYKt31yF2BkeAu115fpo79zYTqeU3FzqbFak`,
-                        chunk_tx_root: `7tkzFg8RHBmMw1ncRJZCCZAizgq4rwCftTKYLce8RU8t`,
-                        outcome_root: `7tkzFg8RHBmMw1ncRJZCCZAizgq4rwCftTKYLce8RU8t`,
-                        chunks_included: 1,
-                        challenges_root: `11111111111111111111111111111111`,
-                        timestamp: 1618558205803345000,
-                        timestamp_nanosec: 1618558205803345000,
-                        random_value: `3cAa93XmoLaKAJQgWz3K7SiKwnA3uaxi8MGgLM78HTNS`,
-                        validator_proposals: [],
-                        chunk_mask: [
-                            true,
-                        ],
-                        gas_price: 1000000000,
-                        rent_paid: 0,
-                        validator_reward: 0,
-                        total_supply: 2050206401403887985811862247311434,
-                        challenges_result: [],
-                        last_final_block: `DCkMmXYHqibzcMjgFjRXJP7eckAMLrA4ijggSApMNwKu`,
-                        last_ds_final_block: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
-                        next_bp_hash: `4DJWnxRbUhRrsXK6EBkx4nFeXHKgJWqteDnJ7Hv4MZ6M`,
-                        block_merkle_root: `Bvn5K89fJ3uPNsj3324Ls9TXAGUVteHPpfKwKqL1La6W`,
-                        approvals: [
-                            Some(
-                                ed25519:F816hgJod7nPfD2qQz5yhaKDMn1JXmvzj2iXegsJpsmPNnYYZpKYJXgyuVTVJ4TKQbcJ2Q3USCGZF6fX2TcwBBv,
-                            ),
-                        ],
-                        signature: ed25519:239NbE4BuJaxneQA3AEsPrsGY7v3wBgaezbgg56HER69zPrBoc3a4fbyVWPXeoKE3LvgGma1g6pSHk9QHkmETCZY,
-                        latest_protocol_version: 43,
-                    },
-                    chunks: [
-                        ChunkHeaderView {
-                            chunk_hash: `2M2oeNFBbUUnHfkU1UuBr8EKBCLMH9xr2vfsGRpyiBmA`,
-                            prev_block_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
-                            outcome_root: `11111111111111111111111111111111`,
-                            prev_state_root: `3gZPPijaumgMRCvMuuZZM1Ab2LoHTSfYigMKwLqZ67m6`,
-                            encoded_merkle_root: `79Bt7ivt9Qhp3c6dJYnueaTyPVweYxZRpQHASRRAiyuy`,
-                            encoded_length: 8,
-                            height_created: 63596,
-                            height_included: 63596,
-                            shard_id: 0,
-                            gas_used: 0,
-                            gas_limit: 1000000000000000,
-                            rent_paid: 0,
-                            validator_reward: 0,
-                            balance_burnt: 0,
-                            outgoing_receipts_root: `H4Rd6SGeEBTbxkitsCdzfu9xL9HtZ2eHoPCQXUeZ6bW4`,
-                            tx_root: `11111111111111111111111111111111`,
-                            validator_proposals: [],
-                            signature: ed25519:2vWNayBzEoW5DRc7gTdhxdLbkKuK6ACQ78p3JGpKSAZZCarnLroeoALPAFwpr9ZNPxBqdVYh9QLBe7WHZebsS17Z,
-                        },
+    // Envelope version, payload encoding and compression codec are the same for all messages, and carried in NATS headers
+    let envelope = Envelope::from_args(&context)
+        .expect("Message Producer: Error while loading Zstd dictionary for payload compression");
+    let message_headers = envelope.to_headers();
+
+    // Filter for publishing of trimmed messages to per-account subjects
+    let filter_config = context.filter_config.as_ref().map(|filter_config_path| {
+        FilterConfig::load(filter_config_path)
+            .expect("Message Producer: Error while loading filter configuration")
+    });
+
+    // Validation of blocks sequence continues from the last published block of the previous run, if streaming resumes from it
+    let mut continuity_tracker = ContinuityTracker::new(
+        context.sync_mode,
+        PublishCheckpoint::load(&home_dir)
+            .unwrap_or_else(|error| {
+                error!(target: "borealis_indexer", "Message Producer: Publish checkpoint loading error: {:?}", error);
+                None
+            }),
+    );
+
+    // Local archive of published full block messages
+    let mut archive_writer = if context.archive {
+        Some(
+            ArchiveWriter::open(&home_dir, &envelope, &context)
+                .expect("Message Producer: Error while opening local archive"),
+        )
+    } else {
+        None
+    };
+
+    // Serialization and compression workers pool: blocks are encoded in parallel, re-sequenced by block height and batched
+    let block_encoder = Arc::new(BlockEncoder::new(envelope.clone(), filter_config, &context));
+    let encoded_blocks_stream = pipeline::encoded_blocks(
+        events_stream,
+        block_encoder.clone(),
+        context.encoding_workers,
+        context.batch_size,
+    );
+    futures::pin_mut!(encoded_blocks_stream);
+
+    while let Some(encoded_blocks) = encoded_blocks_stream.next().await {
+        info!(
+            target: "borealis_indexer",
+            "Message producer loop executed: batch of {} blocks received\n", encoded_blocks.len()
+        );
+
+        let mut encoded_blocks: Vec<EncodedBlock> = encoded_blocks
+            .into_iter()
+            .map(|encoded_block| encoded_block.expect("Message Producer: Message encoding error"))
+            .collect();
+
+        // Validation of heights monotonicity and hash chain continuity, with alerts about anomalies and optional backfilling of missing blocks
+        let mut messages: Vec<OutgoingMessage> = Vec::new();
+        // Heights and full block messages for the local archive, including backfilled blocks
+        let mut archive_records: Vec<(u64, Vec<u8>)> = Vec::new();
+        let mut halted_at: Option<usize> = None;
+        for (index, encoded_block) in encoded_blocks.iter_mut().enumerate() {
+            let header = &encoded_block.streamer_message.block.header;
+            if let Some(anomaly) = continuity_tracker.validate(header) {
+                error!(target: "borealis_indexer", "Message Producer: Anomaly in blocks sequence detected: {:?}", anomaly);
+                messages.push(OutgoingMessage {
+                    subject: format!("{}.anomalies", context.subject),
+                    message_id: format!("{}:{}:anomaly", anomaly.block_height, anomaly.block_hash),
+                    block_height: anomaly.block_height,
+                    message_bytes: envelope
+                        .encode_message(anomaly.block_height, &anomaly)
+                        .expect("Message Producer: Message encoding error"),
+                });
+                match context.on_anomaly {
+                    AnomalyAction::Alert => {}
+                    AnomalyAction::Halt => {
+                        halted_at = Some(index);
+                        break;
+                    }
+                    AnomalyAction::Backfill if anomaly.kind == AnomalyKind::HashChainBreak => {
+                        match continuity::backfill(&view_client, &anomaly).await {
+                            Ok(missing_messages) => {
+                                for missing_message in missing_messages {
+                                    continuity_tracker.observe(&missing_message.block.header);
+                                    let mut missing_block = block_encoder
+                                        .encode_block(missing_message)
+                                        .expect("Message Producer: Message encoding error");
+                                    if archive_writer.is_some() {
+                                        archive_records.push((
+                                            missing_block.streamer_message.block.header.height,
+                                            missing_block.messages[0].message_bytes.clone(),
+                                        ));
+                                    }
+                                    messages.append(&mut missing_block.messages);
+                                }
+                            }
+                            Err(error) => {
+                                error!(target: "borealis_indexer", "Message Producer: Backfilling of missing blocks error: {:?}", error);
+                            }
+                        }
+                    }
+                    AnomalyAction::Backfill => {}
+                }
+            }
+            continuity_tracker.observe(header);
+            if archive_writer.is_some() {
+                archive_records.push((header.height, encoded_block.messages[0].message_bytes.clone()));
+            }
+            messages.append(&mut encoded_block.messages);
+        }
+        if let Some(index) = halted_at {
+            encoded_blocks.truncate(index);
+        }
+
+        // Stream batch of messages to NATS, in order of blocks
+        let messages = Arc::new(messages);
+        let publish_latency_timer = metrics::PUBLISH_LATENCY.start_timer();
+        message_publisher(
+            &actual_connection_rx,
+            &connection_event_tx,
+            &context,
+            &message_headers,
+            messages.clone(),
+        )
+        .await;
+        publish_latency_timer.observe_duration();
+        metrics::PUBLISHED_MESSAGES.inc_by(messages.len() as u64);
+
+        // Archive published messages, before the checkpoint, so archived blocks aren't behind the published ones after restart.
+        // Writing and syncing of archive files are blocking operations.
+        if let Some(mut writer) = archive_writer.take() {
+            let (returned_writer, result) = tokio::task::spawn_blocking(move || {
+                let result = archive_records
+                    .iter()
+                    .try_for_each(|(block_height, message_bytes)| writer.append(*block_height, message_bytes))
+                    .and_then(|_| writer.sync());
+                (writer, result)
+            })
+            .await
+            .expect("Message Producer: Archive writing task panicked");
+            archive_writer = Some(returned_writer);
+            result.unwrap_or_else(|error|
+                error!(target: "borealis_indexer", "Message Producer: Archive writing error: {:?}", error)
+            );
+        }
+
+        // Persist height and hash of the last delivered block, to resume streaming from it after restart
+        if let Some(encoded_block) = encoded_blocks.last() {
+            metrics::observe_published_block(
+                encoded_block.streamer_message.block.header.height,
+                encoded_block.streamer_message.block.header.timestamp_nanosec,
+            );
+            PublishCheckpoint::new(
+                encoded_block.streamer_message.block.header.height,
+                encoded_block.streamer_message.block.header.hash.to_string(),
+            )
+            .store(&home_dir)
+            .unwrap_or_else(|error|
+                error!(target: "borealis_indexer", "Message Producer: Publish checkpoint storing error: {:?}", error)
+            );
+        }
+
+        for encoded_block in encoded_blocks.iter() {
+            message_dump(&encoded_block.streamer_message, verbosity_level);
+        }
+
+        if halted_at.is_some() {
+            error!(target: "borealis_indexer", "Message Producer: Messages streaming halted due to anomaly in blocks sequence\n");
+            SIGNAL.store(HALT_SIGNAL, Ordering::SeqCst);
+            actix::System::current().stop();
+            break;
+        }
+    }
+}
+
+/// Output of published `StreamerMessage` to log and stdout, accordingly to verbosity level
+fn message_dump(
+    streamer_message: &near_indexer::StreamerMessage,
+    verbosity_level: Option<VerbosityLevel>,
+) {
+    /*
+        Example of `StreamerMessage` with all data fields (filled with synthetic data, as an example):
+
+        Note that `outcomes` for a given transaction won't be included into the same block.
+        Execution outcomes are included into the blocks after the transaction or receipt
+        are recorded on a chain; in most cases, it is the next block after the one that has
+        the transaction or receipt.
+
+        StreamerMessage {
+            block: BlockView {
+                author: "test.near",
+                header: BlockHeaderView {
+                    height: 63596,
+                    epoch_id: `Bk7pvZWUTfHRRZtfgTDjnQ6y5cV8yG2h3orCqJvUbiym`,
+                    next_epoch_id: `3JuBZ4Gz5Eauf7PzQegfqSEDyvws3eKJYPbfGHAYmeR5`,
+                    hash: `5X37niQWWcihDGQjsvDMHYKLCurNJyQLxCeLgneDb8mk`,
+                    prev_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
+                    prev_state_root: `GkdxSBf4Kfq8V16N4Kqn3YdcThG1f5KG1KLBmXpMzP1k`,
+                    chunk_receipts_root: `9ETNjrt6MkwTgSVMMbpukfxRshSD1avBUUa4R4NuqwHv`,
+                    chunk_headers_root: `C7dVr9KdXYKt31yF2BkeAu115fpo79zYTqeU3FzqbFak`,
+                    chunk_tx_root: `7tkzFg8RHBmMw1ncRJZCCZAizgq4rwCftTKYLce8RU8t`,
+                    outcome_root: `7tkzFg8RHBmMw1ncRJZCCZAizgq4rwCftTKYLce8RU8t`,
+                    chunks_included: 1,
+                    challenges_root: `11111111111111111111111111111111`,
+                    timestamp: 1618558205803345000,
+                    timestamp_nanosec: 1618558205803345000,
+                    random_value: `3cAa93XmoLaKAJQgWz3K7SiKwnA3uaxi8MGgLM78HTNS`,
+                    validator_proposals: [],
+                    chunk_mask: [
+                        true,
                     ],
+                    gas_price: 1000000000,
+                    rent_paid: 0,
+                    validator_reward: 0,
+                    total_supply: 2050206401403887985811862247311434,
+                    challenges_result: [],
+                    last_final_block: `DCkMmXYHqibzcMjgFjRXJP7eckAMLrA4ijggSApMNwKu`,
+                    last_ds_final_block: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
+                    next_bp_hash: `4DJWnxRbUhRrsXK6EBkx4nFeXHKgJWqteDnJ7Hv4MZ6M`,
+                    block_merkle_root: `Bvn5K89fJ3uPNsj3324Ls9TXAGUVteHPpfKwKqL1La6W`,
+                    approvals: [
+                        Some(
+                            ed25519:F816hgJod7nPfD2qQz5yhaKDMn1JXmvzj2iXegsJpsmPNnYYZpKYJXgyuVTVJ4TKQbcJ2Q3USCGZF6fX2TcwBBv,
+                        ),
+                    ],
+                    signature: ed25519:239NbE4BuJaxneQA3AEsPrsGY7v3wBgaezbgg56HER69zPrBoc3a4fbyVWPXeoKE3LvgGma1g6pSHk9QHkmETCZY,
+                    latest_protocol_version: 43,
                 },
-                shards: [
-                    IndexerShard {
+                chunks: [
+                    ChunkHeaderView {
+                        chunk_hash: `2M2oeNFBbUUnHfkU1UuBr8EKBCLMH9xr2vfsGRpyiBmA`,
+                        prev_block_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
+                        outcome_root: `11111111111111111111111111111111`,
+                        prev_state_root: `3gZPPijaumgMRCvMuuZZM1Ab2LoHTSfYigMKwLqZ67m6`,
+                        encoded_merkle_root: `79Bt7ivt9Qhp3c6dJYnueaTyPVweYxZRpQHASRRAiyuy`,
+                        encoded_length: 8,
+                        height_created: 63596,
+                        height_included: 63596,
                         shard_id: 0,
-                        chunk: Some(
-                            IndexerChunkView {
-                                author: "test.near",
-                                header: ChunkHeaderView {
-                                    chunk_hash: `2M2oeNFBbUUnHfkU1UuBr8EKBCLMH9xr2vfsGRpyiBmA`,
-                                    prev_block_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
-                                    outcome_root: `11111111111111111111111111111111`,
-                                    prev_state_root: `3gZPPijaumgMRCvMuuZZM1Ab2LoHTSfYigMKwLqZ67m6`,
-                                    encoded_merkle_root: `79Bt7ivt9Qhp3c6dJYnueaTyPVweYxZRpQHASRRAiyuy`,
-                                    encoded_length: 8,
-                                    height_created: 63596,
-                                    height_included: 0,
-                                    shard_id: 0,
-                                    gas_used: 0,
-                                    gas_limit: 1000000000000000,
-                                    rent_paid: 0,
-                                    validator_reward: 0,
-                                    balance_burnt: 0,
-                                    outgoing_receipts_root: `H4Rd6SGeEBTbxkitsCdzfu9xL9HtZ2eHoPCQXUeZ6bW4`,
-                                    tx_root: `11111111111111111111111111111111`,
-                                    validator_proposals: [],
-                                    signature: ed25519:2vWNayBzEoW5DRc7gTdhxdLbkKuK6ACQ78p3JGpKSAZZCarnLroeoALPAFwpr9ZNPxBqdVYh9QLBe7WHZebsS17Z,
-                                },
-                                transactions: [
-                                    IndexerTransactionWithOutcome {
-                                        transaction: SignedTransactionView {
-                                            signer_id: "test.near",
-                                            public_key: ed25519:8NA7mh6TAWzy2qz68bHp62QHTEQ6nJLfiYeKDRwEbU3X,
-                                            nonce: 1,
-                                            receiver_id: "some.test.near",
-                                            actions: [
-                                                CreateAccount,
-                                                Transfer {
-                                                    deposit: 40000000000000000000000000,
-                                                },
-                                                AddKey {
-                                                    public_key: ed25519:2syGhqwJ8ba2nUGmP9tkZn9m1DYZPYYobpufiERVnug8,
-                                                    access_key: AccessKeyView {
-                                                        nonce: 0,
-                                                        permission: FullAccess,
-                                                    },
-                                                },
-                                            ],
-                                            signature: ed25519:Qniuu7exnr6xbe6gKafV5vDhuwM1jt9Bn7sCTF6cHfPpYWVJ4Q6kq8RAxKSeLoxbCreVp1XzMMJmXt8YcUqmMYw,
-                                            hash: `8dNv9S8rAFwso9fLwfDQXmw5yv5zscDjQpta96pMF6Bi`,
-                                        },
-                                        outcome: IndexerExecutionOutcomeWithReceipt {
-                                            execution_outcome: ExecutionOutcomeWithIdView {
-                                                proof: [],
-                                                block_hash: `G9v6Fsv94xaa7BRY2N5PFF5PJwT7ec6DPzQK73Yf3CZ6`,
-                                                id: `8dNv9S8rAFwso9fLwfDQXmw5yv5zscDjQpta96pMF6Bi`,
-                                                outcome: ExecutionOutcomeView {
-                                                    logs: [],
-                                                    receipt_ids: [
-                                                        `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
-                                                    ],
-                                                    gas_burnt: 424555062500,
-                                                    tokens_burnt: 424555062500000000000,
-                                                    executor_id: "test.near",
-                                                    status: SuccessReceiptId(CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz),
+                        gas_used: 0,
+                        gas_limit: 1000000000000000,
+                        rent_paid: 0,
+                        validator_reward: 0,
+                        balance_burnt: 0,
+                        outgoing_receipts_root: `H4Rd6SGeEBTbxkitsCdzfu9xL9HtZ2eHoPCQXUeZ6bW4`,
+                        tx_root: `11111111111111111111111111111111`,
+                        validator_proposals: [],
+                        signature: ed25519:2vWNayBzEoW5DRc7gTdhxdLbkKuK6ACQ78p3JGpKSAZZCarnLroeoALPAFwpr9ZNPxBqdVYh9QLBe7WHZebsS17Z,
+                    },
+                ],
+            },
+            shards: [
+                IndexerShard {
+                    shard_id: 0,
+                    chunk: Some(
+                        IndexerChunkView {
+                            author: "test.near",
+                            header: ChunkHeaderView {
+                                chunk_hash: `2M2oeNFBbUUnHfkU1UuBr8EKBCLMH9xr2vfsGRpyiBmA`,
+                                prev_block_hash: `2vJNJca72pBiq2eETq2xvuoc6caKDaUkdRgtdefyutbA`,
+                                outcome_root: `11111111111111111111111111111111`,
+                                prev_state_root: `3gZPPijaumgMRCvMuuZZM1Ab2LoHTSfYigMKwLqZ67m6`,
+                                encoded_merkle_root: `79Bt7ivt9Qhp3c6dJYnueaTyPVweYxZRpQHASRRAiyuy`,
+                                encoded_length: 8,
+                                height_created: 63596,
+                                height_included: 0,
+                                shard_id: 0,
+                                gas_used: 0,
+                                gas_limit: 1000000000000000,
+                                rent_paid: 0,
+                                validator_reward: 0,
+                                balance_burnt: 0,
+                                outgoing_receipts_root: `H4Rd6SGeEBTbxkitsCdzfu9xL9HtZ2eHoPCQXUeZ6bW4`,
+                                tx_root: `11111111111111111111111111111111`,
+                                validator_proposals: [],
+                                signature: ed25519:2vWNayBzEoW5DRc7gTdhxdLbkKuK6ACQ78p3JGpKSAZZCarnLroeoALPAFwpr9ZNPxBqdVYh9QLBe7WHZebsS17Z,
+                            },
+                            transactions: [
+                                IndexerTransactionWithOutcome {
+                                    transaction: SignedTransactionView {
+                                        signer_id: "test.near",
+                                        public_key: ed25519:8NA7mh6TAWzy2qz68bHp62QHTEQ6nJLfiYeKDRwEbU3X,
+                                        nonce: 1,
+                                        receiver_id: "some.test.near",
+                                        actions: [
+                                            CreateAccount,
+                                            Transfer {
+                                                deposit: 40000000000000000000000000,
+                                            },
+                                            AddKey {
+                                                public_key: ed25519:2syGhqwJ8ba2nUGmP9tkZn9m1DYZPYYobpufiERVnug8,
+                                                access_key: AccessKeyView {
+                                                    nonce: 0,
+                                                    permission: FullAccess,
                                                 },
                                             },
-                                            receipt: None,
-                                        },
+                                        ],
+                                        signature: ed25519:Qniuu7exnr6xbe6gKafV5vDhuwM1jt9Bn7sCTF6cHfPpYWVJ4Q6kq8RAxKSeLoxbCreVp1XzMMJmXt8YcUqmMYw,
+                                        hash: `8dNv9S8rAFwso9fLwfDQXmw5yv5zscDjQpta96pMF6Bi`,
                                     },
-                                ],
-                                receipts: [
-                                    ReceiptView {
-                                        predecessor_id: "test.near",
-                                        receiver_id: "some.test.near",
-                                        receipt_id: `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
-                                        receipt: Action {
-                                            signer_id: "test.near",
-                                            signer_public_key: ed25519:8NA7mh6TAWzy2qz68bHp62QHTEQ6nJLfiYeKDRwEbU3X,
-                                            gas_price: 1030000000,
-                                            output_data_receivers: [],
-                                            input_data_ids: [],
-                                            actions: [
-                                                CreateAccount,
-                                                Transfer {
-                                                    deposit: 40000000000000000000000000,
-                                                },
-                                                AddKey {
-                                                    public_key: ed25519:2syGhqwJ8ba2nUGmP9tkZn9m1DYZPYYobpufiERVnug8,
-                                                    access_key: AccessKeyView {
-                                                        nonce: 0,
-                                                        permission: FullAccess,
-                                                    },
-                                                },
-                                            ],
+                                    outcome: IndexerExecutionOutcomeWithReceipt {
+                                        execution_outcome: ExecutionOutcomeWithIdView {
+                                            proof: [],
+                                            block_hash: `G9v6Fsv94xaa7BRY2N5PFF5PJwT7ec6DPzQK73Yf3CZ6`,
+                                            id: `8dNv9S8rAFwso9fLwfDQXmw5yv5zscDjQpta96pMF6Bi`,
+                                            outcome: ExecutionOutcomeView {
+                                                logs: [],
+                                                receipt_ids: [
+                                                    `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
+                                                ],
+                                                gas_burnt: 424555062500,
+                                                tokens_burnt: 424555062500000000000,
+                                                executor_id: "test.near",
+                                                status: SuccessReceiptId(CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz),
+                                            },
                                         },
-                                    },
-                                ],
-                            },
-                        ),
-                        receipt_execution_outcomes: [
-                            IndexerExecutionOutcomeWithReceipt {
-                                execution_outcome: ExecutionOutcomeWithIdView {
-                                    proof: [],
-                                    block_hash: `BXPB6DQGmBrjARvcgYwS8qKLkyto6dk9NfawGSmfjE9Q`,
-                                    id: `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
-                                    outcome: ExecutionOutcomeView {
-                                        logs: [],
-                                        receipt_ids: [
-                                            `8vJ1QWM4pffRDnW3c5CxFFV5cMx8wiqxsAqmZTitHvfh`,
-                                        ],
-                                        gas_burnt: 424555062500,
-                                        tokens_burnt: 424555062500000000000,
-                                        executor_id: "some.test.near",
-                                        status: SuccessValue(``),
+                                        receipt: None,
                                     },
                                 },
-                                receipt: ReceiptView {
+                            ],
+                            receipts: [
+                                ReceiptView {
                                     predecessor_id: "test.near",
                                     receiver_id: "some.test.near",
                                     receipt_id: `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
@@ -326,249 +554,191 @@ async fn message_producer(
                                         ],
                                     },
                                 },
+                            ],
+                        },
+                    ),
+                    receipt_execution_outcomes: [
+                        IndexerExecutionOutcomeWithReceipt {
+                            execution_outcome: ExecutionOutcomeWithIdView {
+                                proof: [],
+                                block_hash: `BXPB6DQGmBrjARvcgYwS8qKLkyto6dk9NfawGSmfjE9Q`,
+                                id: `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
+                                outcome: ExecutionOutcomeView {
+                                    logs: [],
+                                    receipt_ids: [
+                                        `8vJ1QWM4pffRDnW3c5CxFFV5cMx8wiqxsAqmZTitHvfh`,
+                                    ],
+                                    gas_burnt: 424555062500,
+                                    tokens_burnt: 424555062500000000000,
+                                    executor_id: "some.test.near",
+                                    status: SuccessValue(``),
+                                },
                             },
-                        ],
-                        state_changes: [
-                            StateChangeWithCauseView {
-                                cause: ValidatorAccountsUpdate,
-                                value: AccountUpdate {
-                                    account_id: "test.near",
-                                    account: AccountView {
-                                        amount: 1000000000000000000000000000000000,
-                                        locked: 50000000000000000000000000000000,
-                                        code_hash: `11111111111111111111111111111111`,
-                                        storage_usage: 182,
-                                        storage_paid_at: 0,
-                                    },
+                            receipt: ReceiptView {
+                                predecessor_id: "test.near",
+                                receiver_id: "some.test.near",
+                                receipt_id: `CbWu7WYYbYbn3kThs5gcxANrxy7AKLcMcBLxLw8Zq1Fz`,
+                                receipt: Action {
+                                    signer_id: "test.near",
+                                    signer_public_key: ed25519:8NA7mh6TAWzy2qz68bHp62QHTEQ6nJLfiYeKDRwEbU3X,
+                                    gas_price: 1030000000,
+                                    output_data_receivers: [],
+                                    input_data_ids: [],
+                                    actions: [
+                                        CreateAccount,
+                                        Transfer {
+                                            deposit: 40000000000000000000000000,
+                                        },
+                                        AddKey {
+                                            public_key: ed25519:2syGhqwJ8ba2nUGmP9tkZn9m1DYZPYYobpufiERVnug8,
+                                            access_key: AccessKeyView {
+                                                nonce: 0,
+                                                permission: FullAccess,
+                                            },
+                                        },
+                                    ],
                                 },
                             },
-                        ],
-                    },
-                ],
-            }
-        */
-
-        info!(
-            target: "borealis_indexer",
-            "Message producer loop executed: message received\n"
-        );
-
-        // Stream message to NATS
-        match context.msg_format {
-            MsgFormat::Cbor => loop {
-                let nats_connection = actual_connection_rx.borrow().clone();
-                debug!(target: "borealis_indexer", "Message Producer [CBOR bytes vector]: Current Connection: NATS Connection: {:?}", &nats_connection);
-
-                let result = nats_connection.connection.as_ref().unwrap()
-                    .publish(
-                        context.subject.as_str(),
-                        BorealisMessage::new(streamer_message.block.header.height,
-                            if let Some(compression_mode) = context.payload_compression {
-                                let payload_bytes = serde_json::to_vec(&streamer_message).unwrap();
-                                let (payload_bytes_compressed, _payload_len) = match compression_mode {
-                                    CompressionMode::Lz4f => BorealisMessage::<Vec<u8>>::payload_compress_lz4(&payload_bytes).unwrap(),
-                                    CompressionMode::Zstd => BorealisMessage::<Vec<u8>>::payload_compress_zstd(&payload_bytes).unwrap(),
-                                };
-                                payload_bytes_compressed
-                            } else {
-                                serde_json::to_vec(&streamer_message).unwrap()
-                            }
-                        )
-                        .to_cbor()
-                        .unwrap(),
-                    );
-
-                match &result {
-                    Ok(()) => {
-                        debug!(target: "borealis_indexer", "Message Producer [CBOR bytes vector]: Actual Connection: NATS Connection: {:?}", &nats_connection);
-                        drop(result);
-                        drop(nats_connection);
-                        break;
-                    }
-                    Err(error) => {
-                        error!(target: "borealis_indexer", "Message Producer [CBOR bytes vector]: Message passing error, NATS connection error or wrong credentials: {:?}", error);
-                        connection_event_tx
-                            .send(ConnectionEvent::NewConnectionRequest(nats_connection.cid))
-                            .await
-                            .unwrap_or_else(|error|
-                                error!(target: "borealis_indexer", "Message Producer [CBOR bytes vector]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
-                            );
-                        drop(error);
-                        drop(result);
-                        drop(nats_connection);
-                        tokio::time::sleep(core::time::Duration::from_millis(500)).await;
-                        continue;
-                    }
-                }
-            },
-            MsgFormat::Json => loop {
-                let nats_connection = actual_connection_rx.borrow().clone();
-                debug!(target: "borealis_indexer", "Message Producer [JSON bytes vector]: Current Connection: NATS Connection: {:?}", &nats_connection);
-
-                let result = nats_connection.connection.as_ref().unwrap()
-                    .publish(
-                        context.subject.as_str(),
-                        BorealisMessage::new(
-                            streamer_message.block.header.height,
-                            if let Some(compression_mode) = context.payload_compression {
-                                let payload_bytes = serde_json::to_vec(&streamer_message).unwrap();
-                                let (payload_bytes_compressed, _payload_len) = match compression_mode {
-                                    CompressionMode::Lz4f => BorealisMessage::<Vec<u8>>::payload_compress_lz4(&payload_bytes).unwrap(),
-                                    CompressionMode::Zstd => BorealisMessage::<Vec<u8>>::payload_compress_zstd(&payload_bytes).unwrap(),
-                                };
-                                payload_bytes_compressed
-                            } else { 
-                                serde_json::to_vec(&streamer_message).unwrap()
-                            }
-                        )
-                        .to_json_bytes()
-                        .unwrap(),
-                    );
-
-                match &result {
-                    Ok(()) => {
-                        debug!(target: "borealis_indexer", "Message Producer [JSON bytes vector]: Actual Connection: NATS Connection: {:?}", &nats_connection);
-                        drop(result);
-                        drop(nats_connection);
-                        break;
-                    }
-                    Err(error) => {
-                        error!(target: "borealis_indexer", "Message Producer [JSON bytes vector]: Message passing error, NATS connection error or wrong credentials: {:?}", error);
-                        connection_event_tx
-                            .send(ConnectionEvent::NewConnectionRequest(nats_connection.cid))
-                            .await
-                            .unwrap_or_else(|error|
-                                error!(target: "borealis_indexer", "Message Producer [JSON bytes vector]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
-                            );
-                        drop(error);
-                        drop(result);
-                        drop(nats_connection);
-                        tokio::time::sleep(core::time::Duration::from_millis(500)).await;
-                        continue;
-                    }
-                }
-            },
+                        },
+                    ],
+                    state_changes: [
+                        StateChangeWithCauseView {
+                            cause: ValidatorAccountsUpdate,
+                            value: AccountUpdate {
+                                account_id: "test.near",
+                                account: AccountView {
+                                    amount: 1000000000000000000000000000000000,
+                                    locked: 50000000000000000000000000000000,
+                                    code_hash: `11111111111111111111111111111111`,
+                                    storage_usage: 182,
+                                    storage_paid_at: 0,
+                                },
+                            },
+                        },
+                    ],
+                },
+            ],
         }
+    */
 
-        // Data handling from `StreamerMessage` data structure. For custom filtering purposes.
-        // Same as: jq '{block_height: .block.header.height, block_hash: .block.header.hash, block_header_chunk: .block.chunks[0], shard_chunk_header: .shards[0].chunk.header, transactions: .shards[0].chunk.transactions, receipts: .shards[0].chunk.receipts, receipt_execution_outcomes: .shards[0].receipt_execution_outcomes, state_changes: .state_changes}'
+    // Data handling from `StreamerMessage` data structure. For custom filtering purposes.
+    // Same as: jq '{block_height: .block.header.height, block_hash: .block.header.hash, block_header_chunk: .block.chunks[0], shard_chunk_header: .shards[0].chunk.header, transactions: .shards[0].chunk.transactions, receipts: .shards[0].chunk.receipts, receipt_execution_outcomes: .shards[0].receipt_execution_outcomes, state_changes: .state_changes}'
 
-        info!(
-            target: "borealis_indexer",
-            "block_height: #{}, block_hash: {}\n",
-            &streamer_message.block.header.height,
-            &streamer_message.block.header.hash
+    info!(
+        target: "borealis_indexer",
+        "block_height: #{}, block_hash: {}\n",
+        &streamer_message.block.header.height,
+        &streamer_message.block.header.hash
+    );
+
+    if let Some(VerbosityLevel::WithStreamerMessageDump)
+    | Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level
+    {
+        println!(
+            "streamer_message: {}\n",
+            serde_json::to_string_pretty(&streamer_message).unwrap()
+        );
+        println!(
+            "streamer_message: {}\n",
+            serde_json::to_string(&streamer_message).unwrap()
         );
+    };
 
-        if let Some(VerbosityLevel::WithStreamerMessageDump)
-        | Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level
-        {
-            println!(
-                "streamer_message: {}\n",
-                serde_json::to_string_pretty(&streamer_message).unwrap()
-            );
-            println!(
-                "streamer_message: {}\n",
-                serde_json::to_string(&streamer_message).unwrap()
-            );
-        };
+    if let Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level {
+        println!(
+            "streamer_message: {}\n",
+            serde_json::to_value(&streamer_message).unwrap()
+        );
+        println!(
+            "streamer_message: {:?}\n",
+            cbor::to_vec(&streamer_message).unwrap()
+        );
 
-        if let Some(VerbosityLevel::WithStreamerMessageParse) = verbosity_level {
-            println!(
-                "streamer_message: {}\n",
-                serde_json::to_value(&streamer_message).unwrap()
-            );
-            println!(
-                "streamer_message: {:?}\n",
-                cbor::to_vec(&streamer_message).unwrap()
-            );
+        println!(
+            "block_header: {}\n",
+            serde_json::to_value(&streamer_message.block.header).unwrap()
+        );
+        println!(
+            "block_header: {:?}\n",
+            cbor::to_vec(&streamer_message.block.header).unwrap()
+        );
 
+        println!(
+            "block_header_chunks#: {}\n",
+            streamer_message.block.chunks.len()
+        );
+        streamer_message.block.chunks.iter().for_each(|chunk| {
             println!(
-                "block_header: {}\n",
-                serde_json::to_value(&streamer_message.block.header).unwrap()
-            );
-            println!(
-                "block_header: {:?}\n",
-                cbor::to_vec(&streamer_message.block.header).unwrap()
+                "block_header_chunk: {}\n",
+                serde_json::to_value(&chunk).unwrap()
             );
+            println!("block_header_chunk: {:?}\n", cbor::to_vec(&chunk).unwrap());
+        });
 
-            println!(
-                "block_header_chunks#: {}\n",
-                streamer_message.block.chunks.len()
-            );
-            streamer_message.block.chunks.iter().for_each(|chunk| {
+        println!("shards#: {}\n", streamer_message.shards.len());
+        streamer_message.shards.iter().for_each(|shard| {
+            if let Some(chunk) = &shard.chunk {
                 println!(
-                    "block_header_chunk: {}\n",
-                    serde_json::to_value(&chunk).unwrap()
+                    "shard_chunk_header: {}\n",
+                    serde_json::to_value(&chunk.header).unwrap()
+                );
+                println!(
+                    "shard_chunk_header: {:?}\n",
+                    cbor::to_vec(&chunk.header).unwrap()
                 );
-                println!("block_header_chunk: {:?}\n", cbor::to_vec(&chunk).unwrap());
-            });
-
-            println!("shards#: {}\n", streamer_message.shards.len());
-            streamer_message.shards.iter().for_each(|shard| {
-                if let Some(chunk) = &shard.chunk {
-                    println!(
-                        "shard_chunk_header: {}\n",
-                        serde_json::to_value(&chunk.header).unwrap()
-                    );
-                    println!(
-                        "shard_chunk_header: {:?}\n",
-                        cbor::to_vec(&chunk.header).unwrap()
-                    );
-
-                    println!("shard_chunk_transactions#: {}\n", chunk.transactions.len());
-                    println!(
-                        "shard_chunk_transactions: {}\n",
-                        serde_json::to_value(&chunk.transactions).unwrap()
-                    );
-                    println!(
-                        "shard_chunk_transactions: {:?}\n",
-                        cbor::to_vec(&chunk.transactions).unwrap()
-                    );
-
-                    println!("shard_chunk_receipts#: {}\n", chunk.receipts.len());
-                    println!(
-                        "shard_chunk_receipts: {}\n",
-                        serde_json::to_value(&chunk.receipts).unwrap()
-                    );
-                    println!(
-                        "shard_chunk_receipts: {:?}\n",
-                        cbor::to_vec(&chunk.receipts).unwrap()
-                    );
-                } else {
-                    println!("shard_chunk_header: None\n");
-
-                    println!("shard_chunk_transactions#: None\n");
-                    println!("shard_chunk_transactions: None\n");
-
-                    println!("shard_chunk_receipts#: None\n");
-                    println!("shard_chunk_receipts: None\n");
-                };
 
+                println!("shard_chunk_transactions#: {}\n", chunk.transactions.len());
+                println!(
+                    "shard_chunk_transactions: {}\n",
+                    serde_json::to_value(&chunk.transactions).unwrap()
+                );
                 println!(
-                    "shard_receipt_execution_outcomes#: {}\n",
-                    shard.receipt_execution_outcomes.len()
+                    "shard_chunk_transactions: {:?}\n",
+                    cbor::to_vec(&chunk.transactions).unwrap()
                 );
+
+                println!("shard_chunk_receipts#: {}\n", chunk.receipts.len());
                 println!(
-                    "shard_receipt_execution_outcomes: {}\n",
-                    serde_json::to_value(&shard.receipt_execution_outcomes).unwrap()
+                    "shard_chunk_receipts: {}\n",
+                    serde_json::to_value(&chunk.receipts).unwrap()
                 );
                 println!(
-                    "shard_receipt_execution_outcomes: {:?}\n",
-                    cbor::to_vec(&shard.receipt_execution_outcomes).unwrap()
+                    "shard_chunk_receipts: {:?}\n",
+                    cbor::to_vec(&chunk.receipts).unwrap()
                 );
+            } else {
+                println!("shard_chunk_header: None\n");
 
-                println!("StateChanges#: {}\n", shard.state_changes.len());
-                shard.state_changes.iter().for_each(|state_change| {
-                    println!(
-                        "StateChange: {}\n",
-                        serde_json::to_value(&state_change).unwrap()
-                    );
-                    println!("StateChange: {:?}\n", cbor::to_vec(&state_change).unwrap());
-                });
+                println!("shard_chunk_transactions#: None\n");
+                println!("shard_chunk_transactions: None\n");
+
+                println!("shard_chunk_receipts#: None\n");
+                println!("shard_chunk_receipts: None\n");
+            };
+
+            println!(
+                "shard_receipt_execution_outcomes#: {}\n",
+                shard.receipt_execution_outcomes.len()
+            );
+            println!(
+                "shard_receipt_execution_outcomes: {}\n",
+                serde_json::to_value(&shard.receipt_execution_outcomes).unwrap()
+            );
+            println!(
+                "shard_receipt_execution_outcomes: {:?}\n",
+                cbor::to_vec(&shard.receipt_execution_outcomes).unwrap()
+            );
+
+            println!("StateChanges#: {}\n", shard.state_changes.len());
+            shard.state_changes.iter().for_each(|state_change| {
+                println!(
+                    "StateChange: {}\n",
+                    serde_json::to_value(&state_change).unwrap()
+                );
+                println!("StateChange: {:?}\n", cbor::to_vec(&state_change).unwrap());
             });
-        };
-    }
+        });
+    };
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -586,6 +756,16 @@ impl ConnectionEvent
 where
     Self: Send + Sync,
 {
+    /// Name of the event, for metrics labels
+    fn name(&self) -> &'static str {
+        match self {
+            ConnectionEvent::NewConnectionRequest(_) => "NewConnectionRequest",
+            ConnectionEvent::ConnectionReestablished(_) => "ConnectionReestablished",
+            ConnectionEvent::ConnectionLost(_) => "ConnectionLost",
+            ConnectionEvent::ConnectionClosed(_) => "ConnectionClosed",
+        }
+    }
+
     async fn events_processing(
         connection_event_tx: mpsc::Sender<ConnectionEvent>,
         mut connection_event_rx: mpsc::Receiver<ConnectionEvent>,
@@ -594,6 +774,7 @@ where
         connect_args: RunArgs,
     ) {
         while let Some(event) = connection_event_rx.recv().await {
+            metrics::CONNECTION_EVENTS.with_label_values(&[event.name()]).inc();
             match event {
                 ConnectionEvent::NewConnectionRequest(cid) => {
                     info!(target: "borealis_indexer", "New connection has been requested, creation of new connection...");
@@ -770,6 +951,16 @@ where
 
 static CID: AtomicUsize = AtomicUsize::new(0);
 
+/// JetStream publish acknowledgement (`PubAck`), or error reply of the server
+#[derive(Deserialize, Debug)]
+struct PublishAck {
+    stream: Option<String>,
+    seq: Option<u64>,
+    #[serde(default)]
+    duplicate: bool,
+    error: Option<serde_json::Value>,
+}
+
 #[derive(Debug, Clone)]
 struct NATSConnection
 where
@@ -1050,6 +1241,155 @@ where
         }
     }
 
+    /// Publish message to Borealis NATS Bus (core NATS publish, without acknowledgement)
+    fn publish(
+        &self,
+        subject: &str,
+        message_headers: &nats::Headers,
+        message_bytes: &[u8],
+    ) -> Result<(), Error> {
+        let nats_connection = self.connection.as_ref().unwrap();
+        match nats_connection.publish_with_reply_or_headers(
+            subject,
+            None,
+            Some(message_headers),
+            message_bytes,
+        ) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                error!(target: "borealis_indexer", "Publish: NATS message publishing error: {:?}", error);
+                Err(format!("Publish: NATS message publishing error: {:?}", error).into())
+            }
+        }
+    }
+
+    /// Publish batch of messages to Borealis NATS Bus (core NATS publish, without acknowledgement) with one flush for the whole batch.
+    /// Returns the number of published messages.
+    fn publish_batch(
+        &self,
+        message_headers: &nats::Headers,
+        messages: &[OutgoingMessage],
+    ) -> Result<usize, Error> {
+        for message in messages.iter() {
+            self.publish(message.subject.as_str(), &message.headers(message_headers), &message.message_bytes)?;
+        }
+        match self.connection.as_ref().unwrap().flush() {
+            Ok(()) => Ok(messages.len()),
+            Err(error) => {
+                error!(target: "borealis_indexer", "Publish: NATS connection flush error: {:?}", error);
+                Err(format!("Publish: NATS connection flush error: {:?}", error).into())
+            }
+        }
+    }
+
+    /// Publish batch of messages to Borealis NATS Bus through JetStream, without waiting for acknowledgement (`PubAck`) of each message
+    /// before publishing of the next one, and then await for acknowledgements of all messages in the batch.
+    /// Message ID is passed as `Nats-Msg-Id` header, thus retransmits are dropped by the server within stream's `duplicate_window`.
+    /// ID of the previous message is passed as `Nats-Expected-Last-Msg-Id` header, thus the message is rejected by the server,
+    /// if the previous one hasn't been stored, and messages are never stored out of order.
+    /// Only a reply parsed as `PubAck` with stream and sequence acknowledges the message: error replies and empty replies
+    /// (e.g. `503` status, when there is no stream for the subject) are failures.
+    /// Returns the number of consecutively acknowledged messages from the beginning of the batch.
+    fn jetstream_publish_batch(
+        &self,
+        message_headers: &nats::Headers,
+        messages: &[OutgoingMessage],
+        expected_last_message_id: Option<&str>,
+        ack_timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        let nats_connection = self.connection.as_ref().unwrap();
+        let ack_inbox = nats_connection.new_inbox();
+        let ack_subscription = nats_connection
+            .subscribe(format!("{}.*", ack_inbox).as_str())
+            .map_err(|error| format!("JetStream Publish: Acknowledgements subscription error: {:?}", error))?;
+
+        let mut previous_message_id = expected_last_message_id;
+        for (index, message) in messages.iter().enumerate() {
+            let mut headers = message.headers(message_headers);
+            headers
+                .inner
+                .insert(String::from("Nats-Msg-Id"), std::iter::once(message.message_id.clone()).collect());
+            if let Some(previous_message_id) = previous_message_id {
+                headers.inner.insert(
+                    String::from("Nats-Expected-Last-Msg-Id"),
+                    std::iter::once(previous_message_id.to_string()).collect(),
+                );
+            }
+            previous_message_id = Some(message.message_id.as_str());
+            nats_connection
+                .publish_with_reply_or_headers(
+                    message.subject.as_str(),
+                    Some(format!("{}.{}", ack_inbox, index).as_str()),
+                    Some(&headers),
+                    &message.message_bytes,
+                )
+                .map_err(|error| {
+                    error!(target: "borealis_indexer", "JetStream Publish: NATS message publishing error: {:?}; message ID: {}", error, message.message_id);
+                    format!("JetStream Publish: NATS message publishing error: {:?}; message ID: {}", error, message.message_id)
+                })?;
+        }
+
+        let mut acknowledged = vec![false; messages.len()];
+        let deadline = std::time::Instant::now() + ack_timeout;
+        while acknowledged.iter().any(|acked| !acked) {
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            let ack = match ack_subscription.next_timeout(timeout) {
+                Ok(ack) => ack,
+                Err(error) => {
+                    error!(target: "borealis_indexer", "JetStream Publish: acknowledgement waiting timeout elapsed: {:?}; {} of {} messages acknowledged", error, acknowledged.iter().filter(|acked| **acked).count(), messages.len());
+                    break;
+                }
+            };
+            let index = match ack.subject.rsplit('.').next().and_then(|index| index.parse::<usize>().ok()) {
+                Some(index) if index < messages.len() => index,
+                _ => continue,
+            };
+            if ack.data.is_empty() {
+                let status = ack
+                    .headers
+                    .as_ref()
+                    .and_then(|headers| headers.get("Status"))
+                    .and_then(|status| status.iter().next().cloned())
+                    .unwrap_or_default();
+                error!(target: "borealis_indexer", "JetStream Publish: message with ID {} hasn't been stored, empty reply with status {:?} (no stream for subject {}?)", messages[index].message_id, status, messages[index].subject);
+                continue;
+            }
+            match serde_json::from_slice::<PublishAck>(&ack.data) {
+                Ok(PublishAck { stream: Some(stream), seq: Some(seq), duplicate, error: None }) => {
+                    if duplicate {
+                        info!(target: "borealis_indexer", "JetStream Publish: message with ID {} is a duplicate and has been dropped by the server, stream: {}, sequence: {}", messages[index].message_id, stream, seq);
+                    } else {
+                        debug!(target: "borealis_indexer", "JetStream Publish: message with ID {} has been acknowledged, stream: {}, sequence: {}", messages[index].message_id, stream, seq);
+                    }
+                    acknowledged[index] = true;
+                }
+                Ok(publish_ack) => {
+                    error!(target: "borealis_indexer", "JetStream Publish: message with ID {} hasn't been stored by the server: {:?}", messages[index].message_id, publish_ack.error);
+                }
+                Err(error) => {
+                    error!(target: "borealis_indexer", "JetStream Publish: message with ID {} acknowledgement parsing error: {:?}", messages[index].message_id, error);
+                }
+            }
+        }
+
+        ack_subscription.unsubscribe().unwrap_or_else(|error|
+            error!(target: "borealis_indexer", "JetStream Publish: Acknowledgements unsubscription error: {:?}", error)
+        );
+
+        Ok(acknowledged.iter().take_while(|acked| **acked).count())
+    }
+
+    /// Probe connection to Borealis NATS Bus for readiness, the same way as `nats_check_connection()`, but without connection details output
+    fn nats_probe_connection(&self) -> Result<(), Error> {
+        let nats_connection = self.connection.as_ref().unwrap();
+        nats_connection
+            .flush_timeout(core::time::Duration::from_millis(5000))
+            .map_err(|error| {
+                debug!(target: "borealis_indexer", "Probe Connection: NATS connection error: {:?}", error);
+                format!("Probe Connection: NATS connection error: {:?}", error).into()
+            })
+    }
+
     /// Check connection to Borealis NATS Bus
     fn nats_check_connection(&self) -> Result<(), Error> {
         let nats_connection = self.connection.as_ref().unwrap();
@@ -1151,7 +1491,59 @@ fn messages_processing_rt(verbosity_level: Option<VerbosityLevel>) -> Result<Run
     Ok(messages_processing_rt)
 }
 
+/// Create NEAR Indexer configuration accordingly to syncing mode and node's synchronization awaiting options
+fn indexer_config(
+    home_dir: std::path::PathBuf,
+    sync_mode: SyncMode,
+    block_height: Option<u64>,
+    await_synced: AwaitSynced,
+) -> near_indexer::IndexerConfig {
+    // recover and continue message streaming from latest synced block (real-time), or from interruption, or from last published block, or from exact block height
+    let sync_mode = match sync_mode {
+        SyncMode::LatestSynced => near_indexer::SyncModeEnum::LatestSynced,
+        SyncMode::FromInterruption => near_indexer::SyncModeEnum::FromInterruption,
+        SyncMode::FromLastPublished => {
+            match PublishCheckpoint::load(&home_dir)
+                .expect("Main(): Error while loading Indexer's publish checkpoint")
+            {
+                Some(checkpoint) => {
+                    info!(target: "borealis_indexer", "Main(): Resuming from the block next to the last published block #{} ({})", checkpoint.block_height, checkpoint.block_hash);
+                    near_indexer::SyncModeEnum::BlockHeight(checkpoint.block_height + 1)
+                }
+                None => {
+                    info!(target: "borealis_indexer", "Main(): Publish checkpoint is absent, resuming from interruption");
+                    near_indexer::SyncModeEnum::FromInterruption
+                }
+            }
+        }
+        SyncMode::BlockHeight => {
+            near_indexer::SyncModeEnum::BlockHeight(block_height.unwrap_or(0))
+        }
+    };
+
+    near_indexer::IndexerConfig {
+        home_dir,
+        sync_mode,
+        // waiting for full sync or stream messages while syncing
+        await_for_node_synced: match await_synced {
+            AwaitSynced::WaitForFullSync => {
+                near_indexer::AwaitForNodeSyncedEnum::WaitForFullSync
+            }
+            AwaitSynced::StreamWhileSyncing => {
+                near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing
+            }
+        },
+    }
+}
+
 fn main() -> Result<(), Error> {
+    // Metrics and health HTTP server outlives restarts of the system, so it's started only once
+    if let SubCommand::Run(run_args) | SubCommand::Serve(run_args) = Opts::parse().subcmd {
+        if let Some(metrics_address) = run_args.metrics_address {
+            metrics::metrics_server(metrics_address);
+        }
+    }
+
     // restart of system in case of stop or error returned, due to run-time panic in a thread
     loop {
         // Search for the root certificates to perform HTTPS/TLS calls
@@ -1177,7 +1569,12 @@ fn main() -> Result<(), Error> {
         let connection_event_sender = connection_event_tx.clone();
         let actual_connection_receiver = actual_connection_tx.subscribe();
 
-        if let SubCommand::Check(run_args) | SubCommand::Run(run_args) = opts.subcmd.clone() {
+        let connect_args = match opts.subcmd.clone() {
+            SubCommand::Check(run_args) | SubCommand::Run(run_args) | SubCommand::Serve(run_args) => Some(run_args),
+            SubCommand::Replay(replay_args) => Some(replay_args.run_args),
+            SubCommand::Init(_) | SubCommand::TrainDictionary(_) => None,
+        };
+        if let Some(run_args) = connect_args {
             loop {
                 let result = NATSConnection::connect(run_args.to_owned(), connection_event_tx.clone());
                 match &result {
@@ -1254,27 +1651,101 @@ fn main() -> Result<(), Error> {
                     .expect("Main(): Init(): Error while creating Indexer's initial configuration files");
                 break;
             }
-            SubCommand::Run(run_args) => {
-                let indexer_config = near_indexer::IndexerConfig {
+            SubCommand::TrainDictionary(train_args) => {
+                let indexer_config = indexer_config(
                     home_dir,
-                    // recover and continue message streaming from latest synced block (real-time), or from interruption, or from exact block height
-                    sync_mode: match run_args.sync_mode {
-                        SyncMode::LatestSynced => near_indexer::SyncModeEnum::LatestSynced,
-                        SyncMode::FromInterruption => near_indexer::SyncModeEnum::FromInterruption,
-                        SyncMode::BlockHeight => {
-                            near_indexer::SyncModeEnum::BlockHeight(run_args.block_height.unwrap_or(0))
-                        }
-                    },
-                    // waiting for full sync or stream messages while syncing
-                    await_for_node_synced: match run_args.await_synced {
-                        AwaitSynced::WaitForFullSync => {
-                            near_indexer::AwaitForNodeSyncedEnum::WaitForFullSync
-                        }
-                        AwaitSynced::StreamWhileSyncing => {
-                            near_indexer::AwaitForNodeSyncedEnum::StreamWhileSyncing
-                        }
-                    },
-                };
+                    train_args.sync_mode,
+                    train_args.block_height,
+                    train_args.await_synced,
+                );
+
+                let messages_processing_rt = actix::System::with_tokio_rt(||
+                    messages_processing_rt(opts.verbose.clone())
+                    .expect("Main(): TrainDictionary(): Run-time error returned while creating Indexer's custom Tokio run-time for Actix")
+                );
+
+                messages_processing_rt.block_on(async move {
+                    // Unix signals and key sequence handlers
+                    actix::spawn(async move {
+                        key_switch().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        term_switch().await.unwrap();
+                    });
+
+                    let indexer = near_indexer::Indexer::new(indexer_config)
+                        .expect("Main(): TrainDictionary(): Error while creating Indexer's instance");
+
+                    let events_stream = indexer.streamer();
+
+                    actix::spawn(async move {
+                        dictionary::train_dictionary(events_stream, train_args).await;
+                    });
+
+                });
+                messages_processing_rt.run()
+                    .unwrap_or_else(|error|
+                        error!(target: "borealis_indexer", "Main(): TrainDictionary(): Indexer's dictionary training loop returned run-time error: {:?}", error)
+                    );
+                break;
+            }
+            SubCommand::Replay(replay_args) => {
+                let connect_args = replay_args.run_args.clone();
+
+                let messages_processing_rt = actix::System::with_tokio_rt(||
+                    messages_processing_rt(opts.verbose.clone())
+                    .expect("Main(): Replay(): Run-time error returned while creating Indexer's custom Tokio run-time for Actix")
+                );
+
+                messages_processing_rt.block_on(async move {
+                    // Unix signals and key sequence handlers
+                    actix::spawn(async move {
+                        key_switch().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        term_switch().await.unwrap();
+                    });
+
+                    actix::spawn(async move {
+                        ConnectionEvent::events_processing(
+                            connection_event_tx,
+                            connection_event_rx,
+                            actual_connection_tx,
+                            actual_connection_rx,
+                            connect_args,
+                        )
+                        .await;
+                    });
+
+                    ConnectionEvent::events_processing_check(
+                        actual_connection_receiver.clone(),
+                        connection_event_sender.clone(),
+                    );
+
+                    actix::spawn(async move {
+                        replay::replay(
+                            actual_connection_receiver,
+                            connection_event_sender,
+                            replay_args,
+                            home_dir,
+                        )
+                        .await;
+                    });
+
+                });
+                messages_processing_rt.run()
+                    .unwrap_or_else(|error|
+                        error!(target: "borealis_indexer", "Main(): Replay(): Indexer's replay loop returned run-time error: {:?}", error)
+                    );
+                break;
+            }
+            SubCommand::Run(run_args) => {
+                let indexer_config = indexer_config(
+                    home_dir.clone(),
+                    run_args.sync_mode,
+                    run_args.block_height,
+                    run_args.await_synced,
+                );
 
                 let connect_args = run_args.clone();
 
@@ -1320,15 +1791,42 @@ fn main() -> Result<(), Error> {
                     let indexer = near_indexer::Indexer::new(indexer_config)
                         .expect("Main(): Run(): Error while creating Indexer's instance");
 
+                    if run_args.metrics_address.is_some() {
+                        let (_view_client, client) = indexer.client_actors();
+                        let probe_connection_receiver = actual_connection_receiver.clone();
+                        let await_synced = run_args.await_synced;
+                        actix::spawn(async move {
+                            health::readiness_probe(client, probe_connection_receiver, await_synced)
+                                .await;
+                        });
+                    }
+
+                    if run_args.query_service {
+                        let (view_client, _client) = indexer.client_actors();
+                        let query_connection_receiver = actual_connection_receiver.clone();
+                        let query_args = run_args.clone();
+                        actix::spawn(async move {
+                            query::query_service(
+                                view_client,
+                                query_connection_receiver,
+                                query_args,
+                            )
+                            .await;
+                        });
+                    }
+
+                    let (view_client, _client) = indexer.client_actors();
                     let events_stream = indexer.streamer();
 
                     actix::spawn(async move {
                         message_producer(
                             events_stream,
+                            view_client,
                             actual_connection_receiver.clone(),
                             connection_event_sender.clone(),
                             run_args.clone(),
                             opts.verbose,
+                            home_dir,
                         )
                         .await;
                     });
@@ -1339,8 +1837,88 @@ fn main() -> Result<(), Error> {
                         error!(target: "borealis_indexer", "Main(): Run(): Indexer's messages processing loop returned run-time error: {:?}", error)
                     );
             }
+            SubCommand::Serve(run_args) => {
+                // blocks aren't streamed by query service, so syncing mode only affects the node itself
+                let indexer_config = indexer_config(
+                    home_dir,
+                    SyncMode::LatestSynced,
+                    None,
+                    run_args.await_synced,
+                );
+
+                let connect_args = run_args.clone();
+
+                let messages_processing_rt = actix::System::with_tokio_rt(||
+                    messages_processing_rt(opts.verbose.clone())
+                    .expect("Main(): Serve(): Run-time error returned while creating Indexer's custom Tokio run-time for Actix")
+                );
+
+                messages_processing_rt.block_on(async move {
+                    // Unix signals and key sequence handlers
+                    actix::spawn(async move {
+                        key_switch().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        hup_switch().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        term_switch().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        kill_switch_usr1().await.unwrap();
+                    });
+                    actix::spawn(async move {
+                        kill_switch_usr2().await.unwrap();
+                    });
+
+                    actix::spawn(async move {
+                        ConnectionEvent::events_processing(
+                            connection_event_tx,
+                            connection_event_rx,
+                            actual_connection_tx,
+                            actual_connection_rx,
+                            connect_args,
+                        )
+                        .await;
+                    });
+
+                    ConnectionEvent::events_processing_check(
+                        actual_connection_receiver.clone(),
+                        connection_event_sender.clone(),
+                    );
+
+                    let indexer = near_indexer::Indexer::new(indexer_config)
+                        .expect("Main(): Serve(): Error while creating Indexer's instance");
+
+                    if run_args.metrics_address.is_some() {
+                        let (_view_client, client) = indexer.client_actors();
+                        let probe_connection_receiver = actual_connection_receiver.clone();
+                        let await_synced = run_args.await_synced;
+                        actix::spawn(async move {
+                            health::readiness_probe(client, probe_connection_receiver, await_synced)
+                                .await;
+                        });
+                    }
+
+                    let (view_client, _client) = indexer.client_actors();
+
+                    actix::spawn(async move {
+                        query::query_service(
+                            view_client,
+                            actual_connection_receiver.clone(),
+                            run_args.clone(),
+                        )
+                        .await;
+                    });
+
+                });
+                messages_processing_rt.run()
+                    .unwrap_or_else(|error|
+                        error!(target: "borealis_indexer", "Main(): Serve(): Indexer's query service loop returned run-time error: {:?}", error)
+                    );
+            }
         };
-        if let 602437500 | 15 | 12 | 10 = SIGNAL.load(Ordering::SeqCst) {
+        if let 602437500 | 15 | 12 | 10 | HALT_SIGNAL = SIGNAL.load(Ordering::SeqCst) {
             break;
         }
     } // restart of system in case of stop or error returned, due to run-time panic in a thread
@@ -1349,5 +1927,8 @@ fn main() -> Result<(), Error> {
 //  messages_processing_rt.shutdown_timeout(core::time::Duration::from_secs(10));
 //  events_processing_rt.shutdown_timeout(core::time::Duration::from_secs(10));
     std::thread::sleep(core::time::Duration::from_millis(10000));
+    if SIGNAL.load(Ordering::SeqCst) == HALT_SIGNAL {
+        return Err("Messages streaming halted due to anomaly in blocks sequence (`--on-anomaly Halt`)".into());
+    }
     Ok(())
 }
@@ -0,0 +1,150 @@
+use crate::health;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, Gauge, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use tracing::{error, info};
+
+use std::net::SocketAddr;
+
+pub(crate) static LAST_PUBLISHED_BLOCK_HEIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_indexer_last_published_block_height",
+        "Height of the last published block"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static PUBLISHED_MESSAGES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "borealis_indexer_published_messages_total",
+        "Number of published messages (full, per-account, per-shard and per-entity messages)"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static PUBLISH_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "borealis_indexer_publish_latency_seconds",
+        "Time of publishing of a batch of messages, including acknowledgements awaiting and retries",
+        exponential_buckets(0.001, 2.0, 16).unwrap()
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static PAYLOAD_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "borealis_indexer_payload_size_bytes",
+        "Size of messages payload before and after compression",
+        &["compression"],
+        exponential_buckets(1024.0, 2.0, 16).unwrap()
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static CONNECTION_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "borealis_indexer_connection_events_total",
+        "Number of NATS connection events",
+        &["event"]
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static ENCODING_BLOCKS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "borealis_indexer_encoding_blocks",
+        "Number of blocks taken from the streamer and not yet encoded (in encoding workers, or awaiting for encoding of preceding blocks)"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+pub(crate) static BLOCK_LAG: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "borealis_indexer_block_lag_seconds",
+        "Lag between timestamp of the last published block and its publishing time"
+    )
+    .expect("Metrics: Error while registering metric")
+});
+
+/// Update metrics of the last published block
+pub(crate) fn observe_published_block(block_height: u64, block_timestamp_nanosec: u64) {
+    LAST_PUBLISHED_BLOCK_HEIGHT.set(block_height as i64);
+    let now_nanosec = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    BLOCK_LAG.set(now_nanosec.saturating_sub(block_timestamp_nanosec) as f64 / 1_000_000_000.0);
+}
+
+async fn metrics_handler(request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(()) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(buffer)),
+                Err(error) => {
+                    error!(target: "borealis_indexer", "Metrics: Error while encoding metrics: {:?}", error);
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                }
+            }
+        }
+        (&Method::GET, "/healthz") => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("OK")),
+        (&Method::GET, "/readyz") if health::is_ready() => Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from("Ready")),
+        (&Method::GET, "/readyz") => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Not ready")),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("Metrics: Error while building HTTP response"))
+}
+
+/// Run HTTP server with Prometheus metrics endpoint `/metrics` and health (`/healthz`) and readiness (`/readyz`) endpoints
+/// in a separate thread, with its own runtime
+pub(crate) fn metrics_server(address: SocketAddr) {
+    std::thread::Builder::new()
+        .name(String::from("metrics-server"))
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Metrics: Error while creating metrics server runtime");
+            runtime.block_on(async move {
+                let make_service = make_service_fn(|_connection| async {
+                    Ok::<_, hyper::Error>(service_fn(metrics_handler))
+                });
+                match Server::try_bind(&address) {
+                    Ok(server) => {
+                        info!(target: "borealis_indexer", "Metrics: HTTP server listening on http://{} (`/metrics`, `/healthz`, `/readyz`)", address);
+                        server
+                            .serve(make_service)
+                            .await
+                            .unwrap_or_else(|error|
+                                error!(target: "borealis_indexer", "Metrics: HTTP server error: {:?}", error)
+                            );
+                    }
+                    Err(error) => {
+                        error!(target: "borealis_indexer", "Metrics: Error while binding HTTP server to {}: {:?}", address, error);
+                    }
+                }
+            });
+        })
+        .expect("Metrics: Error while spawning metrics server thread");
+}
@@ -0,0 +1,159 @@
+use crate::configs::{Error, RunArgs};
+use crate::envelope::{Envelope, HEADER_BLOCK_HEIGHT};
+use crate::fanout;
+use crate::filter::{self, FilterConfig};
+use crate::metrics;
+use futures::{Stream, StreamExt};
+use near_indexer::StreamerMessage;
+use tokio::sync::mpsc;
+
+use std::sync::Arc;
+
+/// Encoded message, ready to be published
+#[derive(Debug, Clone)]
+pub(crate) struct OutgoingMessage {
+    pub subject: String,
+    /// Unique message ID, for deduplication of retransmitted messages on JetStream side
+    pub message_id: String,
+    /// Height of the block, which the message belongs to
+    pub block_height: u64,
+    pub message_bytes: Vec<u8>,
+}
+
+impl OutgoingMessage {
+    /// Message headers: envelope description headers, shared by all messages, and the block height header
+    pub(crate) fn headers(&self, message_headers: &nats::Headers) -> nats::Headers {
+        let mut headers = message_headers.clone();
+        headers.inner.insert(
+            String::from(HEADER_BLOCK_HEIGHT),
+            std::iter::once(self.block_height.to_string()).collect(),
+        );
+        headers
+    }
+}
+
+/// Block with all its encoded messages (full message, per-account, per-shard and per-entity messages), in publishing order
+#[derive(Debug)]
+pub(crate) struct EncodedBlock {
+    pub streamer_message: StreamerMessage,
+    pub messages: Vec<OutgoingMessage>,
+}
+
+/// Serialization and compression of blocks into messages, shared between encoding workers
+pub(crate) struct BlockEncoder {
+    envelope: Envelope,
+    filter_config: Option<FilterConfig>,
+    subject: String,
+    shard_subjects: bool,
+    fan_out: bool,
+}
+
+impl BlockEncoder {
+    pub(crate) fn new(
+        envelope: Envelope,
+        filter_config: Option<FilterConfig>,
+        context: &RunArgs,
+    ) -> Self {
+        Self {
+            envelope,
+            filter_config,
+            subject: context.subject.clone(),
+            shard_subjects: context.shard_subjects,
+            fan_out: context.fan_out,
+        }
+    }
+
+    /// Encode all messages of the block, accordingly to publishing options
+    pub(crate) fn encode_block(&self, streamer_message: StreamerMessage) -> Result<EncodedBlock, Error> {
+        let block_height = streamer_message.block.header.height;
+        let message_id = format!("{}:{}", block_height, streamer_message.block.header.hash);
+
+        let mut messages = vec![OutgoingMessage {
+            subject: self.subject.clone(),
+            message_id: message_id.clone(),
+            block_height,
+            message_bytes: self.envelope.encode_message(block_height, &streamer_message)?,
+        }];
+
+        // Trimmed messages for per-account subjects
+        if let Some(filter_config) = &self.filter_config {
+            for account_id in filter_config.accounts() {
+                if let Some(trimmed_message) = filter_config.trim(account_id, &streamer_message) {
+                    messages.push(OutgoingMessage {
+                        subject: format!("{}.account.{}", self.subject, filter::account_token(account_id)),
+                        message_id: format!("{}:account:{}", message_id, account_id),
+                        block_height,
+                        message_bytes: self.envelope.encode_message(block_height, &trimmed_message)?,
+                    });
+                }
+            }
+        }
+
+        // Per-shard messages (block with only one shard) for per-shard subjects
+        if self.shard_subjects {
+            for shard in streamer_message.shards.iter() {
+                let shard_message = StreamerMessage {
+                    block: streamer_message.block.clone(),
+                    shards: vec![shard.clone()],
+                };
+                messages.push(OutgoingMessage {
+                    subject: format!("{}.shard.{}", self.subject, shard.shard_id),
+                    message_id: format!("{}:shard:{}", message_id, shard.shard_id),
+                    block_height,
+                    message_bytes: self.envelope.encode_message(block_height, &shard_message)?,
+                });
+            }
+        }
+
+        // Granular per-entity messages (block header, chunks, transactions, receipts, outcomes, state changes)
+        if self.fan_out {
+            for fan_out_message in fanout::fan_out(&self.envelope, &streamer_message)? {
+                messages.push(OutgoingMessage {
+                    subject: format!("{}.{}", self.subject, fan_out_message.subject_suffix),
+                    message_id: format!("{}:{}", message_id, fan_out_message.subject_suffix),
+                    block_height,
+                    message_bytes: fan_out_message.message_bytes,
+                });
+            }
+        }
+
+        Ok(EncodedBlock {
+            streamer_message,
+            messages,
+        })
+    }
+}
+
+/// Pipeline stage between the streamer and the publisher: blocks are encoded by a pool of `encoding_workers`
+/// (on the blocking threads pool), re-sequenced by block height, and grouped into batches of up to `batch_size` blocks,
+/// which are already encoded and ready for publishing.
+/// The number of blocks in processing is bounded by the number of workers, so back-pressure propagates to the streamer.
+pub(crate) fn encoded_blocks(
+    events_stream: mpsc::Receiver<StreamerMessage>,
+    block_encoder: Arc<BlockEncoder>,
+    encoding_workers: usize,
+    batch_size: usize,
+) -> impl Stream<Item = Vec<Result<EncodedBlock, Error>>> {
+    // Blocks being encoded by the previous run of the system (before restart) aren't in processing anymore
+    metrics::ENCODING_BLOCKS.set(0);
+    futures::stream::unfold(events_stream, |mut events_stream| async move {
+        events_stream.recv().await.map(|streamer_message| {
+            metrics::ENCODING_BLOCKS.inc();
+            (streamer_message, events_stream)
+        })
+    })
+    .map(move |streamer_message| {
+        let block_encoder = block_encoder.clone();
+        tokio::task::spawn_blocking(move || block_encoder.encode_block(streamer_message))
+    })
+    // `buffered()` yields results in the order of blocks, regardless of the order of workers completion
+    .buffered(encoding_workers.max(1))
+    .map(|result| {
+        metrics::ENCODING_BLOCKS.dec();
+        match result {
+            Ok(encoded_block) => encoded_block,
+            Err(error) => Err(format!("Pipeline: Encoding worker error: {:?}", error).into()),
+        }
+    })
+    .ready_chunks(batch_size.max(1))
+}
@@ -0,0 +1,365 @@
+use crate::configs::{MsgFormat, RunArgs};
+use crate::envelope::Envelope;
+use crate::NATSConnection;
+use actix::Addr;
+use borealis_types::types::BorealisMessage;
+use near_client::{
+    GetBlock, GetBlockError, GetChunk, GetChunkError, GetReceipt, GetReceiptError, TxStatus, TxStatusError,
+    ViewClientActor,
+};
+use near_indexer::near_primitives::hash::CryptoHash;
+use near_indexer::near_primitives::sharding::ChunkHash;
+use near_indexer::near_primitives::types::{AccountId, BlockId, BlockReference};
+use near_indexer::near_primitives::views::{
+    BlockView, ChunkView, FinalExecutionOutcomeViewEnum, ReceiptView,
+};
+use near_indexer::StreamerMessage;
+use serde::Serialize;
+use tokio::sync::{mpsc, watch, Semaphore};
+use tracing::{debug, error, info};
+
+use core::str::FromStr;
+use std::sync::Arc;
+
+/// Reply of the query service, encoded as a payload of Borealis Message
+#[derive(Serialize, Debug)]
+pub(crate) enum QueryReply {
+    Block(BlockView),
+    Chunk(ChunkView),
+    Transaction(FinalExecutionOutcomeViewEnum),
+    Receipt(ReceiptView),
+    Message(StreamerMessage),
+    Error(QueryError),
+}
+
+/// Structured error reply of the query service
+#[derive(Serialize, Debug)]
+pub(crate) struct QueryError {
+    pub kind: QueryErrorKind,
+    pub message: String,
+}
+
+/// Kind of error, returned by the query service
+#[derive(Serialize, Debug, Clone, Copy)]
+pub(crate) enum QueryErrorKind {
+    /// Request subject or request body can't be parsed
+    BadRequest,
+    /// Requested block, chunk, transaction or receipt is unknown for the node
+    UnknownItem,
+    /// Node isn't able to answer the request at the moment (not synced yet, IO error, actor mailbox error)
+    Internal,
+}
+
+impl QueryError {
+    fn new(kind: QueryErrorKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+/// Request/reply query service for blocks, chunks, transactions and receipts.
+///
+/// Listens for requests on subjects:
+/// `<query_subject>.Block.<height|hash>` - block by height or hash;
+/// `<query_subject>.Chunk.<chunk_hash>` or `<query_subject>.Chunk.<height>.<shard_id>` - chunk by hash or by block height and shard ID;
+/// `<query_subject>.Tx.<hash>` - transaction status by hash, request body should contain signer account ID;
+/// `<query_subject>.Receipt.<id>` - receipt by ID;
+/// `<query_subject>.Message.<height|hash>` - the whole `StreamerMessage` of the block by height or hash (e.g. for backfilling by consumers);
+/// and replies with Borealis Message, encoded in `--msg-format`, with the view read from the node's view client.
+/// Reply carries envelope description headers, so requesters decode it regardless of their own message format.
+/// Borealis Message ID is the block height for replies with blocks, chunks and messages, and the sequential number
+/// of the request for other replies (transactions, receipts and errors).
+/// Requests are processed concurrently (up to `--query-concurrency` at once), each one is replied from its own task.
+pub(crate) async fn query_service(
+    view_client: Addr<ViewClientActor>,
+    mut actual_connection_rx: watch::Receiver<NATSConnection>,
+    context: RunArgs,
+) {
+    let requests_subject = format!("{}.>", context.query_subject);
+    // Replies are natively encoded Borealis Messages without payload compression
+    let reply_headers = Envelope::new(context.msg_format, None).to_headers();
+    let mut requests: u64 = 0;
+    let concurrency = Arc::new(Semaphore::new(context.query_concurrency.max(1)));
+    let context = Arc::new(context);
+
+    loop {
+        let nats_connection = actual_connection_rx.borrow().clone();
+        debug!(target: "borealis_indexer", "Query Service: Current Connection: NATS Connection: {:?}", &nats_connection);
+
+        let subscription = match nats_connection
+            .connection
+            .as_ref()
+            .unwrap()
+            .subscribe(requests_subject.as_str())
+        {
+            Ok(subscription) => subscription,
+            Err(error) => {
+                error!(target: "borealis_indexer", "Query Service: Subscription error for subject {}: {:?}", requests_subject, error);
+                drop(nats_connection);
+                tokio::time::sleep(core::time::Duration::from_millis(500)).await;
+                continue;
+            }
+        };
+
+        info!(target: "borealis_indexer", "Query Service: listening for requests on subject {}", requests_subject);
+
+        // Requests are received on NATS client's handler thread and passed into the service loop
+        let (request_tx, mut request_rx) = mpsc::channel::<nats::Message>(1000);
+        let handler = subscription.with_handler(move |msg| {
+            request_tx.blocking_send(msg).map_err(|error| {
+                std::io::Error::new(std::io::ErrorKind::Other, format!("Query Service: Request send error: {:?}", error))
+            })
+        });
+
+        loop {
+            tokio::select! {
+                request = request_rx.recv() => {
+                    match request {
+                        Some(msg) => {
+                            requests += 1;
+                            // Waiting for a free slot, when the maximum number of requests is already in processing
+                            let permit = match concurrency.clone().acquire_owned().await {
+                                Ok(permit) => permit,
+                                Err(_) => return,
+                            };
+                            let view_client = view_client.clone();
+                            let context = context.clone();
+                            let reply_connection = nats_connection.connection.as_ref().unwrap().clone();
+                            let reply_headers = reply_headers.clone();
+                            let request_number = requests;
+                            actix::spawn(async move {
+                                query_request_reply(&view_client, &context, &reply_connection, &reply_headers, &msg, request_number).await;
+                                drop(permit);
+                            });
+                        }
+                        None => break,
+                    }
+                }
+                changed = actual_connection_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    info!(target: "borealis_indexer", "Query Service: NATS connection has been changed, resubscribing...");
+                    break;
+                }
+            }
+        }
+
+        handler.unsubscribe().unwrap_or_else(|error|
+            error!(target: "borealis_indexer", "Query Service: Unsubscribe error for subject {}: {:?}", requests_subject, error)
+        );
+        drop(nats_connection);
+    }
+}
+
+/// Process the request and publish the reply to its reply subject
+async fn query_request_reply(
+    view_client: &Addr<ViewClientActor>,
+    context: &RunArgs,
+    reply_connection: &nats::Connection,
+    reply_headers: &nats::Headers,
+    msg: &nats::Message,
+    request_number: u64,
+) {
+    let (block_height, reply) = query_request_processing(view_client, context, msg).await;
+    let message_id = block_height.unwrap_or(request_number);
+    let reply_bytes = match context.msg_format {
+        MsgFormat::Cbor => BorealisMessage::new(message_id, reply).to_cbor(),
+        MsgFormat::Json => BorealisMessage::new(message_id, reply).to_json_bytes(),
+    };
+    match (reply_bytes, msg.reply.as_deref()) {
+        (Ok(reply_bytes), Some(reply_subject)) => reply_connection
+            .publish_with_reply_or_headers(reply_subject, None, Some(reply_headers), reply_bytes)
+            .unwrap_or_else(|error|
+                error!(target: "borealis_indexer", "Query Service: Reply error for request on subject {}: {:?}", msg.subject, error)
+            ),
+        (Ok(_), None) => {
+            error!(target: "borealis_indexer", "Query Service: Request on subject {} has no reply subject", msg.subject)
+        }
+        (Err(error), _) => {
+            error!(target: "borealis_indexer", "Query Service: Reply encoding error for request on subject {}: {:?}", msg.subject, error)
+        }
+    }
+}
+
+/// Parse request subject and read requested item from the node's view client.
+/// Returns the height of the block, which the item belongs to, if it's known without additional requests.
+async fn query_request_processing(
+    view_client: &Addr<ViewClientActor>,
+    context: &RunArgs,
+    msg: &nats::Message,
+) -> (Option<u64>, QueryReply) {
+    debug!(target: "borealis_indexer", "Query Service: Received request on subject {}", msg.subject);
+
+    let request = msg
+        .subject
+        .strip_prefix(context.query_subject.as_str())
+        .and_then(|request| request.strip_prefix('.'))
+        .unwrap_or_default();
+    let (item, id) = request.split_once('.').unwrap_or((request, ""));
+
+    let result = match item.to_lowercase().as_str() {
+        "block" => query_block(view_client, id).await,
+        "chunk" => query_chunk(view_client, id).await,
+        "tx" => query_transaction(view_client, id, msg.data.as_ref()).await,
+        "receipt" => query_receipt(view_client, id).await,
+        "message" => query_message(view_client, id).await,
+        _ => Err(QueryError::new(
+            QueryErrorKind::BadRequest,
+            format!("Unknown request item `{}`: should be `Block`, `Chunk`, `Tx`, `Receipt` or `Message`", item),
+        )),
+    };
+
+    match result {
+        Ok((block_height, reply)) => (block_height, reply),
+        Err(query_error) => {
+            info!(target: "borealis_indexer", "Query Service: Error reply for request on subject {}: {:?}", msg.subject, query_error);
+            (None, QueryReply::Error(query_error))
+        }
+    }
+}
+
+fn parse_hash(id: &str) -> Result<CryptoHash, QueryError> {
+    CryptoHash::from_str(id).map_err(|error| {
+        QueryError::new(
+            QueryErrorKind::BadRequest,
+            format!("Wrong hash `{}`: {:?}", id, error),
+        )
+    })
+}
+
+fn mailbox_error(error: actix::MailboxError) -> QueryError {
+    QueryError::new(
+        QueryErrorKind::Internal,
+        format!("View client mailbox error: {:?}", error),
+    )
+}
+
+async fn query_block(
+    view_client: &Addr<ViewClientActor>,
+    id: &str,
+) -> Result<(Option<u64>, QueryReply), QueryError> {
+    let block_id = match id.parse::<u64>() {
+        Ok(block_height) => BlockId::Height(block_height),
+        Err(_) => BlockId::Hash(parse_hash(id)?),
+    };
+
+    match view_client
+        .send(GetBlock(BlockReference::BlockId(block_id)))
+        .await
+        .map_err(mailbox_error)?
+    {
+        Ok(block) => Ok((Some(block.header.height), QueryReply::Block(block))),
+        Err(error @ GetBlockError::UnknownBlock { .. }) => Err(QueryError::new(
+            QueryErrorKind::UnknownItem,
+            format!("{}", error),
+        )),
+        Err(error) => Err(QueryError::new(QueryErrorKind::Internal, format!("{}", error))),
+    }
+}
+
+async fn query_message(
+    view_client: &Addr<ViewClientActor>,
+    id: &str,
+) -> Result<(Option<u64>, QueryReply), QueryError> {
+    let block = match query_block(view_client, id).await? {
+        (_, QueryReply::Block(block)) => block,
+        (_, reply) => {
+            return Err(QueryError::new(
+                QueryErrorKind::Internal,
+                format!("Unexpected block reply: {:?}", reply),
+            ))
+        }
+    };
+
+    match near_indexer::build_streamer_message(view_client, block).await {
+        Ok(streamer_message) => Ok((
+            Some(streamer_message.block.header.height),
+            QueryReply::Message(streamer_message),
+        )),
+        Err(error) => Err(QueryError::new(QueryErrorKind::Internal, format!("{:?}", error))),
+    }
+}
+
+async fn query_chunk(
+    view_client: &Addr<ViewClientActor>,
+    id: &str,
+) -> Result<(Option<u64>, QueryReply), QueryError> {
+    let get_chunk = match id.split_once('.') {
+        Some((block_height, shard_id)) => {
+            match (block_height.parse::<u64>(), shard_id.parse::<u64>()) {
+                (Ok(block_height), Ok(shard_id)) => GetChunk::Height(block_height, shard_id),
+                _ => {
+                    return Err(QueryError::new(
+                        QueryErrorKind::BadRequest,
+                        format!("Wrong chunk ID `{}`: should be `<chunk_hash>` or `<height>.<shard_id>`", id),
+                    ))
+                }
+            }
+        }
+        None => GetChunk::ChunkHash(ChunkHash(parse_hash(id)?)),
+    };
+
+    match view_client.send(get_chunk).await.map_err(mailbox_error)? {
+        Ok(chunk) => Ok((Some(chunk.header.height_included), QueryReply::Chunk(chunk))),
+        Err(error @ GetChunkError::UnknownChunk { .. })
+        | Err(error @ GetChunkError::UnknownBlock { .. }) => Err(QueryError::new(
+            QueryErrorKind::UnknownItem,
+            format!("{}", error),
+        )),
+        Err(error) => Err(QueryError::new(QueryErrorKind::Internal, format!("{}", error))),
+    }
+}
+
+async fn query_transaction(
+    view_client: &Addr<ViewClientActor>,
+    id: &str,
+    request_body: &[u8],
+) -> Result<(Option<u64>, QueryReply), QueryError> {
+    let tx_hash = parse_hash(id)?;
+    let signer_account_id = std::str::from_utf8(request_body)
+        .ok()
+        .and_then(|signer_account_id| AccountId::from_str(signer_account_id.trim()).ok())
+        .ok_or_else(|| {
+            QueryError::new(
+                QueryErrorKind::BadRequest,
+                "Transaction request body should contain signer account ID".to_string(),
+            )
+        })?;
+
+    match view_client
+        .send(TxStatus {
+            tx_hash,
+            signer_account_id,
+            fetch_receipt: true,
+        })
+        .await
+        .map_err(mailbox_error)?
+    {
+        Ok(Some(transaction)) => Ok((None, QueryReply::Transaction(transaction))),
+        Ok(None) | Err(TxStatusError::MissingTransaction(_)) => Err(QueryError::new(
+            QueryErrorKind::UnknownItem,
+            format!("Transaction {} is unknown", id),
+        )),
+        Err(error) => Err(QueryError::new(QueryErrorKind::Internal, format!("{:?}", error))),
+    }
+}
+
+async fn query_receipt(
+    view_client: &Addr<ViewClientActor>,
+    id: &str,
+) -> Result<(Option<u64>, QueryReply), QueryError> {
+    let receipt_id = parse_hash(id)?;
+
+    match view_client
+        .send(GetReceipt { receipt_id })
+        .await
+        .map_err(mailbox_error)?
+    {
+        Ok(Some(receipt)) => Ok((None, QueryReply::Receipt(receipt))),
+        Ok(None) | Err(GetReceiptError::UnknownReceipt(_)) => Err(QueryError::new(
+            QueryErrorKind::UnknownItem,
+            format!("Receipt {} is unknown", id),
+        )),
+        Err(error) => Err(QueryError::new(QueryErrorKind::Internal, format!("{:?}", error))),
+    }
+}
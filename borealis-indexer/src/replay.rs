@@ -0,0 +1,268 @@
+use crate::archive::{self, ArchiveReader};
+use crate::configs::{Error, MsgFormat, ReplayArgs, ReplaySource};
+use crate::envelope::Envelope;
+use borealis_envelope::{HeightSearch, ZstdDictionaries};
+use crate::filter::FilterConfig;
+use crate::pipeline::{BlockEncoder, EncodedBlock, OutgoingMessage};
+use crate::{message_publisher, ConnectionEvent, NATSConnection};
+use nats::jetstream::{AckPolicy, Consumer, ConsumerConfig, DeliverPolicy, ReplayPolicy};
+use near_indexer::StreamerMessage;
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info};
+
+use std::path::Path;
+use std::sync::Arc;
+
+/// Timeout for awaiting of the next message from the source stream, after which the stream is considered to be replayed to the end
+const SOURCE_STREAM_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(10000);
+
+/// Source of replayed blocks, read in order of heights
+enum BlocksSource {
+    Archive {
+        archive_reader: ArchiveReader,
+        next_height: u64,
+        last_height: u64,
+    },
+    Stream {
+        consumer: Consumer,
+        from_height: u64,
+        to_height: u64,
+        finished: bool,
+    },
+}
+
+impl BlocksSource {
+    fn open(
+        context: &ReplayArgs,
+        home_dir: &Path,
+        nats_connection: Option<nats::Connection>,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<Self, Error> {
+        let from_height = context.from_height.unwrap_or(0);
+        let to_height = context.to_height.unwrap_or(u64::MAX);
+        match context.source {
+            ReplaySource::Archive => {
+                let archive_dir = context
+                    .source_archive_dir
+                    .clone()
+                    .unwrap_or_else(|| archive::archive_dir(home_dir));
+                let archive_reader = ArchiveReader::open(&archive_dir)?;
+                let next_height = std::cmp::max(from_height, archive_reader.first_height().unwrap_or(u64::MAX));
+                let last_height = std::cmp::min(to_height, archive_reader.last_height()?.unwrap_or(0));
+                info!(target: "borealis_indexer", "Replay: Replaying blocks #{}..=#{} from the archive {:?}", next_height, last_height, archive_dir);
+                Ok(Self::Archive {
+                    archive_reader,
+                    next_height,
+                    last_height,
+                })
+            }
+            ReplaySource::Stream => {
+                let source_subject = context
+                    .source_subject
+                    .clone()
+                    .ok_or("Replay: `--source-subject` option is required for `Stream` source")?;
+                let source_stream = context
+                    .source_stream
+                    .clone()
+                    .unwrap_or_else(|| format!("JS_{}", source_subject));
+                let nats_connection =
+                    nats_connection.ok_or("Replay: NATS connection isn't established")?;
+                // Start from the first message at `--from-height`, found by binary search over stream sequences
+                let (deliver_policy, opt_start_seq) = match context.from_height {
+                    Some(from_height) => {
+                        let start_sequence = HeightSearch {
+                            nats_connection: &nats_connection,
+                            stream: &source_stream,
+                            subject: &source_subject,
+                            default_msg_format: MsgFormat::Cbor,
+                            zstd_dictionaries,
+                        }
+                        .sequence_of_height(from_height)?;
+                        info!(target: "borealis_indexer", "Replay: Height #{} is mapped to the stream {} sequence {}", from_height, source_stream, start_sequence);
+                        (DeliverPolicy::ByStartSeq, start_sequence as i64)
+                    }
+                    None => (DeliverPolicy::All, 0),
+                };
+                // Ephemeral consumer without acknowledgements, so replay doesn't affect durable consumers of the stream
+                let mut consumer = Consumer::create_or_open(
+                    nats_connection.clone(),
+                    source_stream.as_str(),
+                    ConsumerConfig {
+                        deliver_subject: Some(nats_connection.new_inbox()),
+                        deliver_policy,
+                        opt_start_seq,
+                        ack_policy: AckPolicy::None,
+                        filter_subject: source_subject.clone(),
+                        replay_policy: ReplayPolicy::Instant,
+                        ..Default::default()
+                    },
+                )?;
+                consumer.timeout = SOURCE_STREAM_TIMEOUT;
+                info!(target: "borealis_indexer", "Replay: Replaying blocks #{}..=#{} from the stream {} (subject {})", from_height, to_height, source_stream, source_subject);
+                Ok(Self::Stream {
+                    consumer,
+                    from_height,
+                    to_height,
+                    finished: false,
+                })
+            }
+        }
+    }
+
+    /// Next blocks (up to `batch_size`) in order of heights, empty when the source is exhausted
+    fn next_batch(
+        &mut self,
+        batch_size: usize,
+        zstd_dictionaries: &ZstdDictionaries,
+    ) -> Result<Vec<StreamerMessage>, Error> {
+        let mut streamer_messages = Vec::with_capacity(batch_size);
+        match self {
+            Self::Archive {
+                archive_reader,
+                next_height,
+                last_height,
+            } => {
+                while streamer_messages.len() < batch_size && *next_height <= *last_height {
+                    if let Some((headers, message_bytes)) = archive_reader.read(*next_height)? {
+                        streamer_messages.push(borealis_envelope::decode_message(
+                            Some(&headers),
+                            &message_bytes,
+                            MsgFormat::Cbor,
+                            zstd_dictionaries,
+                        )?);
+                    }
+                    *next_height += 1;
+                }
+            }
+            Self::Stream {
+                consumer,
+                from_height,
+                to_height,
+                finished,
+            } => {
+                while streamer_messages.len() < batch_size && !*finished {
+                    let msg = match consumer.process_timeout(|msg| Ok(msg.clone())) {
+                        Ok(msg) => msg,
+                        Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+                            info!(target: "borealis_indexer", "Replay: No messages within {:?}, the stream has been replayed to the end", SOURCE_STREAM_TIMEOUT);
+                            *finished = true;
+                            break;
+                        }
+                        Err(error) => return Err(error.into()),
+                    };
+                    let streamer_message: StreamerMessage = borealis_envelope::decode_message(
+                        msg.headers.as_ref(),
+                        &msg.data,
+                        MsgFormat::Cbor,
+                        zstd_dictionaries,
+                    )?;
+                    let block_height = streamer_message.block.header.height;
+                    if block_height > *to_height {
+                        *finished = true;
+                    } else if block_height >= *from_height {
+                        streamer_messages.push(streamer_message);
+                    }
+                }
+            }
+        }
+        Ok(streamer_messages)
+    }
+}
+
+/// Republish blocks from the local archive or from JetStream stream onto target subject,
+/// re-encoded accordingly to publishing options (format, compression, per-account, per-shard and fan-out subjects),
+/// with optional rate limiting
+pub(crate) async fn replay(
+    actual_connection_rx: watch::Receiver<NATSConnection>,
+    connection_event_tx: mpsc::Sender<ConnectionEvent>,
+    context: ReplayArgs,
+    home_dir: std::path::PathBuf,
+) {
+    let run_args = context.run_args.clone();
+    let envelope = Envelope::from_args(&run_args)
+        .expect("Replay: Error while loading Zstd dictionary for payload compression");
+    let message_headers = envelope.to_headers();
+    let filter_config = run_args.filter_config.as_ref().map(|filter_config_path| {
+        FilterConfig::load(filter_config_path).expect("Replay: Error while loading filter configuration")
+    });
+    let block_encoder = Arc::new(BlockEncoder::new(envelope, filter_config, &run_args));
+    let zstd_dictionaries = Arc::new(
+        ZstdDictionaries::load(&context.source_zstd_dictionary)
+            .expect("Replay: Error while loading Zstd dictionaries for payload decompression"),
+    );
+
+    let nats_connection = actual_connection_rx.borrow().connection.clone();
+    let mut blocks_source = match BlocksSource::open(&context, &home_dir, nats_connection, &zstd_dictionaries) {
+        Ok(blocks_source) => blocks_source,
+        Err(error) => {
+            error!(target: "borealis_indexer", "Replay: Error while opening source of blocks: {:?}", error);
+            actix::System::current().stop();
+            return;
+        }
+    };
+
+    let started = std::time::Instant::now();
+    let mut replayed_blocks: u64 = 0;
+    loop {
+        // Reading, decoding and re-encoding of blocks are blocking operations
+        let batch_size = run_args.batch_size.max(1);
+        let (returned_source, result) = {
+            let block_encoder = block_encoder.clone();
+            let zstd_dictionaries = zstd_dictionaries.clone();
+            tokio::task::spawn_blocking(move || {
+                let result = blocks_source
+                    .next_batch(batch_size, &zstd_dictionaries)
+                    .and_then(|streamer_messages| {
+                        streamer_messages
+                            .into_iter()
+                            .map(|streamer_message| block_encoder.encode_block(streamer_message))
+                            .collect::<Result<Vec<EncodedBlock>, Error>>()
+                    });
+                (blocks_source, result)
+            })
+            .await
+            .expect("Replay: Blocks reading task panicked")
+        };
+        blocks_source = returned_source;
+
+        let encoded_blocks = match result {
+            Ok(encoded_blocks) if encoded_blocks.is_empty() => break,
+            Ok(encoded_blocks) => encoded_blocks,
+            Err(error) => {
+                error!(target: "borealis_indexer", "Replay: Error while reading blocks: {:?}", error);
+                break;
+            }
+        };
+
+        let last_height = encoded_blocks
+            .last()
+            .map(|encoded_block| encoded_block.streamer_message.block.header.height)
+            .unwrap_or_default();
+        let batch_len = encoded_blocks.len() as u64;
+        let messages: Vec<OutgoingMessage> = encoded_blocks
+            .into_iter()
+            .flat_map(|encoded_block| encoded_block.messages)
+            .collect();
+        message_publisher(
+            &actual_connection_rx,
+            &connection_event_tx,
+            &run_args,
+            &message_headers,
+            Arc::new(messages),
+        )
+        .await;
+        replayed_blocks += batch_len;
+        info!(target: "borealis_indexer", "Replay: {} blocks replayed, last block #{}", replayed_blocks, last_height);
+
+        // Rate limiting: blocks are replayed not faster than `--rate-limit` blocks per second
+        if let Some(rate_limit) = context.rate_limit.filter(|rate_limit| *rate_limit > 0.0) {
+            let expected = core::time::Duration::from_secs_f64(replayed_blocks as f64 / rate_limit);
+            if let Some(delay) = expected.checked_sub(started.elapsed()) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    info!(target: "borealis_indexer", "Replay: Finished, {} blocks replayed within {:?}", replayed_blocks, started.elapsed());
+    actix::System::current().stop();
+}